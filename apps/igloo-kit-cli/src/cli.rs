@@ -1,6 +1,7 @@
 #[macro_use]
 mod display;
 
+pub mod auth;
 mod commands;
 pub mod local_webserver;
 mod logger;
@@ -19,12 +20,15 @@ use self::{
         RunMode,
     },
 };
+use crate::infrastructure::stream::redpanda::StartOffset;
 use crate::project::Project;
 use clap::Parser;
-use commands::Commands;
+use commands::{AuthCommands, Commands};
 use logger::setup_logging;
 use settings::{read_settings, Settings};
+use std::io::{Error, ErrorKind};
 use std::path::Path;
+use std::str::FromStr;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -45,6 +49,82 @@ struct Cli {
     command: Option<Commands>,
 }
 
+fn auth_command_handler(project: &Project, command: &AuthCommands) -> Result<(), Error> {
+    match command {
+        AuthCommands::CreateKey { name, routes } => {
+            let (api_key, secret) = auth::create_key(project, name, routes.clone())?;
+            show_message!(
+                MessageType::Success,
+                Message {
+                    action: "Created".to_string(),
+                    details: format!("API key {} ({})", api_key.name, api_key.id),
+                }
+            );
+            println!("{}", secret);
+            show_message!(
+                MessageType::Info,
+                Message {
+                    action: "Note".to_string(),
+                    details: format!(
+                        "Store this key now, it won't be shown again. The SDK reads it from {}",
+                        auth::API_KEY_ENV_VAR
+                    ),
+                }
+            );
+        }
+        AuthCommands::List {} => {
+            let keys = auth::list_keys(project)?;
+            if keys.is_empty() {
+                show_message!(
+                    MessageType::Info,
+                    Message {
+                        action: "Auth".to_string(),
+                        details: "No API keys, the webserver accepts every request".to_string(),
+                    }
+                );
+            }
+            for api_key in keys {
+                let routes = if api_key.routes.is_empty() {
+                    "all routes".to_string()
+                } else {
+                    api_key.routes.join(", ")
+                };
+                show_message!(
+                    MessageType::Info,
+                    Message {
+                        action: api_key.id,
+                        details: format!(
+                            "{} created {} on {}",
+                            api_key.name, api_key.created_at, routes
+                        ),
+                    }
+                );
+            }
+        }
+        AuthCommands::Revoke { key } => {
+            let revoked = auth::revoke_key(project, key)?;
+            show_message!(
+                MessageType::Success,
+                Message {
+                    action: "Revoked".to_string(),
+                    details: format!("API key {} ({})", revoked.name, revoked.id),
+                }
+            );
+            if auth::list_keys(project)?.is_empty() {
+                show_message!(
+                    MessageType::Info,
+                    Message {
+                        action: "Note".to_string(),
+                        details: "No API keys left, the webserver accepts every request again"
+                            .to_string(),
+                    }
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
 async fn top_command_handler(settings: Settings, commands: &Option<Commands>) {
     if !settings.features.coming_soon_wall {
         match commands {
@@ -83,18 +163,29 @@ async fn top_command_handler(settings: Settings, commands: &Option<Commands>) {
                 let mut controller = RoutineController::new();
                 let run_mode = RunMode::Explicit {};
 
-                controller.add_routine(Box::new(RunLocalInfratructure::new(project.clone())));
+                // The local streaming engine runs in process, there are no containers to start
+                if project.stream_config.engine.requires_docker() {
+                    controller.add_routine(Box::new(RunLocalInfratructure::new(project.clone())));
 
-                controller.add_routine(Box::new(ValidateRedPandaCluster::new()));
+                    controller.add_routine(Box::new(ValidateRedPandaCluster::new()));
 
-                controller.run_routines(run_mode);
+                    controller.run_routines(run_mode);
 
-                // sleep to allow infra to be spun up and realease resources.
-                //
-                // TODO: This is a hack and should be replaced with a better solution
-                // 500 ms seems to be enough time to allow the infra to spin up completely and release resources
-                tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
-                let _ = routines::start_development_mode(&project).await;
+                    // sleep to allow infra to be spun up and realease resources.
+                    //
+                    // TODO: This is a hack and should be replaced with a better solution
+                    // 500 ms seems to be enough time to allow the infra to spin up completely and release resources
+                    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+                }
+                if let Err(e) = routines::start_development_mode(&project).await {
+                    show_message!(
+                        MessageType::Error,
+                        Message {
+                            action: "Dev".to_string(),
+                            details: e.to_string(),
+                        }
+                    );
+                }
             }
             Some(Commands::Update {}) => {
                 // This command may not be needed if we have incredible automation
@@ -115,6 +206,84 @@ async fn top_command_handler(settings: Settings, commands: &Option<Commands>) {
                 controller.add_routine(Box::new(CleanProject::new(project, run_mode)));
                 controller.run_routines(run_mode);
             }
+            Some(Commands::Tail { model, from, table }) => {
+                info!("Running tail command for model: {}", model);
+
+                let project = Project::load_from_current_dir()
+                    .expect("No project found, please run `igloo init` to create a project");
+
+                let result = if *table {
+                    routines::tail::tail_table(&project, model).await
+                } else {
+                    match StartOffset::from_str(from) {
+                        Ok(from) => routines::tail::tail_topic(&project, model, from).await,
+                        Err(e) => Err(Error::new(ErrorKind::InvalidInput, e)),
+                    }
+                };
+
+                if let Err(e) = result {
+                    show_message!(
+                        MessageType::Error,
+                        Message {
+                            action: "Tail".to_string(),
+                            details: e.to_string(),
+                        }
+                    );
+                }
+            }
+            Some(Commands::Replay { model, from, to }) => {
+                info!("Running replay command for model: {}", model);
+
+                let project = Project::load_from_current_dir()
+                    .expect("No project found, please run `igloo init` to create a project");
+
+                if let Err(e) = routines::replay::replay(&project, model, from, to.as_deref()).await
+                {
+                    show_message!(
+                        MessageType::Error,
+                        Message {
+                            action: "Replay".to_string(),
+                            details: e.to_string(),
+                        }
+                    );
+                }
+            }
+            Some(Commands::Serve { host, port }) => {
+                info!("Running serve command");
+
+                let mut project = Project::load_from_current_dir()
+                    .expect("No project found, please run `igloo init` to create a project");
+                if let Some(host) = host {
+                    project.local_webserver_config.host = host.clone();
+                }
+                if let Some(port) = port {
+                    project.local_webserver_config.port = *port;
+                }
+
+                if let Err(e) = routines::start_serve_mode(&project).await {
+                    show_message!(
+                        MessageType::Error,
+                        Message {
+                            action: "Serve".to_string(),
+                            details: e.to_string(),
+                        }
+                    );
+                }
+            }
+            Some(Commands::Auth { command }) => {
+                let project = Project::load_from_current_dir()
+                    .expect("No project found, please run `igloo init` to create a project");
+
+                if let Err(e) = auth_command_handler(&project, command) {
+                    show_message!(
+                        MessageType::Error,
+                        Message {
+                            action: "Auth".to_string(),
+                            details: e.to_string(),
+                        }
+                    );
+                }
+            }
             None => {}
         }
     } else {
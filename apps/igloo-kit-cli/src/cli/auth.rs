@@ -0,0 +1,360 @@
+//! # Auth
+//! API keys that protect the routes of the local webserver. Keys are created per project with
+//! `igloo auth create-key` and stored in `.igloo/api_keys.json`. Only a SHA-256 hash of every key
+//! is stored, the key itself is printed once when it's created.
+//!
+//! As soon as a project has a key, every request to the webserver needs one, either as
+//! `Authorization: Bearer <key>` or as `X-Api-Key: <key>`. A key can be scoped to some routes, in
+//! which case it's only accepted on those routes and the routes below them, e.g. a key scoped to
+//! `ingest` is accepted on every ingest route. The generated SDK sends the key found in the
+//! `IGLOO_API_KEY` environment variable.
+//!
+//! The webserver checks the file for changes at most once per second, so revoked keys stop working
+//! without a restart. A file that can't be read rejects every request until it's fixed, unless
+//! keys were already loaded from it, in which case they stay in place.
+//!
+//! ## Suggested Improvements
+//! - expire keys after a configurable duration
+//! - scope keys to HTTP methods as well as routes
+
+use std::{
+    fmt,
+    io::{Error, ErrorKind},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::project::Project;
+
+pub const API_KEY_ENV_VAR: &str = "IGLOO_API_KEY";
+
+const API_KEYS_FILE: &str = "api_keys.json";
+const API_KEY_PREFIX: &str = "igloo_";
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    key_hash: String,
+    // Routes the key is accepted on, every route when empty
+    #[serde(default)]
+    pub routes: Vec<String>,
+    pub created_at: String,
+}
+
+impl ApiKey {
+    fn allows_route(&self, route: &str) -> bool {
+        self.routes.is_empty()
+            || self.routes.iter().any(|scope| {
+                let scope = scope.trim_matches('/');
+                route == scope || route.starts_with(&format!("{}/", scope))
+            })
+    }
+}
+
+fn hash_key(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+fn api_keys_path(project: &Project) -> PathBuf {
+    // Reading the keys doesn't create the internal directory, a project without it has no keys
+    project.internal_dir_path().join(API_KEYS_FILE)
+}
+
+fn read_keys(path: &Path) -> Result<Vec<ApiKey>, Error> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => serde_json::from_str(&contents).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Failed to parse {}: {}", path.display(), e),
+            )
+        }),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => Err(e),
+    }
+}
+
+fn write_keys(path: &Path, keys: &[ApiKey]) -> Result<(), Error> {
+    let contents = serde_json::to_string_pretty(keys)?;
+    let tmp_path = path.with_extension("json.tmp");
+    std::fs::write(&tmp_path, contents)?;
+    std::fs::rename(&tmp_path, path)
+}
+
+pub fn list_keys(project: &Project) -> Result<Vec<ApiKey>, Error> {
+    read_keys(&api_keys_path(project))
+}
+
+pub fn create_key(
+    project: &Project,
+    name: &str,
+    routes: Vec<String>,
+) -> Result<(ApiKey, String), Error> {
+    //! Creates a key and returns it along with its metadata. The key can't be retrieved afterwards.
+    let path = project.internal_dir()?.join(API_KEYS_FILE);
+    let mut keys = read_keys(&path)?;
+
+    if keys.iter().any(|key| key.name == name) {
+        return Err(Error::new(
+            ErrorKind::AlreadyExists,
+            format!("An API key named {} already exists", name),
+        ));
+    }
+
+    let secret = format!("{}{}", API_KEY_PREFIX, Uuid::new_v4().simple());
+    let api_key = ApiKey {
+        id: Uuid::new_v4().simple().to_string()[..8].to_string(),
+        name: name.to_string(),
+        key_hash: hash_key(&secret),
+        routes,
+        created_at: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+    };
+
+    keys.push(api_key.clone());
+    write_keys(&path, &keys)?;
+
+    Ok((api_key, secret))
+}
+
+pub fn revoke_key(project: &Project, id_or_name: &str) -> Result<ApiKey, Error> {
+    //! Removes the key with the given id or name and returns it.
+    let path = api_keys_path(project);
+    let mut keys = read_keys(&path)?;
+
+    let index = keys
+        .iter()
+        .position(|key| key.id == id_or_name || key.name == id_or_name)
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!("No API key with the id or name {}", id_or_name),
+            )
+        })?;
+
+    let revoked = keys.remove(index);
+    write_keys(&path, &keys)?;
+
+    Ok(revoked)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthError {
+    MissingKey,
+    InvalidKey,
+    RouteNotAllowed,
+    // The keys file can't be read, so no request can be checked
+    KeysUnavailable,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuthError::MissingKey => write!(f, "An API key is required"),
+            AuthError::InvalidKey => write!(f, "The API key is invalid or was revoked"),
+            AuthError::RouteNotAllowed => write!(f, "The API key is not allowed on this route"),
+            AuthError::KeysUnavailable => write!(f, "The API keys of the project can't be read"),
+        }
+    }
+}
+
+struct CachedKeys {
+    checked_at: Option<Instant>,
+    modified: Option<SystemTime>,
+    keys: Vec<ApiKey>,
+    // Set while the file can't be read and there are no keys to fall back on
+    load_error: Option<String>,
+}
+
+pub struct ApiKeys {
+    path: PathBuf,
+    refresh_interval: Duration,
+    // Reloaded whenever the file changes so that keys revoked while the webserver runs stop working
+    cache: Mutex<CachedKeys>,
+}
+
+impl ApiKeys {
+    pub fn load(project: &Project) -> Self {
+        //! Watches the keys file of the project. The file is read on the first request, and a
+        //! missing file means the project has no keys.
+        Self::from_path(api_keys_path(project), REFRESH_INTERVAL)
+    }
+
+    fn from_path(path: PathBuf, refresh_interval: Duration) -> Self {
+        Self {
+            path,
+            refresh_interval,
+            cache: Mutex::new(CachedKeys {
+                checked_at: None,
+                modified: None,
+                keys: vec![],
+                load_error: None,
+            }),
+        }
+    }
+
+    fn refresh(&self, cache: &mut CachedKeys) {
+        if cache.checked_at.map_or(false, |checked_at| {
+            checked_at.elapsed() < self.refresh_interval
+        }) {
+            return;
+        }
+        cache.checked_at = Some(Instant::now());
+
+        let modified = std::fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        if modified == cache.modified && cache.load_error.is_none() {
+            return;
+        }
+
+        match read_keys(&self.path) {
+            Ok(keys) => {
+                cache.keys = keys;
+                cache.modified = modified;
+                cache.load_error = None;
+            }
+            // The previous keys stay in place until the file can be read again
+            Err(e) if !cache.keys.is_empty() => {
+                log::error!("Failed to reload API keys: {}", e);
+                cache.modified = modified;
+            }
+            Err(e) => {
+                log::error!("Failed to load API keys, rejecting every request: {}", e);
+                cache.load_error = Some(e.to_string());
+            }
+        }
+    }
+
+    pub fn authorize(&self, route: &str, key: Option<&str>) -> Result<Option<String>, AuthError> {
+        //! Checks the key sent with a request to the given route and returns the id of the key it
+        //! was accepted as. Every request is allowed, without a key id, while the project has no
+        //! keys.
+        let mut cache = match self.cache.lock() {
+            Ok(cache) => cache,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        self.refresh(&mut cache);
+
+        if cache.load_error.is_some() {
+            return Err(AuthError::KeysUnavailable);
+        }
+
+        if cache.keys.is_empty() {
+            return Ok(None);
+        }
+
+        let key_hash = hash_key(key.ok_or(AuthError::MissingKey)?);
+        let api_key = cache
+            .keys
+            .iter()
+            .find(|api_key| api_key.key_hash == key_hash)
+            .ok_or(AuthError::InvalidKey)?;
+
+        if api_key.allows_route(route) {
+            Ok(Some(api_key.id.clone()))
+        } else {
+            Err(AuthError::RouteNotAllowed)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_fs::TempDir;
+
+    use super::*;
+
+    fn key(name: &str, secret: &str, routes: &[&str]) -> ApiKey {
+        ApiKey {
+            id: name.to_string(),
+            name: name.to_string(),
+            key_hash: hash_key(secret),
+            routes: routes.iter().map(|route| route.to_string()).collect(),
+            created_at: "2024-01-01T00:00:00Z".to_string(),
+        }
+    }
+
+    #[test]
+    fn allows_every_request_without_keys() {
+        let dir = TempDir::new().unwrap();
+        let api_keys = ApiKeys::from_path(dir.path().join(API_KEYS_FILE), Duration::ZERO);
+        assert_eq!(api_keys.authorize("ingest/Users", None), Ok(None));
+    }
+
+    #[test]
+    fn loading_keys_leaves_the_project_untouched() {
+        let dir = TempDir::new().unwrap();
+        let project = Project::new(
+            "test".to_string(),
+            crate::framework::languages::SupportedLanguages::Typescript,
+            dir.path().join("project.toml"),
+        );
+
+        let api_keys = ApiKeys::load(&project);
+        assert_eq!(api_keys.authorize("ingest/Users", None), Ok(None));
+        assert_eq!(list_keys(&project).unwrap().len(), 0);
+        assert!(!project.internal_dir_path().exists());
+    }
+
+    #[test]
+    fn checks_keys_and_their_routes() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join(API_KEYS_FILE);
+        write_keys(&path, &[key("ingest", "secret", &["/ingest/"])]).unwrap();
+        let api_keys = ApiKeys::from_path(path, Duration::ZERO);
+
+        assert_eq!(
+            api_keys.authorize("ingest/Users", Some("secret")),
+            Ok(Some("ingest".to_string()))
+        );
+        assert_eq!(
+            api_keys.authorize("ingestion/Users", Some("secret")),
+            Err(AuthError::RouteNotAllowed)
+        );
+        assert_eq!(
+            api_keys.authorize("ingest/Users", Some("other")),
+            Err(AuthError::InvalidKey)
+        );
+        assert_eq!(
+            api_keys.authorize("ingest/Users", None),
+            Err(AuthError::MissingKey)
+        );
+    }
+
+    #[test]
+    fn rejects_every_request_until_a_broken_file_is_fixed() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join(API_KEYS_FILE);
+        std::fs::write(&path, "not json").unwrap();
+        let api_keys = ApiKeys::from_path(path.clone(), Duration::ZERO);
+
+        assert_eq!(
+            api_keys.authorize("ingest/Users", Some("secret")),
+            Err(AuthError::KeysUnavailable)
+        );
+
+        write_keys(&path, &[key("all", "secret", &[])]).unwrap();
+        assert!(api_keys.authorize("ingest/Users", Some("secret")).is_ok());
+
+        // Keys that were loaded stay in place when the file breaks again
+        std::fs::write(&path, "not json either").unwrap();
+        assert!(api_keys.authorize("ingest/Users", Some("secret")).is_ok());
+    }
+
+    #[test]
+    fn checks_the_file_at_most_once_per_interval() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join(API_KEYS_FILE);
+        let api_keys = ApiKeys::from_path(path.clone(), Duration::from_secs(3600));
+        assert_eq!(api_keys.authorize("ingest/Users", None), Ok(None));
+
+        write_keys(&path, &[key("all", "secret", &[])]).unwrap();
+        assert_eq!(api_keys.authorize("ingest/Users", None), Ok(None));
+    }
+}
@@ -44,4 +44,65 @@ pub enum Commands {
     Stop {},
     // Clears all temporary data and stops development infrastructure
     Clean {},
+    /// Prints the records of a data model as they arrive
+    Tail {
+        /// Name of the data model to tail
+        model: String,
+
+        /// Where to start reading the topic: `earliest`, `latest`, an offset or an RFC 3339 timestamp
+        #[arg(long, default_value = "latest")]
+        from: String,
+
+        /// Tail the rows stored in ClickHouse instead of the topic
+        #[arg(long)]
+        table: bool,
+    },
+    /// Re-ingests the records of a data model's topic into its ClickHouse table
+    Replay {
+        /// Name of the data model to replay
+        model: String,
+
+        /// Where to start reading the topic: `earliest`, `latest`, an offset or an RFC 3339 timestamp
+        #[arg(long, default_value = "earliest")]
+        from: String,
+
+        /// Where to stop reading the topic, excluded. Defaults to the end of the topic
+        #[arg(long)]
+        to: Option<String>,
+    },
+    /// Serves the ingest routes against existing infrastructure, without any development tooling
+    Serve {
+        /// Host to listen on, defaults to the one of the project
+        #[arg(long)]
+        host: Option<String>,
+
+        /// Port to listen on, defaults to the one of the project
+        #[arg(long)]
+        port: Option<u16>,
+    },
+    /// Manages the API keys of the local webserver
+    Auth {
+        #[command(subcommand)]
+        command: AuthCommands,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum AuthCommands {
+    /// Creates an API key, printed only once
+    CreateKey {
+        /// Name of the key
+        name: String,
+
+        /// Route the key is allowed on, e.g. `ingest/UserActivity`. Can be repeated, defaults to every route
+        #[arg(long = "route")]
+        routes: Vec<String>,
+    },
+    /// Lists the API keys of the project
+    List {},
+    /// Revokes an API key
+    Revoke {
+        /// Id or name of the key
+        key: String,
+    },
 }
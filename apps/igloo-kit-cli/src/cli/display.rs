@@ -101,12 +101,13 @@ lazy_static! {
 }
 
 macro_rules! show_message {
-    ($message_type:expr, $message:expr) => {
+    ($message_type:expr, $message:expr) => {{
         use crate::cli::display::styled_banner;
         use crate::cli::display::TERM;
         use console::{pad_str, style};
 
         let padder = 14;
+        let message = $message;
 
         match $message_type {
             MessageType::Info => {
@@ -116,14 +117,14 @@ macro_rules! show_message {
                     .write_line(&format!(
                         "{} {}",
                         style(pad_str(
-                            $message.action.as_str(),
+                            message.action.as_str(),
                             padder,
                             console::Alignment::Right,
                             Some("...")
                         ))
                         .blue()
                         .bold(),
-                        $message.details
+                        message.details
                     ))
                     .expect("failed to write message to terminal");
                 command_terminal.counter += 1;
@@ -135,14 +136,14 @@ macro_rules! show_message {
                     .write_line(&format!(
                         "{} {}",
                         style(pad_str(
-                            $message.action.as_str(),
+                            message.action.as_str(),
                             padder,
                             console::Alignment::Right,
                             Some("...")
                         ))
                         .green()
                         .bold(),
-                        $message.details
+                        message.details
                     ))
                     .expect("failed to write message to terminal");
                 command_terminal.counter += 1;
@@ -154,14 +155,14 @@ macro_rules! show_message {
                     .write_line(&format!(
                         "{} {}",
                         style(pad_str(
-                            $message.action.as_str(),
+                            message.action.as_str(),
                             padder,
                             console::Alignment::Right,
                             Some("...")
                         ))
                         .red()
                         .bold(),
-                        $message.details
+                        message.details
                     ))
                     .expect("failed to write message to terminal");
                 command_terminal.counter += 1;
@@ -175,5 +176,5 @@ macro_rules! show_message {
                 command_terminal.counter += styled_banner().lines().count();
             }
         };
-    };
+    }};
 }
@@ -1,57 +1,137 @@
+pub mod compression;
+pub mod events;
+pub mod health;
+pub mod metrics;
+pub mod openapi;
+pub mod rate_limit;
+pub mod shutdown;
+pub mod tls;
+
+use self::compression::DecompressionError;
+use self::events::{EventBus, ServerEvent, EVENT_STREAM_CONTENT_TYPE};
+use self::metrics::{Metrics, METRICS_CONTENT_TYPE, UNMATCHED_ROUTE};
+use self::rate_limit::{RateLimitConfig, RateLimiter};
+use self::shutdown::RequestTracker;
+use self::tls::TlsConfig;
 use super::display::Message;
 use super::display::MessageType;
 
-use crate::cli::routines::stop::StopLocalInfrastructure;
-use crate::cli::routines::Routine;
-use crate::cli::routines::RunMode;
+use crate::cli::auth::{ApiKeys, AuthError};
 use crate::framework::controller::RouteMeta;
+use crate::infrastructure::ingest;
+use crate::infrastructure::ingest::adapters;
+use crate::infrastructure::ingest::dedup::{
+    self, IdempotencyCache, KeyStatus, IDEMPOTENCY_KEY_HEADER,
+};
+use crate::infrastructure::ingest::metadata::{IngestMetadata, SDK_VERSION_HEADER};
+use crate::infrastructure::ingest::{DeliveryMode, EncodingError};
 use crate::infrastructure::olap;
 
 use crate::infrastructure::olap::clickhouse::ConfiguredDBClient;
-use crate::infrastructure::stream::redpanda;
-use crate::infrastructure::stream::redpanda::ConfiguredProducer;
+use crate::infrastructure::stream::{self, StreamingBackend};
 
 use crate::project::Project;
+use http_body_util::combinators::BoxBody;
 use http_body_util::BodyExt;
 use http_body_util::Full;
+use http_body_util::{LengthLimitError, Limited};
 use hyper::body::Bytes;
 use hyper::body::Incoming;
+use hyper::header::{
+    HeaderMap, HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+    ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS, ALLOW, AUTHORIZATION,
+    CACHE_CONTROL, CONNECTION, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, RETRY_AFTER,
+    USER_AGENT, WWW_AUTHENTICATE,
+};
 use hyper::service::Service;
 use hyper::Request;
 use hyper::Response;
 use hyper::StatusCode;
 use hyper_util::rt::TokioIo;
+use hyper_util::server::graceful::{GracefulShutdown, Watcher};
 use hyper_util::{rt::TokioExecutor, server::conn::auto};
 use log::debug;
 use log::error;
-use rdkafka::producer::FutureRecord;
-use rdkafka::util::Timeout;
 use serde::Deserialize;
 use serde::Serialize;
 use serde_json::json;
 use std::collections::HashMap;
+use std::convert::Infallible;
 use std::future::Future;
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
+use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpListener;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, OwnedSemaphorePermit, RwLock, Semaphore};
+use uuid::Uuid;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct LocalWebserverConfig {
     pub host: String,
     pub port: u16,
+    // Larger request bodies are rejected with a 413
+    #[serde(default = "default_max_body_bytes")]
+    pub max_body_bytes: usize,
+    // Compressed bodies that expand past this size are rejected with a 413
+    #[serde(default = "default_max_decompressed_bytes")]
+    pub max_decompressed_bytes: usize,
+    // Requests are rejected with a 503 while this many records are being produced
+    #[serde(default = "default_max_in_flight_records")]
+    pub max_in_flight_records: usize,
+    #[serde(default)]
+    pub rate_limits: RateLimitConfig,
+    // How long in-flight requests get to finish on shutdown, and records to be acknowledged
+    #[serde(default = "default_shutdown_timeout_secs")]
+    pub shutdown_timeout_secs: u64,
+    // Whether the containers are stopped when the server shuts down
+    #[serde(default = "default_stop_infrastructure_on_exit")]
+    pub stop_infrastructure_on_exit: bool,
+    #[serde(default)]
+    pub tls: TlsConfig,
+}
+
+fn default_max_body_bytes() -> usize {
+    10 * 1024 * 1024
+}
+
+fn default_shutdown_timeout_secs() -> u64 {
+    30
+}
+
+fn default_stop_infrastructure_on_exit() -> bool {
+    true
+}
+
+fn default_max_decompressed_bytes() -> usize {
+    50 * 1024 * 1024
+}
+
+fn default_max_in_flight_records() -> usize {
+    10_000
 }
 
 impl LocalWebserverConfig {
     pub fn new(host: String, port: u16) -> Self {
-        Self { host, port }
+        Self {
+            host,
+            port,
+            max_body_bytes: default_max_body_bytes(),
+            max_decompressed_bytes: default_max_decompressed_bytes(),
+            max_in_flight_records: default_max_in_flight_records(),
+            rate_limits: RateLimitConfig::default(),
+            shutdown_timeout_secs: default_shutdown_timeout_secs(),
+            stop_infrastructure_on_exit: default_stop_infrastructure_on_exit(),
+            tls: TlsConfig::default(),
+        }
     }
 
     pub fn url(&self) -> String {
-        format!("http://{}:{}", self.host, self.port)
+        let scheme = if self.tls.enabled { "https" } else { "http" };
+        format!("{}://{}:{}", scheme, self.host, self.port)
     }
 }
 
@@ -60,6 +140,13 @@ impl Default for LocalWebserverConfig {
         Self {
             host: "localhost".to_string(),
             port: 4000,
+            max_body_bytes: default_max_body_bytes(),
+            max_decompressed_bytes: default_max_decompressed_bytes(),
+            max_in_flight_records: default_max_in_flight_records(),
+            rate_limits: RateLimitConfig::default(),
+            shutdown_timeout_secs: default_shutdown_timeout_secs(),
+            stop_infrastructure_on_exit: default_stop_infrastructure_on_exit(),
+            tls: TlsConfig::default(),
         }
     }
 }
@@ -88,102 +175,813 @@ impl RouteInfo {
     }
 }
 
+#[derive(Clone)]
 struct RouteService {
-    route_table: Arc<Mutex<HashMap<PathBuf, RouteMeta>>>,
-    configured_producer: Arc<Mutex<ConfiguredProducer>>,
+    route_table: Arc<RwLock<HashMap<PathBuf, Arc<RouteMeta>>>>,
+    streaming_backend: Arc<dyn StreamingBackend>,
     configured_db_client: Arc<Mutex<ConfiguredDBClient>>,
+    api_keys: Arc<ApiKeys>,
+    rate_limiter: Arc<RateLimiter>,
+    idempotency: Arc<IdempotencyCache>,
+    metrics: Arc<Metrics>,
+    // Set once the schemas directory was crawled, which `/ready` waits for
+    schema_crawl_complete: Arc<AtomicBool>,
+    events: EventBus,
+    requests: Arc<RequestTracker>,
+    // Records that are being produced, bounded so that the server pushes back when the stream falls behind
+    in_flight_records: Arc<Semaphore>,
+    max_in_flight_records: usize,
+    max_body_bytes: usize,
+    max_decompressed_bytes: usize,
+    client_addr: SocketAddr,
 }
 
 impl Service<Request<Incoming>> for RouteService {
-    type Response = Response<Full<Bytes>>;
+    type Response = Response<ResponseBody>;
     type Error = hyper::http::Error;
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
     fn call(&self, req: Request<Incoming>) -> Self::Future {
-        Box::pin(router(
-            req,
-            self.route_table.clone(),
-            self.configured_producer.clone(),
-            self.configured_db_client.clone(),
-        ))
+        let request = self.requests.track();
+        let service = self.clone();
+        Box::pin(async move {
+            let _request = request;
+            router(req, service).await
+        })
+    }
+}
+
+impl RouteService {
+    fn client_id(&self, key_id: Option<&str>) -> String {
+        //! Identifies the client of a request for rate limiting, by the id of the API key it was
+        //! authorized with or by IP address. Keys that weren't accepted never get their own bucket.
+        match key_id {
+            Some(key_id) => format!("key:{}", key_id),
+            None => format!("ip:{}", self.client_addr.ip()),
+        }
+    }
+
+    fn reserve_records(&self, count: usize) -> Option<OwnedSemaphorePermit> {
+        //! Reserves room for records in the producer queue, `None` when it's full. Nothing waits
+        //! for room, and batches larger than the queue are only taken while it's empty.
+        let count = count.clamp(1, self.max_in_flight_records) as u32;
+        self.in_flight_records
+            .clone()
+            .try_acquire_many_owned(count)
+            .ok()
+    }
+}
+
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
+const API_KEY_HEADER: &str = "X-Api-Key";
+
+// Every response is sent at once except for the event stream
+type ResponseBody = BoxBody<Bytes, Infallible>;
+
+fn json_response(
+    status: StatusCode,
+    body: serde_json::Value,
+) -> Result<Response<Full<Bytes>>, hyper::http::Error> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Full::new(Bytes::from(body.to_string())))
+}
+
+fn error_response(
+    status: StatusCode,
+    request_id: &str,
+    message: String,
+) -> Result<Response<Full<Bytes>>, hyper::http::Error> {
+    json_response(
+        status,
+        json!({ "error": { "message": message }, "request_id": request_id }),
+    )
+}
+
+fn stream_error_response(
+    request_id: &str,
+    e: &std::io::Error,
+) -> Result<Response<Full<Bytes>>, hyper::http::Error> {
+    //! Transient streaming failures are reported as 503 so that clients retry them.
+    error!("Failed to produce record for request {}: {}", request_id, e);
+    let retryable = stream::is_retryable(e);
+
+    let mut response = json_response(
+        if retryable {
+            StatusCode::SERVICE_UNAVAILABLE
+        } else {
+            StatusCode::INTERNAL_SERVER_ERROR
+        },
+        json!({
+            "error": { "message": e.to_string(), "retryable": retryable },
+            "request_id": request_id,
+        }),
+    )?;
+    if retryable {
+        response
+            .headers_mut()
+            .insert(RETRY_AFTER, HeaderValue::from_static("1"));
     }
+    Ok(response)
+}
+
+fn add_common_headers(headers: &mut HeaderMap, request_id: &str) {
+    //! Every response carries the CORS headers and the id of the request it answers.
+    headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+    headers.insert(
+        ACCESS_CONTROL_ALLOW_METHODS,
+        HeaderValue::from_static("GET, POST, PUT, PATCH, OPTIONS"),
+    );
+    headers.insert(
+        ACCESS_CONTROL_ALLOW_HEADERS,
+        HeaderValue::from_static(
+            "Content-Type, Content-Encoding, Authorization, X-Api-Key, X-Igloo-Sdk-Version, Idempotency-Key, Baggage, Sentry-Trace, X-Request-Id",
+        ),
+    );
+    headers.insert(
+        ACCESS_CONTROL_EXPOSE_HEADERS,
+        HeaderValue::from_static(REQUEST_ID_HEADER),
+    );
+    if let Ok(value) = HeaderValue::from_str(request_id) {
+        headers.insert(REQUEST_ID_HEADER, value);
+    }
+}
+
+fn request_api_key(headers: &HeaderMap) -> Option<&str> {
+    //! The key of a request, sent either as a bearer token or in the `X-Api-Key` header.
+    let bearer = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    bearer
+        .or_else(|| {
+            headers
+                .get(API_KEY_HEADER)
+                .and_then(|value| value.to_str().ok())
+        })
+        .map(|key| key.trim())
+}
+
+fn unauthorized(
+    request_id: &str,
+    e: AuthError,
+) -> Result<Response<Full<Bytes>>, hyper::http::Error> {
+    let status = match e {
+        AuthError::MissingKey | AuthError::InvalidKey => StatusCode::UNAUTHORIZED,
+        AuthError::RouteNotAllowed => StatusCode::FORBIDDEN,
+        AuthError::KeysUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+    };
+
+    let mut response = error_response(status, request_id, e.to_string())?;
+    if status == StatusCode::UNAUTHORIZED {
+        response
+            .headers_mut()
+            .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+    }
+    Ok(response)
+}
+
+fn too_many_requests(
+    request_id: &str,
+    retry_after: Duration,
+) -> Result<Response<Full<Bytes>>, hyper::http::Error> {
+    let retry_after_secs = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+    let mut response = json_response(
+        StatusCode::TOO_MANY_REQUESTS,
+        json!({
+            "error": { "message": "Rate limit exceeded", "retryable": true },
+            "request_id": request_id,
+        }),
+    )?;
+    response
+        .headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from(retry_after_secs));
+    Ok(response)
+}
+
+fn queue_full(request_id: &str) -> Result<Response<Full<Bytes>>, hyper::http::Error> {
+    //! The stream isn't keeping up with the records that are sent.
+    let mut response = json_response(
+        StatusCode::SERVICE_UNAVAILABLE,
+        json!({
+            "error": {
+                "message": "Too many records are waiting to be produced, retry later",
+                "retryable": true,
+            },
+            "request_id": request_id,
+        }),
+    )?;
+    response
+        .headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from_static("1"));
+    Ok(response)
+}
+
+fn shutting_down(request_id: &str) -> Result<Response<Full<Bytes>>, hyper::http::Error> {
+    //! The server is draining, the client should retry once it's back.
+    let mut response = error_response(
+        StatusCode::SERVICE_UNAVAILABLE,
+        request_id,
+        "The server is shutting down".to_string(),
+    )?;
+    response
+        .headers_mut()
+        .insert(CONNECTION, HeaderValue::from_static("close"));
+    Ok(response)
+}
+
+fn method_not_allowed(
+    request_id: &str,
+    method: &hyper::Method,
+    path: &str,
+    methods: &[String],
+) -> Result<Response<Full<Bytes>>, hyper::http::Error> {
+    let mut response = error_response(
+        StatusCode::METHOD_NOT_ALLOWED,
+        request_id,
+        format!("/{} doesn't take {} requests", path, method),
+    )?;
+    if let Ok(value) = HeaderValue::from_str(&methods.join(", ")) {
+        response.headers_mut().insert(ALLOW, value);
+    }
+    Ok(response)
 }
 
 fn options_route() -> Result<Response<Full<Bytes>>, hyper::http::Error> {
-    let response = Response::builder()
-        .status(StatusCode::OK)
-        .header("Access-Control-Allow-Origin", "*")
-        .header("Access-Control-Allow-Methods", "POST, OPTIONS")
-        .header(
-            "Access-Control-Allow-Headers",
-            "Content-Type, Baggage, Sentry-Trace",
+    Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Full::new(Bytes::new()))
+}
+
+fn bad_request(
+    route: &Path,
+    request_id: &str,
+    e: EncodingError,
+) -> Result<Response<Full<Bytes>>, hyper::http::Error> {
+    debug!("Rejected payload on {:?}: {}", route, e);
+    json_response(
+        StatusCode::BAD_REQUEST,
+        json!({ "errors": e.field_errors(), "request_id": request_id }),
+    )
+}
+
+fn dedup_key(route: &Path, key: &str) -> String {
+    // Keys are scoped to their route so that data models never share them
+    format!("{} {}", route.display(), key)
+}
+
+fn duplicate_response(request_id: &str) -> Result<Response<Full<Bytes>>, hyper::http::Error> {
+    json_response(
+        StatusCode::OK,
+        json!({ "status": "ok", "duplicate": true, "request_id": request_id }),
+    )
+}
+
+fn in_flight_response(request_id: &str) -> Result<Response<Full<Bytes>>, hyper::http::Error> {
+    error_response(
+        StatusCode::CONFLICT,
+        request_id,
+        "A request with the same idempotency key is still being processed, retry it later"
+            .to_string(),
+    )
+}
+
+fn key_conflict(
+    service: &RouteService,
+    key: &str,
+    request_id: &str,
+) -> Option<Result<Response<Full<Bytes>>, hyper::http::Error>> {
+    //! Takes the key for this request, or returns the response to send when it's already taken.
+    match service.idempotency.begin(key) {
+        KeyStatus::New => None,
+        KeyStatus::InFlight => Some(in_flight_response(request_id)),
+        KeyStatus::Committed => Some(duplicate_response(request_id)),
+    }
+}
+
+async fn deliver(
+    service: &RouteService,
+    route: &Path,
+    topic: &str,
+    payload: &[u8],
+    key: Option<&str>,
+) -> Result<(), std::io::Error> {
+    //! Produces a record and records how it went. Its dedup key is committed once the record is
+    //! produced, or forgotten if it failed so that it can be sent again.
+    let started = Instant::now();
+    let res = service
+        .streaming_backend
+        .produce(
+            topic,
+            topic, // This should probably be generated by the client that pushes data to the API
+            payload,
         )
-        .body(Full::new(Bytes::from("Success")))
-        .unwrap();
+        .await;
+    let produced = usize::from(res.is_ok());
+    service.metrics.record_delivery(
+        &route.display().to_string(),
+        produced,
+        1 - produced,
+        started.elapsed(),
+    );
+    match (key, &res) {
+        (Some(key), Ok(_)) => service.idempotency.commit(key),
+        (Some(key), Err(_)) => service.idempotency.forget(key),
+        _ => {}
+    }
+    res
+}
 
-    Ok(response)
+async fn deliver_batch(
+    service: &RouteService,
+    route: &Path,
+    topic: &str,
+    payloads: &[Vec<u8>],
+    keys: &[Option<String>],
+) -> Vec<Result<(), std::io::Error>> {
+    //! Produces the records of a batch and records how it went. The dedup keys of the records that
+    //! were produced are committed, and those of the records that failed are forgotten so that they
+    //! can be sent again.
+    let started = Instant::now();
+    let produced = service
+        .streaming_backend
+        .produce_batch(topic, topic, payloads)
+        .await;
+    if !payloads.is_empty() {
+        let errors = produced.iter().filter(|result| result.is_err()).count();
+        service.metrics.record_delivery(
+            &route.display().to_string(),
+            produced.len() - errors,
+            errors,
+            started.elapsed(),
+        );
+    }
+    for (key, result) in keys.iter().zip(&produced) {
+        match (key, result) {
+            (Some(key), Ok(_)) => service.idempotency.commit(key),
+            (Some(key), Err(_)) => service.idempotency.forget(key),
+            _ => {}
+        }
+    }
+    produced
+}
+
+async fn ingest_batch(
+    records: Vec<Result<serde_json::Value, EncodingError>>,
+    route_meta: &RouteMeta,
+    route: &Path,
+    request_id: &str,
+    service: &RouteService,
+    permit: OwnedSemaphorePermit,
+    mut request_key: Option<String>,
+) -> Result<Response<Full<Bytes>>, hyper::http::Error> {
+    //! Produces every valid record of the batch and reports the outcome of each record by its index.
+    //! Records whose dedup field was already produced are acknowledged without being produced
+    //! again, and records whose dedup field is still being produced fail as retryable.
+    //!
+    //! The idempotency key of the request is committed once every record made it, and forgotten
+    //! otherwise so that the batch can be retried with it, see `dedup`.
+    let mut results = vec![serde_json::Value::Null; records.len()];
+    let mut payloads = vec![];
+    let mut payload_indexes = vec![];
+    let mut payload_keys = vec![];
+    let mut retryable_failures = 0;
+
+    for (index, record) in records.into_iter().enumerate() {
+        let encoded = record.and_then(|record| {
+            let key = route_meta
+                .dedup_field
+                .as_ref()
+                .and_then(|field| dedup::dedup_value(&record, field))
+                .map(|key| dedup_key(route, &key));
+            ingest::encode_record(&route_meta.codec, &route_meta.data_model, &record)
+                .map(|payload| (payload, key))
+        });
+        let status = match &encoded {
+            Ok((_, Some(key))) => service.idempotency.begin(key),
+            _ => KeyStatus::New,
+        };
+        match encoded {
+            Ok(_) if status == KeyStatus::Committed => {
+                results[index] = json!({ "index": index, "status": "ok", "duplicate": true })
+            }
+            Ok(_) if status == KeyStatus::InFlight => {
+                retryable_failures += 1;
+                results[index] = json!({
+                    "index": index,
+                    "status": "error",
+                    "errors": [{ "message": "A record with the same dedup value is still being produced" }],
+                    "retryable": true,
+                })
+            }
+            Ok((payload, key)) => {
+                payloads.push(payload);
+                payload_indexes.push(index);
+                payload_keys.push(key);
+            }
+            Err(e) => {
+                results[index] =
+                    json!({ "index": index, "status": "error", "errors": e.field_errors() })
+            }
+        }
+    }
+
+    let fire_and_forget = route_meta.delivery == DeliveryMode::FireAndForget;
+    if fire_and_forget {
+        for index in &payload_indexes {
+            results[*index] = json!({ "index": index, "status": "accepted" });
+        }
+        // The records are produced after the response is sent, and still count as in flight
+        let service = service.clone();
+        let route = route.to_path_buf();
+        let topic = route_meta.table_name.clone();
+        let request = service.requests.track();
+        // Only a fully accepted batch can still be committed, once its records are produced
+        let rejected = results
+            .iter()
+            .filter(|result| result["status"] == "error")
+            .count();
+        let request_key = match request_key.take() {
+            Some(key) if rejected > 0 => {
+                service.idempotency.forget(&key);
+                None
+            }
+            request_key => request_key,
+        };
+        tokio::spawn(async move {
+            let produced = deliver_batch(&service, &route, &topic, &payloads, &payload_keys).await;
+            let failed = produced.iter().filter(|result| result.is_err()).count();
+            if failed > 0 {
+                error!("Failed to produce {} accepted records to {}", failed, topic);
+            }
+            match request_key {
+                Some(key) if failed == 0 => service.idempotency.commit(&key),
+                Some(key) => service.idempotency.forget(&key),
+                None => {}
+            }
+            drop((permit, request));
+        });
+    } else {
+        let produced = deliver_batch(
+            service,
+            route,
+            &route_meta.table_name,
+            &payloads,
+            &payload_keys,
+        )
+        .await;
+        drop(permit);
+        for (index, result) in payload_indexes.into_iter().zip(produced) {
+            results[index] = match result {
+                Ok(_) => json!({ "index": index, "status": "ok" }),
+                Err(e) => {
+                    let retryable = stream::is_retryable(&e);
+                    if retryable {
+                        retryable_failures += 1;
+                    }
+                    json!({
+                        "index": index,
+                        "status": "error",
+                        "errors": [{ "message": e.to_string() }],
+                        "retryable": retryable,
+                    })
+                }
+            };
+        }
+    }
+
+    let succeeded = results
+        .iter()
+        .filter(|result| result["status"] == "ok" || result["status"] == "accepted")
+        .count();
+    let failed = results.len() - succeeded;
+
+    show_message!(
+        if failed == 0 {
+            MessageType::Success
+        } else {
+            MessageType::Error
+        },
+        Message {
+            action: "BATCH".to_string(),
+            details: format!(
+                "{} records to {}, {} failed",
+                results.len(),
+                route_meta.table_name,
+                failed
+            ),
+        }
+    );
+
+    // Multi-Status tells the client to look at every record when only some of them made it
+    let status = match (succeeded, failed) {
+        (_, 0) if fire_and_forget => StatusCode::ACCEPTED,
+        (_, 0) => StatusCode::OK,
+        (0, _) if retryable_failures > 0 => StatusCode::SERVICE_UNAVAILABLE,
+        (0, _) => StatusCode::BAD_REQUEST,
+        _ => StatusCode::MULTI_STATUS,
+    };
+    // Fire and forget batches handed their key over to their delivery
+    if let Some(key) = &request_key {
+        if status == StatusCode::OK {
+            service.idempotency.commit(key);
+        } else {
+            service.idempotency.forget(key);
+        }
+    }
+
+    json_response(
+        status,
+        json!({
+            "succeeded": succeeded,
+            "failed": failed,
+            "results": results,
+            "request_id": request_id,
+        }),
+    )
+}
+
+fn shape_record(
+    record: &mut serde_json::Value,
+    route_meta: &RouteMeta,
+    text_encoded: bool,
+    metadata: &IngestMetadata,
+) {
+    //! Brings a record as it was sent into the shape of its data model: the fields of an ingestion
+    //! point are mapped, string values are coerced to their column types and the metadata is added.
+    if let Some(ingestion_point) = &route_meta.ingestion_point {
+        ingestion_point.map_record(record);
+    }
+    if text_encoded {
+        adapters::coerce_record(&route_meta.data_model, record);
+    }
+    metadata.enrich(record, &route_meta.metadata_columns);
+}
+
+async fn read_body(
+    req: Request<Incoming>,
+    max_body_bytes: usize,
+) -> Result<Vec<u8>, (StatusCode, String)> {
+    let too_large = || {
+        (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("The request body is larger than {} bytes", max_body_bytes),
+        )
+    };
+
+    // Checked first so that oversized bodies are rejected without being read
+    let content_length = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    if content_length.map_or(false, |len| len > max_body_bytes) {
+        return Err(too_large());
+    }
+
+    match Limited::new(req.into_body(), max_body_bytes)
+        .collect()
+        .await
+    {
+        Ok(collected) => Ok(collected.to_bytes().to_vec()),
+        Err(e) if e.downcast_ref::<LengthLimitError>().is_some() => Err(too_large()),
+        Err(e) => Err((
+            StatusCode::BAD_REQUEST,
+            format!("Failed to read the request body: {}", e),
+        )),
+    }
 }
 
 async fn ingest_route(
     req: Request<hyper::body::Incoming>,
     route: PathBuf,
-    configured_producer: Arc<Mutex<ConfiguredProducer>>,
-    route_table: Arc<Mutex<HashMap<PathBuf, RouteMeta>>>,
+    route_meta: Option<Arc<RouteMeta>>,
+    request_id: &str,
+    service: &RouteService,
 ) -> Result<Response<Full<Bytes>>, hyper::http::Error> {
     show_message!(
         MessageType::Info,
         Message {
-            action: "POST".to_string(),
-            details: route.to_str().unwrap().to_string().to_string(),
+            action: req.method().to_string(),
+            details: route.display().to_string(),
         }
     );
-    if route_table.lock().await.contains_key(&route) {
-        let body = req.collect().await.unwrap().to_bytes().to_vec();
 
-        let guard = route_table.lock().await;
-        let topic_name = &guard.get(&route).unwrap().table_name;
+    let not_found = || {
+        error_response(
+            StatusCode::NOT_FOUND,
+            request_id,
+            format!(
+                "No ingest route at /{}, please visit /console to view your routes",
+                route.display()
+            ),
+        )
+    };
 
-        let res = configured_producer
-            .lock()
-            .await
-            .producer
-            .send(
-                FutureRecord::to(topic_name)
-                    .key(topic_name) // This should probably be generated by the client that pushes data to the API
-                    .payload(&body),
-                Timeout::After(Duration::from_secs(1)),
-            )
+    let Some(route_meta) = route_meta else {
+        return not_found();
+    };
+
+    let header = |name: &str| {
+        req.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_string())
+    };
+    let content_type = header(CONTENT_TYPE.as_str());
+    let content_encoding = header(CONTENT_ENCODING.as_str());
+    let idempotency_key = header(IDEMPOTENCY_KEY_HEADER).map(|key| dedup_key(&route, &key));
+    let metadata = IngestMetadata {
+        ingested_at: SystemTime::now(),
+        request_id: request_id.to_string(),
+        source_ip: service.client_addr.ip().to_string(),
+        user_agent: header(USER_AGENT.as_str()),
+        sdk_version: header(SDK_VERSION_HEADER),
+    };
+
+    let body = match read_body(req, service.max_body_bytes).await {
+        Ok(body) => body,
+        Err((status, message)) => return error_response(status, request_id, message),
+    };
+    service
+        .metrics
+        .record_payload(&route.display().to_string(), body.len());
+
+    let body = match content_encoding {
+        Some(content_encoding) => {
+            let max_bytes = service.max_decompressed_bytes;
+            // Decompressing is CPU bound, so it's kept off the threads that serve requests
+            let decompressed = tokio::task::spawn_blocking(move || {
+                compression::decompress(body, Some(&content_encoding), max_bytes)
+            })
             .await;
 
-        match res {
-            Ok(_) => {
-                show_message!(
-                    MessageType::Success,
-                    Message {
-                        action: "SUCCESS".to_string(),
-                        details: route.to_str().unwrap().to_string(),
-                    }
-                );
-                Ok(Response::new(Full::new(Bytes::from("SUCCESS"))))
+            match decompressed {
+                Ok(Ok(body)) => body,
+                Ok(Err(e)) => {
+                    let status = match e {
+                        DecompressionError::UnsupportedEncoding(_) => {
+                            StatusCode::UNSUPPORTED_MEDIA_TYPE
+                        }
+                        DecompressionError::TooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+                        DecompressionError::Corrupt(_) => StatusCode::BAD_REQUEST,
+                    };
+                    return error_response(status, request_id, e.to_string());
+                }
+                Err(e) => {
+                    error!("Failed to decompress request {}: {}", request_id, e);
+                    return error_response(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        request_id,
+                        "Failed to decompress the request body".to_string(),
+                    );
+                }
             }
-            Err(e) => {
-                println!("Error: {:?}", e);
-                Ok(Response::new(Full::new(Bytes::from("Error"))))
+        }
+        None => body,
+    };
+
+    // The route may have been removed or reloaded while the body was read. The meta is shared, so
+    // the watcher can change the route table while the records are produced.
+    let route_meta = match service.route_table.read().await.get(&route) {
+        Some(current) if Arc::ptr_eq(current, &route_meta) => route_meta,
+        Some(current) => current.clone(),
+        None => return not_found(),
+    };
+    let topic_name = &route_meta.table_name;
+
+    // CSV and form bodies are read into records whose values are all strings
+    let text_encoded = ingest::is_text_encoded(content_type.as_deref());
+    let batch = match ingest::parse_batch(&body, content_type.as_deref()) {
+        Ok(batch) => batch,
+        Err(e) => return bad_request(&route, request_id, e),
+    };
+
+    if let Some(mut records) = batch {
+        for record in records.iter_mut().flatten() {
+            shape_record(record, &route_meta, text_encoded, &metadata);
+        }
+
+        let permit = match service.reserve_records(records.len()) {
+            Some(permit) => permit,
+            None => return queue_full(request_id),
+        };
+
+        if let Some(key) = &idempotency_key {
+            if let Some(response) = key_conflict(service, key, request_id) {
+                return response;
             }
         }
+
+        return ingest_batch(
+            records,
+            &route_meta,
+            &route,
+            request_id,
+            service,
+            permit,
+            idempotency_key,
+        )
+        .await;
+    }
+
+    // JSON records are forwarded as sent unless they have to be read or changed here
+    let maps_fields = route_meta
+        .ingestion_point
+        .as_ref()
+        .map_or(false, |ingestion_point| ingestion_point.maps_fields());
+    let record = if route_meta.metadata_columns.is_empty()
+        && route_meta.dedup_field.is_none()
+        && !maps_fields
+        && !text_encoded
+    {
+        None
     } else {
-        Response::builder()
-            .status(StatusCode::NOT_FOUND)
-            .body(Full::new(Bytes::from(
-                "Please visit /console to view your routes",
-            )))
+        match ingest::parse_record(&body, content_type.as_deref()) {
+            Ok(mut record) => {
+                shape_record(&mut record, &route_meta, text_encoded, &metadata);
+                Some(record)
+            }
+            Err(e) => return bad_request(&route, request_id, e),
+        }
+    };
+
+    let encoded = match &record {
+        Some(record) => ingest::encode_record(&route_meta.codec, &route_meta.data_model, record),
+        None => ingest::encode_payload(&route_meta.codec, &route_meta.data_model, &body),
+    };
+    let payload = match encoded {
+        Ok(payload) => payload,
+        Err(e) => return bad_request(&route, request_id, e),
+    };
+
+    let permit = match service.reserve_records(1) {
+        Some(permit) => permit,
+        None => return queue_full(request_id),
+    };
+
+    let key = idempotency_key.or_else(|| {
+        let field = route_meta.dedup_field.as_ref()?;
+        dedup::dedup_value(record.as_ref()?, field).map(|key| dedup_key(&route, &key))
+    });
+    if let Some(key) = &key {
+        if let Some(response) = key_conflict(service, key, request_id) {
+            return response;
+        }
+    }
+
+    if route_meta.delivery == DeliveryMode::FireAndForget {
+        // The record is produced after the response is sent, and still counts as in flight
+        let service = service.clone();
+        let topic_name = topic_name.clone();
+        let request = service.requests.track();
+        show_message!(
+            MessageType::Success,
+            Message {
+                action: "ACCEPTED".to_string(),
+                details: route.display().to_string(),
+            }
+        );
+        tokio::spawn(async move {
+            if let Err(e) = deliver(&service, &route, &topic_name, &payload, key.as_deref()).await {
+                error!("Failed to produce accepted record to {}: {}", topic_name, e);
+            }
+            drop((permit, request));
+        });
+        return json_response(
+            StatusCode::ACCEPTED,
+            json!({ "status": "accepted", "request_id": request_id }),
+        );
+    }
+
+    let res = deliver(service, &route, topic_name, &payload, key.as_deref()).await;
+    drop(permit);
+
+    match res {
+        Ok(_) => {
+            show_message!(
+                MessageType::Success,
+                Message {
+                    action: "SUCCESS".to_string(),
+                    details: route.display().to_string(),
+                }
+            );
+            json_response(
+                StatusCode::OK,
+                json!({ "status": "ok", "request_id": request_id }),
+            )
+        }
+        Err(e) => stream_error_response(request_id, &e),
     }
 }
 
 async fn console_route(
+    request_id: &str,
     configured_db_client: Arc<Mutex<ConfiguredDBClient>>,
-    configured_producer: Arc<Mutex<ConfiguredProducer>>,
-    route_table: Arc<Mutex<HashMap<PathBuf, RouteMeta>>>,
+    streaming_backend: Arc<dyn StreamingBackend>,
+    route_table: Arc<RwLock<HashMap<PathBuf, Arc<RouteMeta>>>>,
 ) -> Result<Response<Full<Bytes>>, hyper::http::Error> {
     show_message!(
         MessageType::Info,
@@ -194,96 +992,264 @@ async fn console_route(
     );
 
     let db_guard = configured_db_client.lock().await;
-    let producer_guard = configured_producer.lock().await;
-    let route_table_guard = route_table.lock().await;
 
-    let tables = olap::clickhouse::fetch_all_tables(&db_guard).await.unwrap();
-    let topics = redpanda::fetch_topics(&producer_guard.config)
+    // ClickHouse isn't running with streaming engines that don't feed it
+    let tables = olap::clickhouse::fetch_all_tables(&db_guard)
+        .await
+        .unwrap_or_else(|e| {
+            debug!("Failed to fetch tables: {}", e);
+            vec![]
+        });
+    let topics = match streaming_backend.list_topics().await {
+        Ok(topics) => topics,
+        Err(e) => return stream_error_response(request_id, &e),
+    };
+    let routes_table: Vec<RouteInfo> = route_table
+        .read()
         .await
-        .unwrap();
-    let routes_table: Vec<RouteInfo> = route_table_guard
-        .clone()
         .iter()
         .map(|(k, v)| {
             RouteInfo::new(
-                k.to_str().unwrap().to_string(),
-                v.original_file_path.to_str().unwrap().to_string(),
+                k.display().to_string(),
+                v.original_file_path.display().to_string(),
                 v.table_name.clone(),
                 v.view_name.clone(),
             )
         })
         .collect();
+    // Only set when the spool is enabled
+    let spool = streaming_backend.spool_status().await;
 
-    let response = Response::builder()
-        .status(StatusCode::OK)
-        .header("Access-Control-Allow-Origin", "*")
-        .header("Access-Control-Allow-Methods", "GET")
-        .header(
-            "Access-Control-Allow-Headers",
-            "Content-Type, Baggage, Sentry-Trace",
-        )
-        .body(Full::new(Bytes::from(
-            json!({
-                "tables": tables,
-                "topics": topics,
-                "routes": routes_table
-            })
-            .to_string(),
-        )))?;
-    Ok(response)
+    json_response(
+        StatusCode::OK,
+        json!({
+            "tables": tables,
+            "topics": topics,
+            "routes": routes_table,
+            "spool": spool,
+        }),
+    )
 }
 
 async fn router(
     req: Request<hyper::body::Incoming>,
-    route_table: Arc<Mutex<HashMap<PathBuf, RouteMeta>>>,
-    configured_producer: Arc<Mutex<ConfiguredProducer>>,
-    configured_db_client: Arc<Mutex<ConfiguredDBClient>>,
-) -> Result<Response<Full<Bytes>>, hyper::http::Error> {
+    service: RouteService,
+) -> Result<Response<ResponseBody>, hyper::http::Error> {
+    let started = Instant::now();
     debug!(
         "HTTP Request Received: {:?}, with Route Table {:?}",
-        req, route_table
+        req, service.route_table
     );
 
-    let route_prefix = PathBuf::from("/");
-    let route = PathBuf::from(req.uri().path())
-        .strip_prefix(route_prefix)
-        .unwrap()
-        .to_path_buf()
-        .clone();
+    // Clients can pass their own id to correlate requests with their logs
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| !value.is_empty() && value.len() <= 128)
+        .map(|value| value.to_string())
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+    let path = req.uri().path().trim_start_matches('/').to_string();
+    let route = PathBuf::from(&path);
 
     debug!(
         "Processing route: {:?}, with Route Table {:?}",
-        route, route_table
+        route, service.route_table
     );
 
-    let route_split = route.to_str().unwrap().split('/').collect::<Vec<&str>>();
+    let route_split = path.split('/').collect::<Vec<&str>>();
+    let method = req.method().clone();
+
+    // The methods of the route and whether it's public, when it's served by a data model or an
+    // ingestion point
+    let route_meta = service.route_table.read().await.get(&route).cloned();
+    let route_access = route_meta
+        .as_ref()
+        .map(|route_meta| (route_meta.methods(), route_meta.is_public()));
+    let is_ingest_request = match &route_access {
+        Some((methods, _)) => methods.iter().any(|allowed| allowed == method.as_str()),
+        // Unknown ingest routes are told apart from other paths with a message of their own
+        None => method == hyper::Method::POST && matches!(route_split[..], ["ingest", _]),
+    };
+
+    // Unknown paths share a label so that they can't grow the number of series
+    let metrics_route = match &route_split[..] {
+        _ if route_access.is_some() => path.as_str(),
+        ["console"]
+        | ["console", "routes" | "tables"]
+        | ["openapi.json"]
+        | ["metrics"]
+        | ["health"]
+        | ["ready"]
+        | ["events"] => path.as_str(),
+        _ => UNMATCHED_ROUTE,
+    };
+
+    let record_request = |status: StatusCode| {
+        service.metrics.record_request(
+            metrics_route,
+            method.as_str(),
+            status.as_u16(),
+            started.elapsed(),
+        )
+    };
 
-    match (req.method(), &route_split[..]) {
-        (&hyper::Method::POST, ["ingest", _]) => {
-            ingest_route(req, route, configured_producer, route_table).await
+    if service.requests.is_draining() {
+        let mut response = shutting_down(&request_id)?;
+        add_common_headers(response.headers_mut(), &request_id);
+        record_request(response.status());
+        return Ok(response.map(BodyExt::boxed));
+    }
+
+    // Preflight requests and probes never carry credentials and aren't rate limited
+    let is_probe = matches!(route_split[..], ["health"] | ["ready"]);
+    if req.method() != hyper::Method::OPTIONS && !is_probe {
+        let api_key = request_api_key(req.headers());
+
+        // Public ingestion points take requests without keys, but are rate limited like any route
+        let authorized = match &route_access {
+            Some((_, true)) => Ok(service.api_keys.authorize(&path, api_key).unwrap_or(None)),
+            _ => service.api_keys.authorize(&path, api_key),
+        };
+        let rejection = match authorized {
+            Err(e) => {
+                debug!("Rejected request {} to /{}: {}", request_id, path, e);
+                Some(unauthorized(&request_id, e))
+            }
+            Ok(key_id) => service
+                .rate_limiter
+                .check(&path, &service.client_id(key_id.as_deref()))
+                .err()
+                .map(|retry_after| {
+                    debug!("Rate limited request {} to /{}", request_id, path);
+                    too_many_requests(&request_id, retry_after)
+                }),
+        };
+
+        if let Some(response) = rejection {
+            let mut response = response?;
+            add_common_headers(response.headers_mut(), &request_id);
+            record_request(response.status());
+            return Ok(response.map(BodyExt::boxed));
         }
+    }
+
+    // Streamed until the client goes away
+    if let (&hyper::Method::GET, ["events"]) = (req.method(), &route_split[..]) {
+        let mut response = Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, EVENT_STREAM_CONTENT_TYPE)
+            .header(CACHE_CONTROL, "no-cache")
+            .body(events::subscribe(&service.events).boxed())?;
+        add_common_headers(response.headers_mut(), &request_id);
+        record_request(response.status());
+        return Ok(response);
+    }
+
+    let response = match (req.method(), &route_split[..]) {
+        _ if is_ingest_request => ingest_route(req, route, route_meta, &request_id, &service).await,
 
         (&hyper::Method::GET, ["console"]) => {
-            console_route(configured_db_client, configured_producer, route_table).await
+            console_route(
+                &request_id,
+                service.configured_db_client.clone(),
+                service.streaming_backend.clone(),
+                service.route_table.clone(),
+            )
+            .await
         }
-        (&hyper::Method::GET, ["console", "routes"]) => {
-            todo!("get all routes");
+        (&hyper::Method::GET, ["openapi.json"]) => {
+            let spec = openapi::build_spec(&*service.route_table.read().await);
+            json_response(StatusCode::OK, spec)
         }
-        (&hyper::Method::GET, ["console", "routes", _route_id]) => {
-            todo!("get specific route");
+        // The process is alive as long as it answers, whatever the state of its dependencies
+        (&hyper::Method::GET, ["health"]) => {
+            json_response(StatusCode::OK, json!({ "status": "ok" }))
         }
-
-        (&hyper::Method::GET, ["console", "tables"]) => {
-            todo!("get all tables");
+        (&hyper::Method::GET, ["ready"]) => {
+            let db_client = service.configured_db_client.lock().await;
+            let (ready, checks) = health::readiness(
+                &db_client,
+                service.streaming_backend.as_ref(),
+                &service.schema_crawl_complete,
+            )
+            .await;
+            if ready {
+                json_response(
+                    StatusCode::OK,
+                    json!({ "status": "ready", "checks": checks }),
+                )
+            } else {
+                json_response(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    json!({ "status": "not_ready", "checks": checks }),
+                )
+            }
         }
-        (&hyper::Method::GET, ["console", "tables", _table_name]) => {
-            todo!("get specific table");
+        (&hyper::Method::GET, ["metrics"]) => {
+            let route_table_size = service.route_table.read().await.len();
+            Response::builder()
+                .status(StatusCode::OK)
+                .header(CONTENT_TYPE, METRICS_CONTENT_TYPE)
+                .body(Full::new(Bytes::from(
+                    service.metrics.render(route_table_size),
+                )))
         }
+        (&hyper::Method::GET, ["console", "routes" | "tables", ..]) => error_response(
+            StatusCode::NOT_IMPLEMENTED,
+            &request_id,
+            format!("/{} is not available yet", path),
+        ),
 
         (&hyper::Method::OPTIONS, _) => options_route(),
-        _ => Response::builder()
-            .status(StatusCode::NOT_FOUND)
-            .body(Full::new(Bytes::from("no match"))),
+        (method, _) if route_access.is_some() => {
+            let methods = route_access.map(|(methods, _)| methods).unwrap_or_default();
+            method_not_allowed(&request_id, method, &path, &methods)
+        }
+        _ => error_response(
+            StatusCode::NOT_FOUND,
+            &request_id,
+            format!("No route matches {} /{}", req.method(), path),
+        ),
+    };
+
+    let mut response = response?;
+    add_common_headers(response.headers_mut(), &request_id);
+    record_request(response.status());
+
+    if is_ingest_request {
+        let (route, status, request_id) = (path.clone(), response.status().as_u16(), request_id);
+        service.events.publish(if response.status().is_success() {
+            ServerEvent::Ingest {
+                route,
+                status,
+                request_id,
+            }
+        } else {
+            ServerEvent::IngestError {
+                route,
+                status,
+                request_id,
+            }
+        });
+    }
+    Ok(response.map(BodyExt::boxed))
+}
+
+async fn serve_connection<I>(stream: I, service: RouteService, watcher: Watcher)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // Use an adapter to access something implementing `tokio::io` traits as if they implement
+    // `hyper::rt` IO traits.
+    let io = TokioIo::new(stream);
+
+    // HTTP/2 is picked by ALPN over TLS, or by the connection preface of clients that know it's served
+    let builder = auto::Builder::new(TokioExecutor::new());
+    // Once the server drains, the connection is closed as soon as its current request is answered
+    if let Err(e) = watcher.watch(builder.serve_connection(io, service)).await {
+        error!("server error: {}", e);
     }
 }
 
@@ -308,18 +1274,51 @@ impl Webserver {
 
     pub async fn start(
         &self,
-        route_table: Arc<Mutex<HashMap<PathBuf, RouteMeta>>>,
+        route_table: Arc<RwLock<HashMap<PathBuf, Arc<RouteMeta>>>>,
+        streaming_backend: Arc<dyn StreamingBackend>,
         project: &Project,
-    ) {
-        //! Starts the local webserver
+        schema_crawl_complete: Arc<AtomicBool>,
+        events: EventBus,
+    ) -> Result<(), std::io::Error> {
+        //! Starts the local webserver, and returns once it received SIGINT or SIGTERM and the
+        //! requests in flight finished or timed out. Fails without serving anything when TLS can't
+        //! be set up or the port can't be bound.
+        let tls_config = &project.local_webserver_config.tls;
+        let tls_acceptor = if tls_config.enabled {
+            let tls_acceptor = tls::acceptor(tls_config, &self.host, &project.project_dir())
+                .map_err(|e| {
+                    std::io::Error::new(
+                        e.kind(),
+                        format!("Failed to set up TLS for the webserver: {}", e),
+                    )
+                })?;
+            Some(tls_acceptor)
+        } else {
+            None
+        };
+
         let socket = self.socket().await;
 
         // We create a TcpListener and bind it to 127.0.0.1:3000
-        let listener = TcpListener::bind(socket).await.unwrap();
+        let listener = TcpListener::bind(socket).await.map_err(|e| {
+            std::io::Error::new(e.kind(), format!("Failed to listen on {}: {}", socket, e))
+        })?;
+
+        let max_body_bytes = project.local_webserver_config.max_body_bytes;
+        let max_decompressed_bytes = project.local_webserver_config.max_decompressed_bytes;
+        let max_in_flight_records = project.local_webserver_config.max_in_flight_records.max(1);
+        let api_keys = Arc::new(ApiKeys::load(project));
+        let rate_limiter = Arc::new(RateLimiter::new(
+            project.local_webserver_config.rate_limits.clone(),
+        ));
+        let in_flight_records = Arc::new(Semaphore::new(max_in_flight_records));
+        let idempotency = Arc::new(IdempotencyCache::new(&project.ingest_config.deduplication));
+        let metrics = Arc::new(Metrics::default());
+        let requests = Arc::new(RequestTracker::default());
+        let connections = GracefulShutdown::new();
+        let shutdown_timeout =
+            Duration::from_secs(project.local_webserver_config.shutdown_timeout_secs);
 
-        let producer = Arc::new(Mutex::new(redpanda::create_producer(
-            project.redpanda_config.clone(),
-        )));
         let db_client = Arc::new(Mutex::new(olap::clickhouse::create_client(
             project.clickhouse_config.clone(),
         )));
@@ -339,43 +1338,79 @@ impl Webserver {
 
         loop {
             tokio::select! {
-                _ = sigint.recv() => {
-                    let run_mode = RunMode::Explicit;
-                    StopLocalInfrastructure::new(run_mode).run(run_mode).unwrap();
-                    std::process::exit(0);
-                }
-                _ = sigterm.recv() => {
-                    let run_mode = RunMode::Explicit;
-                    StopLocalInfrastructure::new(run_mode).run(run_mode).unwrap();
-                    std::process::exit(0);
-                }
+                _ = sigint.recv() => break,
+                _ = sigterm.recv() => break,
                 listener_result = listener.accept() => {
-                    let (stream, _) = listener_result.unwrap();
-                    // Use an adapter to access something implementing `tokio::io` traits as if they implement
-                    // `hyper::rt` IO traits.
-                    let io = TokioIo::new(stream);
+                    let (stream, client_addr) = listener_result.unwrap();
 
-                    let route_table = route_table.clone();
-                    let producer = producer.clone();
-                    let db_client = db_client.clone();
+                    let service = RouteService {
+                        route_table: route_table.clone(),
+                        streaming_backend: streaming_backend.clone(),
+                        configured_db_client: db_client.clone(),
+                        api_keys: api_keys.clone(),
+                        rate_limiter: rate_limiter.clone(),
+                        idempotency: idempotency.clone(),
+                        metrics: metrics.clone(),
+                        schema_crawl_complete: schema_crawl_complete.clone(),
+                        events: events.clone(),
+                        requests: requests.clone(),
+                        in_flight_records: in_flight_records.clone(),
+                        max_in_flight_records,
+                        max_body_bytes,
+                        max_decompressed_bytes,
+                        client_addr,
+                    };
+                    let tls_acceptor = tls_acceptor.clone();
+                    let watcher = connections.watcher();
 
                     // Spawn a tokio task to serve multiple connections concurrently
                     tokio::task::spawn(async move {
-                        // Run this server for... forever!
-                        if let Err(e) = auto::Builder::new(TokioExecutor::new()).serve_connection(
-                                io,
-                                RouteService {
-                                    route_table,
-                                    configured_producer: producer,
-                                    configured_db_client: db_client,
-                                },
-                            ).await {
-                                error!("server error: {}", e);
-                            }
-
+                        match tls_acceptor {
+                            Some(tls_acceptor) => match tls::accept(&tls_acceptor, stream).await {
+                                Ok(stream) => serve_connection(stream, service, watcher).await,
+                                Err(e) => debug!("TLS handshake with {} failed: {}", client_addr, e),
+                            },
+                            None => serve_connection(stream, service, watcher).await,
+                        }
                     });
                 }
             }
         }
+
+        // No new connections are accepted from here on
+        drop(listener);
+        requests.start_draining();
+        // Idle keep-alive connections close right away, the others after their current request.
+        // Event streams keep their connection open, so the server doesn't wait for all of them.
+        tokio::spawn(connections.shutdown());
+
+        show_message!(
+            MessageType::Info,
+            Message {
+                action: "Stopping".to_string(),
+                details: format!(
+                    "server, waiting for {} requests in flight",
+                    requests.in_flight()
+                ),
+            }
+        );
+
+        if tokio::time::timeout(shutdown_timeout, requests.wait_idle())
+            .await
+            .is_err()
+        {
+            show_message!(
+                MessageType::Error,
+                Message {
+                    action: "Stopping".to_string(),
+                    details: format!(
+                        "server with {} requests still in flight after {}s",
+                        requests.in_flight(),
+                        shutdown_timeout.as_secs()
+                    ),
+                }
+            );
+        }
+        Ok(())
     }
 }
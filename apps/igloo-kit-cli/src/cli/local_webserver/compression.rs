@@ -0,0 +1,183 @@
+//! # Compression
+//! Decodes request bodies sent with a `Content-Encoding` of `gzip`, `zstd` or `br`. Bodies are
+//! decompressed up to a configured size so that a small compressed body can't expand into an
+//! unbounded amount of memory.
+//!
+//! ## Suggested Improvements
+//! - stream the decompressed body into the batch parser instead of buffering it
+//! - support `deflate`
+
+use std::{
+    fmt,
+    io::{Error, Read},
+};
+
+#[derive(Debug)]
+pub enum DecompressionError {
+    UnsupportedEncoding(String),
+    TooLarge { max_bytes: usize },
+    Corrupt(Error),
+}
+
+impl fmt::Display for DecompressionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecompressionError::UnsupportedEncoding(encoding) => write!(
+                f,
+                "The content encoding {} is not supported, use gzip, zstd or br",
+                encoding
+            ),
+            DecompressionError::TooLarge { max_bytes } => write!(
+                f,
+                "The decompressed request body is larger than {} bytes",
+                max_bytes
+            ),
+            DecompressionError::Corrupt(e) => {
+                write!(f, "Failed to decompress the request body: {}", e)
+            }
+        }
+    }
+}
+
+fn decode(encoding: &str, body: &[u8], max_bytes: usize) -> Result<Vec<u8>, DecompressionError> {
+    let decoder: Box<dyn Read + '_> = match encoding {
+        "gzip" | "x-gzip" => Box::new(flate2::read::MultiGzDecoder::new(body)),
+        "zstd" => {
+            Box::new(zstd::stream::read::Decoder::new(body).map_err(DecompressionError::Corrupt)?)
+        }
+        "br" => Box::new(brotli::Decompressor::new(body, 4096)),
+        _ => {
+            return Err(DecompressionError::UnsupportedEncoding(
+                encoding.to_string(),
+            ))
+        }
+    };
+
+    // One byte past the limit is enough to tell that the body is too large
+    let mut decompressed = vec![];
+    decoder
+        .take(max_bytes as u64 + 1)
+        .read_to_end(&mut decompressed)
+        .map_err(DecompressionError::Corrupt)?;
+
+    if decompressed.len() > max_bytes {
+        return Err(DecompressionError::TooLarge { max_bytes });
+    }
+    Ok(decompressed)
+}
+
+pub fn decompress(
+    body: Vec<u8>,
+    content_encoding: Option<&str>,
+    max_bytes: usize,
+) -> Result<Vec<u8>, DecompressionError> {
+    //! Undoes the encodings of the body, listed in the order they were applied.
+    let encodings = content_encoding
+        .map(|content_encoding| {
+            content_encoding
+                .split(',')
+                .map(|encoding| encoding.trim().to_lowercase())
+                .filter(|encoding| !encoding.is_empty() && encoding != "identity")
+                .collect::<Vec<String>>()
+        })
+        .unwrap_or_default();
+
+    encodings
+        .iter()
+        .rev()
+        .try_fold(body, |body, encoding| decode(encoding, &body, max_bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn gzip(body: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(body).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn zstd(body: &[u8]) -> Vec<u8> {
+        zstd::stream::encode_all(body, 0).unwrap()
+    }
+
+    fn brotli(body: &[u8]) -> Vec<u8> {
+        let mut compressed = vec![];
+        {
+            let mut encoder = brotli::CompressorWriter::new(&mut compressed, 4096, 5, 22);
+            encoder.write_all(body).unwrap();
+        }
+        compressed
+    }
+
+    #[test]
+    fn decodes_every_supported_encoding() {
+        let body = br#"{"id": 1}"#.repeat(100);
+        for (encoding, compressed) in [
+            ("gzip", gzip(&body)),
+            ("x-gzip", gzip(&body)),
+            ("zstd", zstd(&body)),
+            ("br", brotli(&body)),
+        ] {
+            assert_eq!(
+                decompress(compressed, Some(encoding), body.len()).unwrap(),
+                body,
+                "{}",
+                encoding
+            );
+        }
+    }
+
+    #[test]
+    fn undoes_stacked_encodings_in_reverse_order() {
+        let body = b"stacked".to_vec();
+        let compressed = brotli(&zstd(&gzip(&body)));
+
+        assert_eq!(
+            decompress(compressed.clone(), Some("gzip, identity, ZSTD, br"), 1024).unwrap(),
+            body
+        );
+        // Applied in the wrong order, the outer layer isn't gzip
+        assert!(matches!(
+            decompress(compressed, Some("br, zstd, gzip"), 1024),
+            Err(DecompressionError::Corrupt(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_bodies_past_the_limit() {
+        let body = vec![b'a'; 1000];
+
+        assert_eq!(decompress(gzip(&body), Some("gzip"), 1000).unwrap(), body);
+        assert!(matches!(
+            decompress(gzip(&body), Some("gzip"), 999),
+            Err(DecompressionError::TooLarge { max_bytes: 999 })
+        ));
+
+        // Every layer is held to the limit
+        let stacked = gzip(&gzip(&body));
+        assert!(matches!(
+            decompress(stacked, Some("gzip, gzip"), 999),
+            Err(DecompressionError::TooLarge { max_bytes: 999 })
+        ));
+    }
+
+    #[test]
+    fn rejects_unknown_encodings_and_corrupt_bodies() {
+        assert!(matches!(
+            decompress(b"body".to_vec(), Some("deflate"), 1024),
+            Err(DecompressionError::UnsupportedEncoding(encoding)) if encoding == "deflate"
+        ));
+        assert!(matches!(
+            decompress(b"not gzip".to_vec(), Some("gzip"), 1024),
+            Err(DecompressionError::Corrupt(_))
+        ));
+        assert_eq!(
+            decompress(b"plain".to_vec(), None, 1024).unwrap(),
+            b"plain".to_vec()
+        );
+    }
+}
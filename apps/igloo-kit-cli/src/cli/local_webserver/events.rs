@@ -0,0 +1,157 @@
+//! # Events
+//! A push channel for the console and CLI tools, served at `/events` as Server-Sent Events. The
+//! webserver publishes what happens on the ingest routes, and the watcher publishes schema changes,
+//! recreated tables, rebuilt SDKs and routes that were added or removed. Every event is sent with
+//! its type as the SSE event name and as JSON data:
+//!
+//! ```text
+//! event: route_added
+//! data: {"type":"route_added","route":"ingest/UserActivity"}
+//! ```
+//!
+//! Events are only sent to the clients connected when they happen. Clients that fall too far
+//! behind get a `lagged` event with the number of events they missed.
+//!
+//! ## Suggested Improvements
+//! - let clients filter the event types they receive
+//! - replay recent events to clients that reconnect with `Last-Event-ID`
+//! - serve the same events over a WebSocket
+
+use std::{
+    convert::Infallible,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use hyper::body::{Body, Bytes, Frame};
+use serde::Serialize;
+use serde_json::json;
+use tokio::sync::{broadcast, mpsc};
+
+pub const EVENT_STREAM_CONTENT_TYPE: &str = "text/event-stream";
+
+// Events a slow client can fall behind by before it starts missing some
+const EVENT_BUFFER: usize = 1024;
+
+// Comments are sent this often so that proxies don't close idle streams
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerEvent {
+    Ingest {
+        route: String,
+        status: u16,
+        request_id: String,
+    },
+    IngestError {
+        route: String,
+        status: u16,
+        request_id: String,
+    },
+    SchemaChanged {
+        file: String,
+    },
+    TableRecreated {
+        table: String,
+    },
+    SdkRebuilt {
+        location: String,
+    },
+    RouteAdded {
+        route: String,
+    },
+    RouteRemoved {
+        route: String,
+    },
+    WatcherError {
+        file: String,
+        message: String,
+    },
+}
+
+impl ServerEvent {
+    fn name(&self) -> &'static str {
+        match self {
+            ServerEvent::Ingest { .. } => "ingest",
+            ServerEvent::IngestError { .. } => "ingest_error",
+            ServerEvent::SchemaChanged { .. } => "schema_changed",
+            ServerEvent::TableRecreated { .. } => "table_recreated",
+            ServerEvent::SdkRebuilt { .. } => "sdk_rebuilt",
+            ServerEvent::RouteAdded { .. } => "route_added",
+            ServerEvent::RouteRemoved { .. } => "route_removed",
+            ServerEvent::WatcherError { .. } => "watcher_error",
+        }
+    }
+
+    fn to_sse(&self) -> Bytes {
+        let data = serde_json::to_string(self).unwrap_or_else(|_| "{}".to_string());
+        Bytes::from(format!("event: {}\ndata: {}\n\n", self.name(), data))
+    }
+}
+
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<ServerEvent>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_BUFFER);
+        Self { sender }
+    }
+
+    pub fn publish(&self, event: ServerEvent) {
+        // Sending only fails when nobody listens, which is fine
+        let _ = self.sender.send(event);
+    }
+}
+
+pub struct EventStreamBody {
+    frames: mpsc::Receiver<Bytes>,
+}
+
+impl Body for EventStreamBody {
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        self.frames
+            .poll_recv(cx)
+            .map(|frame| frame.map(|data| Ok(Frame::data(data))))
+    }
+}
+
+pub fn subscribe(events: &EventBus) -> EventStreamBody {
+    //! Starts streaming the events published from now on. The stream ends when the client goes away.
+    let mut receiver = events.sender.subscribe();
+    let (frames, body) = mpsc::channel(16);
+
+    tokio::spawn(async move {
+        let mut keep_alive = tokio::time::interval(KEEP_ALIVE_INTERVAL);
+        loop {
+            let frame = tokio::select! {
+                event = receiver.recv() => match event {
+                    Ok(event) => event.to_sse(),
+                    Err(broadcast::error::RecvError::Lagged(missed)) => Bytes::from(format!(
+                        "event: lagged\ndata: {}\n\n",
+                        json!({ "type": "lagged", "missed": missed })
+                    )),
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                _ = keep_alive.tick() => Bytes::from_static(b": keep-alive\n\n"),
+            };
+
+            // The body was dropped, so the client is gone
+            if frames.send(frame).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    EventStreamBody { frames: body }
+}
@@ -0,0 +1,107 @@
+//! # Health
+//! Checks behind `/ready`, which only answers with a 200 once ClickHouse runs a query, the stream
+//! returns its metadata and the initial crawl of the schemas directory has finished, so that
+//! scripts and orchestrators can wait for `igloo dev` before sending data. It reports every
+//! dependency with the time its check took. `/health` answers right away as long as the process
+//! serves requests, without checking anything.
+//!
+//! Neither route requires an API key nor is rate limited.
+//!
+//! ## Suggested Improvements
+//! - cache the results for a moment so that frequent probes don't hit the dependencies every time
+//! - report the flows that failed to start
+
+use std::{
+    future::Future,
+    io::{Error, ErrorKind},
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use serde_json::{json, Value};
+
+use crate::infrastructure::{
+    olap::{self, clickhouse::ConfiguredDBClient},
+    stream::StreamingBackend,
+};
+
+// A dependency that takes longer than this to answer is reported as down
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+struct Check {
+    ok: bool,
+    latency: Duration,
+    error: Option<String>,
+}
+
+impl Check {
+    fn to_json(&self) -> Value {
+        let mut check = json!({
+            "status": if self.ok { "ok" } else { "error" },
+            "latency_ms": self.latency.as_secs_f64() * 1000.0,
+        });
+        if let Some(error) = &self.error {
+            check["error"] = json!(error);
+        }
+        check
+    }
+}
+
+async fn timed<F>(check: F) -> Check
+where
+    F: Future<Output = Result<(), Error>>,
+{
+    let started = Instant::now();
+    let result = match tokio::time::timeout(CHECK_TIMEOUT, check).await {
+        Ok(result) => result,
+        Err(_) => Err(Error::new(
+            ErrorKind::TimedOut,
+            format!("No answer within {}ms", CHECK_TIMEOUT.as_millis()),
+        )),
+    };
+
+    Check {
+        ok: result.is_ok(),
+        latency: started.elapsed(),
+        error: result.err().map(|e| e.to_string()),
+    }
+}
+
+async fn check_clickhouse(db_client: &ConfiguredDBClient) -> Result<(), Error> {
+    olap::clickhouse::run_query("SELECT 1".to_string(), db_client)
+        .await
+        .map_err(|e| Error::new(ErrorKind::NotConnected, e.to_string()))
+}
+
+async fn check_stream(streaming_backend: &dyn StreamingBackend) -> Result<(), Error> {
+    streaming_backend.list_topics().await.map(|_| ())
+}
+
+pub async fn readiness(
+    db_client: &ConfiguredDBClient,
+    streaming_backend: &dyn StreamingBackend,
+    schema_crawl_complete: &AtomicBool,
+) -> (bool, Value) {
+    //! Checks every dependency concurrently. Returns whether all of them are up, along with a
+    //! report of each of them.
+    let (clickhouse, stream) = tokio::join!(
+        timed(check_clickhouse(db_client)),
+        timed(check_stream(streaming_backend)),
+    );
+    let crawled = schema_crawl_complete.load(Ordering::Acquire);
+    let schema_crawl = Check {
+        ok: crawled,
+        latency: Duration::ZERO,
+        error: (!crawled).then(|| "The schemas directory is still being crawled".to_string()),
+    };
+
+    let ready = clickhouse.ok && stream.ok && schema_crawl.ok;
+    (
+        ready,
+        json!({
+            "clickhouse": clickhouse.to_json(),
+            "stream": stream.to_json(),
+            "schema_crawl": schema_crawl.to_json(),
+        }),
+    )
+}
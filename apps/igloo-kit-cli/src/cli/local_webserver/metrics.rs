@@ -0,0 +1,230 @@
+//! # Metrics
+//! Counters and histograms of the local webserver, served at `/metrics` in the Prometheus text
+//! format. Requests are counted by route, method and status, and the ingest routes also record the
+//! size of the bodies they receive and how long the stream took to acknowledge their records.
+//!
+//! Requests to paths that don't match any route are counted under the `unmatched` route, so that
+//! random paths can't grow the number of series.
+//!
+//! ## Suggested Improvements
+//! - expose the producer queue depth and the rate limiter rejections
+//! - make the histogram buckets configurable
+
+use std::{collections::BTreeMap, fmt::Write, sync::Mutex, time::Duration};
+
+pub const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+pub const UNMATCHED_ROUTE: &str = "unmatched";
+
+const DURATION_BUCKETS: &[f64] = &[
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+const SIZE_BUCKETS: &[f64] = &[
+    256.0, 1024.0, 4096.0, 16384.0, 65536.0, 262144.0, 1048576.0, 4194304.0, 16777216.0,
+];
+
+struct Histogram {
+    bounds: &'static [f64],
+    // Observations by bucket, made cumulative when rendered
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        if let Some(index) = self.bounds.iter().position(|bound| value <= *bound) {
+            self.counts[index] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+#[derive(Default)]
+struct MetricsState {
+    requests: BTreeMap<(String, String, u16), u64>,
+    request_durations: BTreeMap<String, Histogram>,
+    payload_sizes: BTreeMap<String, Histogram>,
+    produced_records: BTreeMap<String, u64>,
+    producer_errors: BTreeMap<String, u64>,
+    delivery_durations: BTreeMap<String, Histogram>,
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    state: Mutex<MetricsState>,
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn write_counters(out: &mut String, name: &str, help: &str, counters: &BTreeMap<String, u64>) {
+    write_header(out, name, "counter", help);
+    for (route, value) in counters {
+        let _ = writeln!(
+            out,
+            "{}{{route=\"{}\"}} {}",
+            name,
+            escape_label(route),
+            value
+        );
+    }
+}
+
+fn write_histograms(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    histograms: &BTreeMap<String, Histogram>,
+) {
+    write_header(out, name, "histogram", help);
+    for (route, histogram) in histograms {
+        let route = escape_label(route);
+        let mut cumulative = 0;
+        for (bound, count) in histogram.bounds.iter().zip(&histogram.counts) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "{}_bucket{{route=\"{}\",le=\"{}\"}} {}",
+                name, route, bound, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{route=\"{}\",le=\"+Inf\"}} {}",
+            name, route, histogram.count
+        );
+        let _ = writeln!(out, "{}_sum{{route=\"{}\"}} {}", name, route, histogram.sum);
+        let _ = writeln!(
+            out,
+            "{}_count{{route=\"{}\"}} {}",
+            name, route, histogram.count
+        );
+    }
+}
+
+impl Metrics {
+    fn state(&self) -> std::sync::MutexGuard<'_, MetricsState> {
+        match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    pub fn record_request(&self, route: &str, method: &str, status: u16, duration: Duration) {
+        let mut state = self.state();
+        *state
+            .requests
+            .entry((route.to_string(), method.to_string(), status))
+            .or_default() += 1;
+        state
+            .request_durations
+            .entry(route.to_string())
+            .or_insert_with(|| Histogram::new(DURATION_BUCKETS))
+            .observe(duration.as_secs_f64());
+    }
+
+    pub fn record_payload(&self, route: &str, bytes: usize) {
+        self.state()
+            .payload_sizes
+            .entry(route.to_string())
+            .or_insert_with(|| Histogram::new(SIZE_BUCKETS))
+            .observe(bytes as f64);
+    }
+
+    pub fn record_delivery(&self, route: &str, produced: usize, errors: usize, duration: Duration) {
+        //! Records the outcome of a produce call, which may have carried several records.
+        let mut state = self.state();
+        *state.produced_records.entry(route.to_string()).or_default() += produced as u64;
+        *state.producer_errors.entry(route.to_string()).or_default() += errors as u64;
+        state
+            .delivery_durations
+            .entry(route.to_string())
+            .or_insert_with(|| Histogram::new(DURATION_BUCKETS))
+            .observe(duration.as_secs_f64());
+    }
+
+    pub fn render(&self, route_table_size: usize) -> String {
+        //! Renders every metric in the Prometheus text exposition format.
+        let state = self.state();
+        let mut out = String::new();
+
+        write_header(
+            &mut out,
+            "igloo_http_requests_total",
+            "counter",
+            "Requests handled by the webserver",
+        );
+        for ((route, method, status), value) in &state.requests {
+            let _ = writeln!(
+                out,
+                "igloo_http_requests_total{{route=\"{}\",method=\"{}\",status=\"{}\"}} {}",
+                escape_label(route),
+                escape_label(method),
+                status,
+                value
+            );
+        }
+
+        write_histograms(
+            &mut out,
+            "igloo_http_request_duration_seconds",
+            "Time taken to handle a request",
+            &state.request_durations,
+        );
+        write_histograms(
+            &mut out,
+            "igloo_ingest_payload_bytes",
+            "Size of the request bodies received by the ingest routes, as sent",
+            &state.payload_sizes,
+        );
+        write_counters(
+            &mut out,
+            "igloo_ingest_records_produced_total",
+            "Records acknowledged by the stream",
+            &state.produced_records,
+        );
+        write_counters(
+            &mut out,
+            "igloo_producer_errors_total",
+            "Records the stream failed to acknowledge",
+            &state.producer_errors,
+        );
+        write_histograms(
+            &mut out,
+            "igloo_producer_delivery_duration_seconds",
+            "Time taken by the stream to acknowledge the records of a request",
+            &state.delivery_durations,
+        );
+
+        write_header(
+            &mut out,
+            "igloo_route_table_size",
+            "gauge",
+            "Ingest routes currently served",
+        );
+        let _ = writeln!(out, "igloo_route_table_size {}", route_table_size);
+
+        out
+    }
+}
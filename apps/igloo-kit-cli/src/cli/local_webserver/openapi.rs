@@ -0,0 +1,485 @@
+//! # OpenAPI
+//! Builds the OpenAPI 3 document served at `/openapi.json` from the route table, so it always
+//! reflects the routes the watcher has created so far. Every data model becomes a schema built from
+//! its columns, the same way the ingest routes validate records. Ingestion points are listed with
+//! the methods they take, and describe a free-form body when they map fields. The operational
+//! routes of the webserver are listed as well.
+//!
+//! ## Suggested Improvements
+//! - describe the wire formats other than JSON
+//! - add examples generated from the column types
+
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
+
+use schema_ast::ast::FieldArity;
+use serde_json::{json, Map, Value};
+
+use crate::{
+    framework::{
+        controller::RouteMeta,
+        schema::{Column, ColumnType, Table},
+    },
+    infrastructure::ingest::{
+        adapters::{CSV_CONTENT_TYPE, FORM_CONTENT_TYPE},
+        NDJSON_CONTENT_TYPE,
+    },
+};
+
+use super::{events::EVENT_STREAM_CONTENT_TYPE, metrics::METRICS_CONTENT_TYPE};
+
+fn column_type_schema(data_type: &ColumnType) -> Value {
+    match data_type {
+        ColumnType::String => json!({ "type": "string" }),
+        ColumnType::Boolean => json!({ "type": "boolean" }),
+        ColumnType::Int => json!({ "type": "integer" }),
+        ColumnType::BigInt => json!({ "type": "integer", "format": "int64" }),
+        ColumnType::Float | ColumnType::Decimal => json!({ "type": "number" }),
+        // Milliseconds since the epoch are accepted as well
+        ColumnType::DateTime => json!({
+            "oneOf": [
+                { "type": "string", "format": "date-time" },
+                { "type": "integer", "format": "int64" },
+            ]
+        }),
+        ColumnType::Json => json!({ "type": "object" }),
+        ColumnType::Bytes | ColumnType::Unsupported => json!({}),
+    }
+}
+
+fn column_schema(column: &Column) -> Value {
+    let mut schema = match column.arity {
+        FieldArity::List => {
+            json!({ "type": "array", "items": column_type_schema(&column.data_type) })
+        }
+        _ => column_type_schema(&column.data_type),
+    };
+    if column.arity == FieldArity::Optional {
+        schema["nullable"] = json!(true);
+    }
+    schema
+}
+
+fn model_schema(data_model: &Table) -> Value {
+    let properties = data_model
+        .columns
+        .iter()
+        .map(|column| (column.name.clone(), column_schema(column)))
+        .collect::<Map<String, Value>>();
+
+    // Columns with a default are filled in by ClickHouse
+    let required = data_model
+        .columns
+        .iter()
+        .filter(|column| column.arity == FieldArity::Required && column.default.is_none())
+        .map(|column| json!(column.name))
+        .collect::<Vec<Value>>();
+
+    json!({
+        "type": "object",
+        "properties": properties,
+        "required": required,
+        "additionalProperties": false,
+    })
+}
+
+fn error_response(description: &str) -> Value {
+    json!({
+        "description": description,
+        "content": { "application/json": { "schema": { "$ref": "#/components/schemas/Error" } } },
+    })
+}
+
+fn ingest_operation(data_model: &Table) -> Value {
+    let model_ref = json!({ "$ref": format!("#/components/schemas/{}", data_model.name) });
+
+    json!({
+        "summary": format!("Ingest {} records", data_model.name),
+        "operationId": format!("ingest{}", data_model.name),
+        "tags": ["ingest"],
+        "parameters": [
+            {
+                "name": "Idempotency-Key",
+                "in": "header",
+                "required": false,
+                "description": "Requests with a key whose records were already produced are acknowledged without being produced again",
+                "schema": { "type": "string" },
+            },
+            {
+                "name": "Content-Encoding",
+                "in": "header",
+                "required": false,
+                "schema": { "type": "string", "enum": ["gzip", "zstd", "br"] },
+            },
+        ],
+        "requestBody": {
+            "required": true,
+            "content": {
+                "application/json": {
+                    "schema": { "oneOf": [model_ref, { "type": "array", "items": model_ref }] },
+                },
+                NDJSON_CONTENT_TYPE: {
+                    "schema": { "type": "string", "description": format!("One {} record per line", data_model.name) },
+                },
+                CSV_CONTENT_TYPE: {
+                    "schema": { "type": "string", "description": format!("A header row naming {} fields, then one record per row", data_model.name) },
+                },
+                FORM_CONTENT_TYPE: { "schema": model_ref },
+            },
+        },
+        "responses": {
+            "200": {
+                "description": "The record was produced, or every record of the batch was",
+                "content": { "application/json": { "schema": { "oneOf": [
+                    { "$ref": "#/components/schemas/IngestResult" },
+                    { "$ref": "#/components/schemas/BatchResult" },
+                ] } } },
+            },
+            "202": {
+                "description": "The route delivers fire and forget, and the record or every valid record of the batch was accepted",
+                "content": { "application/json": { "schema": { "oneOf": [
+                    { "$ref": "#/components/schemas/IngestResult" },
+                    { "$ref": "#/components/schemas/BatchResult" },
+                ] } } },
+            },
+            "207": {
+                "description": "Only some records of the batch were produced",
+                "content": { "application/json": { "schema": { "$ref": "#/components/schemas/BatchResult" } } },
+            },
+            "400": {
+                "description": "The record is invalid, or no record of the batch is valid",
+                "content": { "application/json": { "schema": { "oneOf": [
+                    { "$ref": "#/components/schemas/ValidationErrors" },
+                    { "$ref": "#/components/schemas/BatchResult" },
+                ] } } },
+            },
+            "401": error_response("The API key is missing or invalid"),
+            "403": error_response("The API key is not allowed on this route"),
+            "404": error_response("The route doesn't exist"),
+            "413": error_response("The request body is too large"),
+            "409": error_response("A request with the same idempotency key is still being processed, retry later"),
+            "415": error_response("The content encoding is not supported"),
+            "429": error_response("The rate limit of the route was exceeded"),
+            "500": error_response("The record could not be produced"),
+            "503": error_response("The stream is unavailable or falling behind, retry later"),
+        },
+    })
+}
+
+fn console_operation() -> Value {
+    json!({
+        "get": {
+            "summary": "List the tables, topics and routes of the project",
+            "operationId": "getConsole",
+            "tags": ["console"],
+            "responses": {
+                "200": {
+                    "description": "The infrastructure of the project",
+                    "content": { "application/json": { "schema": {
+                        "type": "object",
+                        "properties": {
+                            "tables": { "type": "array", "items": { "type": "object" } },
+                            "topics": { "type": "array", "items": { "type": "string" } },
+                            "routes": { "type": "array", "items": {
+                                "type": "object",
+                                "properties": {
+                                    "route_path": { "type": "string" },
+                                    "file_path": { "type": "string" },
+                                    "table_name": { "type": "string" },
+                                    "view_name": { "type": "string", "nullable": true },
+                                },
+                            } },
+                            "spool": {
+                                "type": "object",
+                                "nullable": true,
+                                "description": "The records waiting on disk for the stream to take them, when the spool is enabled",
+                                "properties": {
+                                    "records": { "type": "integer" },
+                                    "bytes": { "type": "integer" },
+                                    "max_bytes": { "type": "integer" },
+                                    "topics": { "type": "object", "additionalProperties": { "type": "integer" } },
+                                    "spooled_total": { "type": "integer" },
+                                    "replayed_total": { "type": "integer" },
+                                    "last_error": { "type": "string", "nullable": true },
+                                },
+                            },
+                        },
+                    } } },
+                },
+                "401": error_response("The API key is missing or invalid"),
+                "503": error_response("The stream is unavailable"),
+            },
+        }
+    })
+}
+
+fn probe_operation(summary: &str, operation_id: &str, responses: Value) -> Value {
+    json!({
+        "get": {
+            "summary": summary,
+            "operationId": operation_id,
+            "tags": ["operations"],
+            // Probes never need a key
+            "security": [],
+            "responses": responses,
+        }
+    })
+}
+
+fn operational_paths() -> Map<String, Value> {
+    let health_report = json!({ "$ref": "#/components/schemas/HealthReport" });
+    let paths = json!({
+        "/health": probe_operation(
+            "Check that the webserver is alive",
+            "getHealth",
+            json!({
+                "200": {
+                    "description": "The webserver serves requests, its dependencies aren't checked",
+                    "content": { "application/json": { "schema": {
+                        "type": "object",
+                        "properties": { "status": { "type": "string", "enum": ["ok"] } },
+                    } } },
+                },
+            }),
+        ),
+        "/ready": probe_operation(
+            "Check that the webserver and its dependencies are ready for data",
+            "getReady",
+            json!({
+                "200": {
+                    "description": "ClickHouse and the stream are up and the schemas directory was crawled",
+                    "content": { "application/json": { "schema": health_report } },
+                },
+                "503": {
+                    "description": "A dependency is down or the schemas directory is still being crawled",
+                    "content": { "application/json": { "schema": health_report } },
+                },
+            }),
+        ),
+        "/metrics": {
+            "get": {
+                "summary": "Read the metrics of the webserver",
+                "operationId": "getMetrics",
+                "tags": ["operations"],
+                "responses": {
+                    "200": {
+                        "description": "The metrics in the Prometheus text format",
+                        "content": { METRICS_CONTENT_TYPE: { "schema": { "type": "string" } } },
+                    },
+                    "401": error_response("The API key is missing or invalid"),
+                    "429": error_response("The rate limit of the route was exceeded"),
+                },
+            },
+        },
+        "/events": {
+            "get": {
+                "summary": "Stream what happens on the routes and in the project",
+                "operationId": "getEvents",
+                "tags": ["operations"],
+                "responses": {
+                    "200": {
+                        "description": "Server-Sent Events named after their type, with the event as JSON data",
+                        "content": { EVENT_STREAM_CONTENT_TYPE: { "schema": { "type": "string" } } },
+                    },
+                    "401": error_response("The API key is missing or invalid"),
+                    "429": error_response("The rate limit of the route was exceeded"),
+                },
+            },
+        },
+        "/openapi.json": {
+            "get": {
+                "summary": "Read this document",
+                "operationId": "getOpenApi",
+                "tags": ["operations"],
+                "responses": {
+                    "200": {
+                        "description": "The OpenAPI document of the routes created so far",
+                        "content": { "application/json": { "schema": { "type": "object" } } },
+                    },
+                    "401": error_response("The API key is missing or invalid"),
+                    "429": error_response("The rate limit of the route was exceeded"),
+                },
+            },
+        },
+    });
+
+    match paths {
+        Value::Object(paths) => paths,
+        _ => Map::new(),
+    }
+}
+
+fn common_schemas() -> Map<String, Value> {
+    let schemas = json!({
+        "Error": {
+            "type": "object",
+            "properties": {
+                "error": {
+                    "type": "object",
+                    "properties": {
+                        "message": { "type": "string" },
+                        "retryable": { "type": "boolean" },
+                    },
+                    "required": ["message"],
+                },
+                "request_id": { "type": "string" },
+            },
+        },
+        "FieldError": {
+            "type": "object",
+            "properties": {
+                "field": { "type": "string" },
+                "message": { "type": "string" },
+            },
+            "required": ["message"],
+        },
+        "ValidationErrors": {
+            "type": "object",
+            "properties": {
+                "errors": { "type": "array", "items": { "$ref": "#/components/schemas/FieldError" } },
+                "request_id": { "type": "string" },
+            },
+        },
+        "IngestResult": {
+            "type": "object",
+            "properties": {
+                "status": { "type": "string", "enum": ["ok", "accepted"] },
+                "duplicate": { "type": "boolean" },
+                "request_id": { "type": "string" },
+            },
+        },
+        "Check": {
+            "type": "object",
+            "properties": {
+                "status": { "type": "string", "enum": ["ok", "error"] },
+                "latency_ms": { "type": "number" },
+                "error": { "type": "string" },
+            },
+        },
+        "HealthReport": {
+            "type": "object",
+            "properties": {
+                "status": { "type": "string", "enum": ["ready", "not_ready"] },
+                "checks": {
+                    "type": "object",
+                    "properties": {
+                        "clickhouse": { "$ref": "#/components/schemas/Check" },
+                        "stream": { "$ref": "#/components/schemas/Check" },
+                        "schema_crawl": { "$ref": "#/components/schemas/Check" },
+                    },
+                },
+            },
+        },
+        "BatchResult": {
+            "type": "object",
+            "properties": {
+                "succeeded": { "type": "integer" },
+                "failed": { "type": "integer" },
+                "results": { "type": "array", "items": {
+                    "type": "object",
+                    "properties": {
+                        "index": { "type": "integer" },
+                        "status": { "type": "string", "enum": ["ok", "accepted", "error"] },
+                        "duplicate": { "type": "boolean" },
+                        "retryable": { "type": "boolean" },
+                        "errors": { "type": "array", "items": { "$ref": "#/components/schemas/FieldError" } },
+                    },
+                } },
+                "request_id": { "type": "string" },
+            },
+        },
+    });
+
+    match schemas {
+        Value::Object(schemas) => schemas,
+        _ => Map::new(),
+    }
+}
+
+pub fn build_spec(route_table: &HashMap<PathBuf, Arc<RouteMeta>>) -> Value {
+    //! Builds the document for the routes currently in the route table.
+    let mut routes = route_table.iter().collect::<Vec<_>>();
+    routes.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let mut paths = Map::new();
+    let mut schemas = common_schemas();
+
+    for (route, route_meta) in routes {
+        let operations = route_meta
+            .methods()
+            .into_iter()
+            .map(|method| {
+                let method = method.to_lowercase();
+                let mut operation = ingest_operation(&route_meta.data_model);
+                if let Some(ingestion_point) = &route_meta.ingestion_point {
+                    // Operation ids are unique, so the ones of ingestion points follow their path
+                    operation["operationId"] = json!(format!(
+                        "{}_{}",
+                        method,
+                        route.display().to_string().replace(['/', '.', '-'], "_")
+                    ));
+                    if ingestion_point.maps_fields() {
+                        operation["requestBody"]["content"]["application/json"]["schema"] = json!({
+                            "type": "object",
+                            "description": format!("Mapped to {} records", route_meta.data_model.name),
+                        });
+                    }
+                }
+                if route_meta.is_public() {
+                    operation["security"] = json!([]);
+                }
+                (method, operation)
+            })
+            .collect::<Map<String, Value>>();
+        paths.insert(format!("/{}", route.display()), Value::Object(operations));
+        schemas.insert(
+            route_meta.data_model.name.clone(),
+            model_schema(&route_meta.data_model),
+        );
+    }
+    paths.insert("/console".to_string(), console_operation());
+    paths.extend(operational_paths());
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Igloo ingest API",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "servers": [{ "url": "/" }],
+        "paths": paths,
+        "components": {
+            "schemas": schemas,
+            "securitySchemes": {
+                "bearerAuth": { "type": "http", "scheme": "bearer" },
+                "apiKeyAuth": { "type": "apiKey", "in": "header", "name": "X-Api-Key" },
+            },
+        },
+        // Keys are only required once the project has some
+        "security": [{}, { "bearerAuth": [] }, { "apiKeyAuth": [] }],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_the_operational_routes() {
+        let spec = build_spec(&HashMap::new());
+
+        for path in [
+            "/console",
+            "/health",
+            "/ready",
+            "/metrics",
+            "/events",
+            "/openapi.json",
+        ] {
+            assert!(spec["paths"][path]["get"].is_object(), "{}", path);
+        }
+        // Probes are open even when the project has keys
+        assert_eq!(spec["paths"]["/health"]["get"]["security"], json!([]));
+        assert_eq!(spec["paths"]["/ready"]["get"]["security"], json!([]));
+        assert!(spec["paths"]["/ready"]["get"]["responses"]["503"].is_object());
+    }
+}
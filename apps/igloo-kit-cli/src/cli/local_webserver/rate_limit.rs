@@ -0,0 +1,247 @@
+//! # Rate Limit
+//! Token buckets that limit how fast clients can call the routes of the local webserver. Every
+//! client gets its own bucket on every route, clients being told apart by the API key they were
+//! authorized with or, without one, by their IP address. Limits are configured in the
+//! `project.toml`:
+//!
+//! ```toml
+//! [local_webserver_config.rate_limits.default]
+//! requests_per_second = 100.0
+//! burst = 200
+//!
+//! [local_webserver_config.rate_limits.routes."ingest/UserActivity"]
+//! requests_per_second = 10.0
+//! burst = 20
+//! ```
+//!
+//! Routes without a limit of their own use the default one, and nothing is limited without any.
+//! Routes are matched case-insensitively since the config loader lowercases keys.
+//!
+//! ## Suggested Improvements
+//! - limit records per second as well as requests, batches count as a single request today
+//! - share the buckets between several webservers
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+// Buckets that are full again are dropped this often, so the map can't grow forever
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub requests_per_second: f64,
+    // Requests a client can make at once after being idle
+    pub burst: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RateLimitConfig {
+    #[serde(default)]
+    pub default: Option<RateLimit>,
+    // Limits by route, e.g. `ingest/UserActivity`
+    #[serde(default)]
+    pub routes: HashMap<String, RateLimit>,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.requests_per_second).min(limit.burst as f64);
+        self.last_refill = now;
+    }
+}
+
+struct Buckets {
+    by_client: HashMap<(String, String), TokenBucket>,
+    last_sweep: Instant,
+}
+
+pub struct RateLimiter {
+    default: Option<RateLimit>,
+    // Keyed by lowercased route
+    routes: HashMap<String, RateLimit>,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            default: config.default,
+            routes: config
+                .routes
+                .into_iter()
+                .map(|(route, limit)| (route.trim_matches('/').to_lowercase(), limit))
+                .collect(),
+            buckets: Mutex::new(Buckets {
+                by_client: HashMap::new(),
+                last_sweep: Instant::now(),
+            }),
+        }
+    }
+
+    fn limit_for(&self, route: &str) -> Option<RateLimit> {
+        self.routes
+            .get(&route.to_lowercase())
+            .or(self.default.as_ref())
+            .copied()
+            .filter(|limit| limit.requests_per_second > 0.0 && limit.burst > 0)
+    }
+
+    pub fn check(&self, route: &str, client: &str) -> Result<(), Duration> {
+        //! Takes a token from the bucket of the client on the route, or returns how long the client
+        //! has to wait for the next one.
+        self.check_at(route, client, Instant::now())
+    }
+
+    fn check_at(&self, route: &str, client: &str, now: Instant) -> Result<(), Duration> {
+        let limit = match self.limit_for(route) {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+
+        let mut buckets = match self.buckets.lock() {
+            Ok(buckets) => buckets,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        if now.saturating_duration_since(buckets.last_sweep) >= SWEEP_INTERVAL {
+            buckets.last_sweep = now;
+            buckets.by_client.retain(|(route, _), bucket| {
+                let limit = self.limit_for(route);
+                limit.map_or(false, |limit| {
+                    bucket.refill(&limit, now);
+                    bucket.tokens < limit.burst as f64
+                })
+            });
+        }
+
+        let bucket = buckets
+            .by_client
+            .entry((route.to_string(), client.to_string()))
+            .or_insert_with(|| TokenBucket {
+                tokens: limit.burst as f64,
+                last_refill: now,
+            });
+        bucket.refill(&limit, now);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / limit.requests_per_second,
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(routes: &[(&str, f64, u32)]) -> RateLimiter {
+        RateLimiter::new(RateLimitConfig {
+            default: None,
+            routes: routes
+                .iter()
+                .map(|(route, requests_per_second, burst)| {
+                    (
+                        route.to_string(),
+                        RateLimit {
+                            requests_per_second: *requests_per_second,
+                            burst: *burst,
+                        },
+                    )
+                })
+                .collect(),
+        })
+    }
+
+    #[test]
+    fn refills_tokens_over_time() {
+        let limiter = limiter(&[("ingest/users", 2.0, 2)]);
+        let now = Instant::now();
+
+        assert!(limiter.check_at("ingest/users", "ip:1", now).is_ok());
+        assert!(limiter.check_at("ingest/users", "ip:1", now).is_ok());
+        assert!(limiter.check_at("ingest/users", "ip:1", now).is_err());
+
+        // Half a second gives back one token at 2 requests per second
+        let later = now + Duration::from_millis(500);
+        assert!(limiter.check_at("ingest/users", "ip:1", later).is_ok());
+        assert!(limiter.check_at("ingest/users", "ip:1", later).is_err());
+
+        // Other clients have their own bucket
+        assert!(limiter.check_at("ingest/users", "ip:2", later).is_ok());
+    }
+
+    #[test]
+    fn returns_the_time_until_the_next_token() {
+        let limiter = limiter(&[("ingest/users", 4.0, 1)]);
+        let now = Instant::now();
+
+        assert!(limiter.check_at("ingest/users", "ip:1", now).is_ok());
+        assert_eq!(
+            limiter.check_at("ingest/users", "ip:1", now),
+            Err(Duration::from_millis(250))
+        );
+        assert_eq!(
+            limiter.check_at("ingest/users", "ip:1", now + Duration::from_millis(100)),
+            Err(Duration::from_millis(150))
+        );
+    }
+
+    #[test]
+    fn matches_routes_case_insensitively() {
+        let limiter = limiter(&[("/ingest/UserActivity/", 1.0, 1)]);
+        let now = Instant::now();
+
+        assert!(limiter.check_at("ingest/UserActivity", "ip:1", now).is_ok());
+        assert!(limiter
+            .check_at("ingest/UserActivity", "ip:1", now)
+            .is_err());
+        assert!(limiter.check_at("ingest/useractivity", "ip:2", now).is_ok());
+        assert!(limiter
+            .check_at("ingest/useractivity", "ip:2", now)
+            .is_err());
+
+        // Routes without a limit aren't limited
+        assert!(limiter.check_at("ingest/Other", "ip:1", now).is_ok());
+        assert!(limiter.check_at("ingest/Other", "ip:1", now).is_ok());
+    }
+
+    #[test]
+    fn evicts_full_buckets_on_every_sweep() {
+        let limiter = limiter(&[("ingest/users", 0.1, 10)]);
+        let now = Instant::now();
+
+        assert!(limiter.check_at("ingest/users", "ip:1", now).is_ok());
+        for _ in 0..10 {
+            let _ = limiter.check_at("ingest/users", "ip:2", now);
+        }
+
+        // By the next sweep the first client is full again while the second one isn't
+        let sweep = now + SWEEP_INTERVAL;
+        assert!(limiter.check_at("ingest/users", "ip:3", sweep).is_ok());
+        let buckets = limiter.buckets.lock().unwrap();
+        let mut clients = buckets
+            .by_client
+            .keys()
+            .map(|(_, client)| client.as_str())
+            .collect::<Vec<&str>>();
+        clients.sort();
+        assert_eq!(clients, vec!["ip:2", "ip:3"]);
+    }
+}
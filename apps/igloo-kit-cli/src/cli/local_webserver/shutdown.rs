@@ -0,0 +1,67 @@
+//! # Shutdown
+//! Keeps track of the requests the webserver is handling, so that it can let them finish before
+//! the process exits. Once the server drains, idle keep-alive connections are closed, and requests
+//! that still arrive on open connections are turned away with a 503 instead of being started.
+//!
+//! ## Suggested Improvements
+//! - end the event streams with a final event
+
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc,
+};
+
+use tokio::sync::Notify;
+
+#[derive(Default)]
+pub struct RequestTracker {
+    in_flight: AtomicUsize,
+    draining: AtomicBool,
+    idle: Notify,
+}
+
+pub struct RequestGuard {
+    tracker: Arc<RequestTracker>,
+}
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        if self.tracker.in_flight.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.tracker.idle.notify_waiters();
+        }
+    }
+}
+
+impl RequestTracker {
+    pub fn track(self: &Arc<Self>) -> RequestGuard {
+        //! Counts a request as in flight until the guard is dropped.
+        self.in_flight.fetch_add(1, Ordering::AcqRel);
+        RequestGuard {
+            tracker: Arc::clone(self),
+        }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Acquire)
+    }
+
+    pub fn start_draining(&self) {
+        self.draining.store(true, Ordering::Release);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Acquire)
+    }
+
+    pub async fn wait_idle(&self) {
+        //! Waits until no request is in flight.
+        loop {
+            // Created before checking the count so that a wakeup in between isn't missed
+            let idle = self.idle.notified();
+            if self.in_flight() == 0 {
+                return;
+            }
+            idle.await;
+        }
+    }
+}
@@ -0,0 +1,333 @@
+//! # TLS
+//! Serves the local webserver over HTTPS, so that browser apps served over HTTPS can call it
+//! without mixed-content errors. TLS is off by default and turned on in the `project.toml`:
+//!
+//! ```toml
+//! [local_webserver_config.tls]
+//! enabled = true
+//! # Optional, a certificate is generated for the host when they're left out. Relative paths are
+//! # read from the project directory.
+//! cert_file = "certs/localhost.pem"
+//! key_file = "certs/localhost-key.pem"
+//! ```
+//!
+//! Without a certificate of its own, the project gets one signed by a local certificate authority
+//! that the CLI creates once in `~/.igloo/tls`. Browsers only accept it once `ca.pem` is trusted by
+//! the system or the browser. ALPN negotiates HTTP/2 with clients that support it.
+//!
+//! ## Suggested Improvements
+//! - renew generated certificates before they expire
+//! - add the local certificate authority to the system trust store with a command
+
+use std::{
+    fs::{self, Permissions},
+    io::{Error, ErrorKind},
+    net::IpAddr,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    pin::Pin,
+    time::Duration,
+};
+
+use openssl::{
+    asn1::Asn1Time,
+    bn::{BigNum, MsbOption},
+    error::ErrorStack,
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{PKey, Private},
+    rsa::Rsa,
+    ssl::{self, AlpnError, Ssl, SslAcceptor, SslFiletype, SslMethod},
+    x509::{
+        extension::{BasicConstraints, ExtendedKeyUsage, KeyUsage, SubjectAlternativeName},
+        X509Name, X509,
+    },
+};
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpStream;
+use tokio_openssl::SslStream;
+
+use crate::cli::{
+    display::{Message, MessageType},
+    settings::user_directory,
+};
+
+// Protocols offered to clients in order of preference, in the ALPN wire format
+const ALPN_PROTOCOLS: &[u8] = b"\x02h2\x08http/1.1";
+
+// Clients that connect without finishing the handshake would otherwise hold their task forever
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const CA_VALIDITY_DAYS: u32 = 3650;
+// Browsers reject server certificates that are valid for longer
+const CERT_VALIDITY_DAYS: u32 = 825;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TlsConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub cert_file: Option<PathBuf>,
+    #[serde(default)]
+    pub key_file: Option<PathBuf>,
+}
+
+fn tls_error(context: &str, e: ErrorStack) -> Error {
+    Error::new(ErrorKind::Other, format!("{}: {}", context, e))
+}
+
+fn tls_dir() -> PathBuf {
+    user_directory().join("tls")
+}
+
+fn write_private_key(path: &Path, key: &PKey<Private>) -> Result<(), Error> {
+    let pem = key
+        .private_key_to_pem_pkcs8()
+        .map_err(|e| tls_error("Failed to encode the private key", e))?;
+    fs::write(path, pem)?;
+    fs::set_permissions(path, Permissions::from_mode(0o600))
+}
+
+fn generate_key() -> Result<PKey<Private>, ErrorStack> {
+    PKey::from_rsa(Rsa::generate(2048)?)
+}
+
+fn serial_number() -> Result<openssl::asn1::Asn1Integer, ErrorStack> {
+    let mut serial = BigNum::new()?;
+    serial.rand(128, MsbOption::MAYBE_ZERO, false)?;
+    serial.to_asn1_integer()
+}
+
+fn generate_ca() -> Result<(X509, PKey<Private>), ErrorStack> {
+    let key = generate_key()?;
+
+    let mut name = X509Name::builder()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, "Igloo Local CA")?;
+    let name = name.build();
+
+    let mut builder = X509::builder()?;
+    builder.set_version(2)?;
+    builder.set_serial_number(serial_number()?.as_ref())?;
+    builder.set_subject_name(&name)?;
+    builder.set_issuer_name(&name)?;
+    builder.set_pubkey(&key)?;
+    builder.set_not_before(Asn1Time::days_from_now(0)?.as_ref())?;
+    builder.set_not_after(Asn1Time::days_from_now(CA_VALIDITY_DAYS)?.as_ref())?;
+    builder.append_extension(BasicConstraints::new().critical().ca().build()?)?;
+    builder.append_extension(
+        KeyUsage::new()
+            .critical()
+            .key_cert_sign()
+            .crl_sign()
+            .build()?,
+    )?;
+    builder.sign(&key, MessageDigest::sha256())?;
+
+    Ok((builder.build(), key))
+}
+
+fn generate_cert(
+    host: &str,
+    ca_cert: &X509,
+    ca_key: &PKey<Private>,
+) -> Result<(X509, PKey<Private>), ErrorStack> {
+    let key = generate_key()?;
+
+    let mut name = X509Name::builder()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, host)?;
+    let name = name.build();
+
+    let mut builder = X509::builder()?;
+    builder.set_version(2)?;
+    builder.set_serial_number(serial_number()?.as_ref())?;
+    builder.set_subject_name(&name)?;
+    builder.set_issuer_name(ca_cert.subject_name())?;
+    builder.set_pubkey(&key)?;
+    builder.set_not_before(Asn1Time::days_from_now(0)?.as_ref())?;
+    builder.set_not_after(Asn1Time::days_from_now(CERT_VALIDITY_DAYS)?.as_ref())?;
+    builder.append_extension(BasicConstraints::new().build()?)?;
+    builder.append_extension(
+        KeyUsage::new()
+            .critical()
+            .digital_signature()
+            .key_encipherment()
+            .build()?,
+    )?;
+    builder.append_extension(ExtendedKeyUsage::new().server_auth().build()?)?;
+
+    // The certificate covers the usual names of the local machine as well as the configured host
+    let mut alt_names = SubjectAlternativeName::new();
+    alt_names.dns("localhost").ip("127.0.0.1").ip("::1");
+    match host.parse::<IpAddr>() {
+        Ok(_) => alt_names.ip(host),
+        Err(_) => alt_names.dns(host),
+    };
+    let alt_names = alt_names.build(&builder.x509v3_context(Some(ca_cert), None))?;
+    builder.append_extension(alt_names)?;
+
+    builder.sign(ca_key, MessageDigest::sha256())?;
+    Ok((builder.build(), key))
+}
+
+fn load_or_create_ca(dir: &Path) -> Result<(X509, PKey<Private>), Error> {
+    let cert_path = dir.join("ca.pem");
+    let key_path = dir.join("ca-key.pem");
+
+    if cert_path.exists() && key_path.exists() {
+        let cert = X509::from_pem(&fs::read(&cert_path)?)
+            .map_err(|e| tls_error("Failed to read the local certificate authority", e))?;
+        let key = PKey::private_key_from_pem(&fs::read(&key_path)?)
+            .map_err(|e| tls_error("Failed to read the local certificate authority key", e))?;
+        return Ok((cert, key));
+    }
+
+    let (cert, key) = generate_ca()
+        .map_err(|e| tls_error("Failed to generate the local certificate authority", e))?;
+    fs::write(
+        &cert_path,
+        cert.to_pem()
+            .map_err(|e| tls_error("Failed to encode the local certificate authority", e))?,
+    )?;
+    write_private_key(&key_path, &key)?;
+
+    show_message!(
+        MessageType::Info,
+        Message {
+            action: "Created".to_string(),
+            details: format!(
+                "a local certificate authority, trust {} to use HTTPS without warnings",
+                cert_path.display()
+            ),
+        }
+    );
+    Ok((cert, key))
+}
+
+fn local_cert_files(host: &str) -> Result<(PathBuf, PathBuf), Error> {
+    //! The certificate and key generated for the host, created with the local certificate
+    //! authority the first time they're needed.
+    let dir = tls_dir();
+    fs::create_dir_all(&dir)?;
+
+    let cert_path = dir.join(format!("{}.pem", host));
+    let key_path = dir.join(format!("{}-key.pem", host));
+    if cert_path.exists() && key_path.exists() {
+        return Ok((cert_path, key_path));
+    }
+
+    let (ca_cert, ca_key) = load_or_create_ca(&dir)?;
+    let (cert, key) = generate_cert(host, &ca_cert, &ca_key)
+        .map_err(|e| tls_error("Failed to generate a certificate", e))?;
+
+    // The chain includes the authority so that clients can verify it from the trusted root
+    let mut chain = cert
+        .to_pem()
+        .map_err(|e| tls_error("Failed to encode the certificate", e))?;
+    chain.extend(
+        ca_cert
+            .to_pem()
+            .map_err(|e| tls_error("Failed to encode the certificate", e))?,
+    );
+    fs::write(&cert_path, chain)?;
+    write_private_key(&key_path, &key)?;
+
+    Ok((cert_path, key_path))
+}
+
+pub fn acceptor(config: &TlsConfig, host: &str, project_dir: &Path) -> Result<SslAcceptor, Error> {
+    //! Builds the acceptor that terminates TLS for the webserver, with the configured certificate
+    //! or one generated for the host.
+    let (cert_path, key_path) = match (&config.cert_file, &config.key_file) {
+        (Some(cert_path), Some(key_path)) => {
+            (project_dir.join(cert_path), project_dir.join(key_path))
+        }
+        (None, None) => local_cert_files(host)?,
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Both cert_file and key_file must be set to use your own certificate",
+            ))
+        }
+    };
+
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server())
+        .map_err(|e| tls_error("Failed to set up TLS", e))?;
+    builder
+        .set_certificate_chain_file(&cert_path)
+        .map_err(|e| tls_error(&format!("Failed to load {}", cert_path.display()), e))?;
+    builder
+        .set_private_key_file(&key_path, SslFiletype::PEM)
+        .map_err(|e| tls_error(&format!("Failed to load {}", key_path.display()), e))?;
+    builder
+        .check_private_key()
+        .map_err(|e| tls_error("The key doesn't match the certificate", e))?;
+    builder.set_alpn_select_callback(|_, client_protocols| {
+        ssl::select_next_proto(ALPN_PROTOCOLS, client_protocols).ok_or(AlpnError::NOACK)
+    });
+
+    Ok(builder.build())
+}
+
+pub async fn accept(
+    acceptor: &SslAcceptor,
+    stream: TcpStream,
+) -> Result<SslStream<TcpStream>, Error> {
+    //! Runs the TLS handshake on a new connection, giving up when the client doesn't finish it in
+    //! time.
+    let ssl = Ssl::new(acceptor.context()).map_err(|e| tls_error("Failed to set up TLS", e))?;
+    let mut stream =
+        SslStream::new(ssl, stream).map_err(|e| tls_error("Failed to set up TLS", e))?;
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, Pin::new(&mut stream).accept())
+        .await
+        .map_err(|_| {
+            Error::new(
+                ErrorKind::TimedOut,
+                format!(
+                    "The TLS handshake didn't finish within {}s",
+                    HANDSHAKE_TIMEOUT.as_secs()
+                ),
+            )
+        })?
+        .map_err(|e| Error::new(ErrorKind::ConnectionAborted, e.to_string()))?;
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_fs::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn requires_both_files_of_a_certificate() {
+        let config = TlsConfig {
+            enabled: true,
+            cert_file: Some(PathBuf::from("certs/localhost.pem")),
+            key_file: None,
+        };
+        let e = acceptor(&config, "localhost", Path::new("/"))
+            .err()
+            .unwrap();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn reads_certificates_from_the_project_directory() {
+        let project_dir = TempDir::new().unwrap();
+        let config = TlsConfig {
+            enabled: true,
+            cert_file: Some(PathBuf::from("certs/localhost.pem")),
+            key_file: Some(PathBuf::from("certs/localhost-key.pem")),
+        };
+
+        let e = acceptor(&config, "localhost", project_dir.path())
+            .err()
+            .unwrap();
+        let cert_path = project_dir.path().join("certs/localhost.pem");
+        assert!(
+            e.to_string().contains(&cert_path.display().to_string()),
+            "{}",
+            e
+        );
+    }
+}
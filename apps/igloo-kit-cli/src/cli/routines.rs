@@ -71,9 +71,16 @@
 //! The `start_development_mode` function is used to start the file watcher and the webserver. It takes a `ClickhouseConfig` and a
 //! `RedpandaConfig` as arguments. The `ClickhouseConfig` is used to configure the Clickhouse database. The `RedpandaConfig` is used
 //! to configure the Redpanda stream processor. This is a special routine due to it's async nature.
+//! The webserver starts listening while the schemas directory is crawled, and reports on `/ready`
+//! once the crawl has finished.
+//! On SIGINT or SIGTERM the webserver stops accepting connections and lets the requests in flight finish, then
+//! the watcher and the flows are stopped, the records left in the producer are flushed and the containers are stopped.
+//!
+//! ## Start Serve Mode
+//! The `start_serve_mode` function backs `igloo serve`. It loads the routes of the data models once and serves them against the
+//! ClickHouse and Redpanda configured in the project, without starting containers, watching files or building the SDK.
 //!
 //! ## Suggested Improvements
-//! - Explore using a RWLock instead of a Mutex to ensure concurrent reads without locks
 //! - Simplify the API for the user when using RunMode::Explicit since it creates lifetime and ownership issues
 //! - Enable creating nested routines and cascading down the RunMode to show messages to the user
 //! - Organize routines better in the file hiearchy
@@ -81,20 +88,27 @@
 
 use std::collections::HashMap;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use std::{io::Error, path::PathBuf};
 
 use log::debug;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, RwLock};
 
+use super::local_webserver::events::EventBus;
 use super::local_webserver::Webserver;
 use super::watcher::FileWatcher;
 use super::{Message, MessageType};
-use crate::cli::watcher::process_schema_file;
+use crate::cli::watcher::{
+    ingestion_point_routes, load_schema_file_routes, process_schema_file, reload_ingestion_points,
+    ReloadContext,
+};
 
 use crate::framework::controller::RouteMeta;
+use crate::framework::flows::{start_all_flows, FlowRegistry};
 use crate::infrastructure::olap;
-use crate::infrastructure::olap::clickhouse::ConfiguredDBClient;
+use crate::infrastructure::stream::{self, StreamingBackend};
 use crate::project::Project;
 use log::info;
 
@@ -102,8 +116,10 @@ use async_recursion::async_recursion;
 
 pub mod clean;
 pub mod initialize;
+pub mod replay;
 pub mod start;
 pub mod stop;
+pub mod tail;
 pub mod validate;
 
 #[derive(Clone)]
@@ -213,37 +229,199 @@ pub async fn start_development_mode(project: &Project) -> Result<(), Error> {
         }
     );
 
-    // TODO: Explore using a RWLock instead of a Mutex to ensure concurrent reads without locks
-    let route_table = Arc::new(Mutex::new(HashMap::<PathBuf, RouteMeta>::new()));
-
-    info!("Initializing project state");
-    initialize_project_state(project.schemas_dir(), project, Arc::clone(&route_table)).await?;
+    // Requests only read the route table, and the watcher only writes it once a reload is done
+    let route_table = Arc::new(RwLock::new(HashMap::<PathBuf, Arc<RouteMeta>>::new()));
+    let streaming_backend = stream::create_streaming_backend(project)?;
+    let schema_crawl_complete = Arc::new(AtomicBool::new(false));
+    let events = EventBus::new();
 
     let web_server = Webserver::new(
         project.local_webserver_config.host.clone(),
         project.local_webserver_config.port,
     );
     let file_watcher = FileWatcher::new();
+    let flow_registry = Arc::new(Mutex::new(FlowRegistry::new()));
+
+    // The webserver answers while the project is set up, so that `/ready` can tell when it's done
+    let setup = async {
+        info!("Initializing project state");
+        initialize_project_state(
+            project.schemas_dir(),
+            project,
+            Arc::clone(&route_table),
+            streaming_backend.as_ref(),
+            &events,
+        )
+        .await?;
+        reload_ingestion_points(project, &route_table, &events).await;
+        schema_crawl_complete.store(true, Ordering::Release);
+
+        info!("Starting flows");
+        start_all_flows(
+            project,
+            &mut *flow_registry.lock().await,
+            Arc::clone(&route_table),
+            Arc::clone(&streaming_backend),
+        )?;
 
-    file_watcher.start(project, Arc::clone(&route_table))?;
+        file_watcher.start(
+            project,
+            Arc::clone(&route_table),
+            Arc::clone(&streaming_backend),
+            Arc::clone(&flow_registry),
+            events.clone(),
+        )
+    };
 
     info!("Starting web server...");
+    let server = web_server.start(
+        Arc::clone(&route_table),
+        Arc::clone(&streaming_backend),
+        project,
+        Arc::clone(&schema_crawl_complete),
+        events.clone(),
+    );
+    tokio::pin!(server);
 
-    web_server.start(Arc::clone(&route_table), project).await;
+    // The server returns once it was asked to shut down and drained its requests. When the setup
+    // or the server fails, whatever was started is still shut down before the error is returned.
+    let (watcher, error) = tokio::select! {
+        result = setup => match result {
+            Ok(watcher) => (Some(watcher), None),
+            Err(e) => (None, Some(e)),
+        },
+        result = &mut server => (None, result.err()),
+    };
+    let error = match watcher {
+        Some(_) => server.await.err(),
+        None => error,
+    };
 
-    Ok(())
+    // Nothing produces records past this point, so the ones left can be flushed
+    if let Some(watcher) = watcher {
+        watcher.abort();
+    }
+    flow_registry.lock().await.stop_all();
+
+    flush_stream(project, streaming_backend.as_ref()).await;
+
+    if project.local_webserver_config.stop_infrastructure_on_exit {
+        stop_local_infrastructure(project);
+    }
+
+    match error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+pub async fn start_serve_mode(project: &Project) -> Result<(), Error> {
+    //! Serves the routes of the project's data models against the configured ClickHouse and
+    //! Redpanda. Unlike development mode, nothing is created or watched: the topics and tables are
+    //! expected to exist already, and no container, file watcher or SDK build is involved.
+    show_message!(
+        MessageType::Success,
+        Message {
+            action: "Starting".to_string(),
+            details: "ingest server".to_string(),
+        }
+    );
+
+    let events = EventBus::new();
+    let mut routes = load_routes(&project.schemas_dir(), project)?;
+    let ingestion_points = ingestion_point_routes(project, &routes, &events);
+    routes.extend(ingestion_points);
+    show_message!(
+        MessageType::Info,
+        Message {
+            action: "Loaded".to_string(),
+            details: format!("{} routes", routes.len()),
+        }
+    );
+
+    let route_table = Arc::new(RwLock::new(routes));
+    let streaming_backend = stream::create_streaming_backend(project)?;
+
+    let web_server = Webserver::new(
+        project.local_webserver_config.host.clone(),
+        project.local_webserver_config.port,
+    );
+    let served = web_server
+        .start(
+            route_table,
+            Arc::clone(&streaming_backend),
+            project,
+            // The models were all loaded before the server started
+            Arc::new(AtomicBool::new(true)),
+            events,
+        )
+        .await;
+
+    flush_stream(project, streaming_backend.as_ref()).await;
+    served
+}
+
+fn load_routes(
+    schema_dir: &Path,
+    project: &Project,
+) -> Result<HashMap<PathBuf, Arc<RouteMeta>>, Error> {
+    let mut routes = HashMap::new();
+    if schema_dir.is_dir() {
+        for entry in std::fs::read_dir(schema_dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                routes.extend(load_routes(&path, project)?);
+            } else if path.extension().map_or(false, |ext| ext == "prisma") {
+                debug!("Loading routes of: {:?}", path);
+                routes.extend(load_schema_file_routes(&path, project)?);
+            }
+        }
+    }
+    Ok(routes)
+}
+
+async fn flush_stream(project: &Project, streaming_backend: &dyn StreamingBackend) {
+    let shutdown_timeout =
+        Duration::from_secs(project.local_webserver_config.shutdown_timeout_secs);
+    if let Err(e) = streaming_backend.flush(shutdown_timeout).await {
+        show_message!(
+            MessageType::Error,
+            Message {
+                action: "Flushing".to_string(),
+                details: format!("records failed: {}", e),
+            }
+        );
+    }
+}
+
+fn stop_local_infrastructure(project: &Project) {
+    // Nothing runs in containers when the streaming engine doesn't need docker
+    if project.stream_config.engine.requires_docker() {
+        let run_mode = RunMode::Explicit;
+        stop::StopLocalInfrastructure::new(run_mode)
+            .run(run_mode)
+            .unwrap();
+    }
 }
 
 async fn initialize_project_state(
     schema_dir: PathBuf,
     project: &Project,
-    route_table: Arc<Mutex<HashMap<PathBuf, RouteMeta>>>,
+    route_table: Arc<RwLock<HashMap<PathBuf, Arc<RouteMeta>>>>,
+    streaming_backend: &dyn StreamingBackend,
+    events: &EventBus,
 ) -> Result<(), Error> {
     let configured_client = olap::clickhouse::create_client(project.clickhouse_config.clone());
 
     info!("Starting schema directory crawl...");
-    let crawl_result =
-        crawl_schema_project_dir(&schema_dir, project, &configured_client, route_table).await;
+    let context = ReloadContext {
+        project,
+        configured_client: &configured_client,
+        streaming_backend,
+        route_table: &route_table,
+        events,
+    };
+    let crawl_result = crawl_schema_project_dir(&schema_dir, &context).await;
 
     match crawl_result {
         Ok(_) => {
@@ -261,9 +439,7 @@ async fn initialize_project_state(
 #[async_recursion]
 async fn crawl_schema_project_dir(
     schema_dir: &Path,
-    project: &Project,
-    configured_client: &ConfiguredDBClient,
-    route_table: Arc<Mutex<HashMap<PathBuf, RouteMeta>>>,
+    context: &ReloadContext<'_>,
 ) -> Result<(), Error> {
     if schema_dir.is_dir() {
         for entry in std::fs::read_dir(schema_dir)? {
@@ -271,11 +447,10 @@ async fn crawl_schema_project_dir(
             let path = entry.path();
             if path.is_dir() {
                 debug!("Processing directory: {:?}", path);
-                crawl_schema_project_dir(&path, project, configured_client, route_table.clone())
-                    .await?;
+                crawl_schema_project_dir(&path, context).await?;
             } else {
                 debug!("Processing file: {:?}", path);
-                process_schema_file(&path, project, configured_client, route_table.clone()).await?
+                process_schema_file(&path, context).await?
             }
         }
     }
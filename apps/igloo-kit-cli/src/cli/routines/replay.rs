@@ -0,0 +1,328 @@
+//! # Replay
+//! Re-ingests the history of a data model by reading its topic between two positions and inserting
+//! the records in the view that stores the model in ClickHouse. This is how a table gets backfilled
+//! after its definition changed or after the view was recreated.
+//!
+//! Progress is saved in `.igloo/replay/<model>.json` after every batch that made it to ClickHouse.
+//! Running the same replay again resumes from the saved offsets, so an interrupted replay can
+//! simply be restarted. A batch may be inserted twice if the replay stops between the insert and
+//! the save.
+//!
+//! ## Suggested Improvements
+//! - deduplicate replayed rows once tables use a merge tree engine
+//! - replay several models at once
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::{Error, ErrorKind},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use rdkafka::{
+    consumer::{Consumer, StreamConsumer},
+    Message as KafkaMessage, Offset,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    cli::display::{Message, MessageType},
+    framework::controller::{get_framework_object_by_name, FrameworkObject},
+    infrastructure::{
+        ingest,
+        olap::{
+            self,
+            clickhouse::{ClickhouseView, ConfiguredDBClient},
+        },
+        stream::{
+            redpanda::{self, StartOffset},
+            StreamingEngine,
+        },
+    },
+    project::Project,
+};
+
+const REPLAY_BATCH_SIZE: usize = 1000;
+const REPLAY_IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct ReplayProgress {
+    from: String,
+    to: Option<String>,
+    // The next offset to insert for every partition
+    next_offsets: HashMap<i32, i64>,
+    // Offsets aren't inserted from here on, resolved once so that a resumed replay stops at the same place
+    end_offsets: HashMap<i32, i64>,
+    inserted_rows: u64,
+}
+
+impl ReplayProgress {
+    fn pending_partitions(&self) -> HashSet<i32> {
+        self.next_offsets
+            .iter()
+            .filter(|(partition, next)| {
+                self.end_offsets
+                    .get(*partition)
+                    .map_or(false, |end| **next < *end)
+            })
+            .map(|(partition, _)| *partition)
+            .collect()
+    }
+}
+
+fn progress_file_path(project: &Project, model_name: &str) -> Result<PathBuf, Error> {
+    let replay_dir = project.internal_dir()?.join("replay");
+    std::fs::create_dir_all(&replay_dir)?;
+    Ok(replay_dir.join(format!("{}.json", model_name)))
+}
+
+fn load_progress(path: &Path, from: &str, to: Option<&str>) -> Option<ReplayProgress> {
+    let contents = std::fs::read_to_string(path).ok()?;
+    let progress: ReplayProgress = serde_json::from_str(&contents).ok()?;
+
+    // Progress of a replay over a different range doesn't apply
+    if progress.from == from && progress.to.as_deref() == to {
+        Some(progress)
+    } else {
+        None
+    }
+}
+
+fn save_progress(path: &Path, progress: &ReplayProgress) -> Result<(), Error> {
+    let contents = serde_json::to_string_pretty(progress)?;
+    // Written next to the file first so that an interruption never leaves a partial file behind
+    let tmp_path = path.with_extension("json.tmp");
+    std::fs::write(&tmp_path, contents)?;
+    std::fs::rename(&tmp_path, path)
+}
+
+fn resolve_offsets(project: &Project, topic: &str, at: &str) -> Result<HashMap<i32, i64>, Error> {
+    let at = StartOffset::from_str(at).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    redpanda::resolve_offsets(&project.redpanda_config, topic, at).map_err(|e| {
+        Error::new(
+            ErrorKind::Other,
+            format!("Failed to resolve offsets of topic {}: {}", topic, e),
+        )
+    })
+}
+
+fn new_progress(
+    project: &Project,
+    fo: &FrameworkObject,
+    from: &str,
+    to: Option<&str>,
+) -> Result<ReplayProgress, Error> {
+    Ok(ReplayProgress {
+        from: from.to_string(),
+        to: to.map(|to| to.to_string()),
+        next_offsets: resolve_offsets(project, &fo.topic, from)?,
+        end_offsets: resolve_offsets(project, &fo.topic, to.unwrap_or("latest"))?,
+        inserted_rows: 0,
+    })
+}
+
+async fn flush(
+    query: &str,
+    batch: &mut Vec<Value>,
+    progress: &mut ReplayProgress,
+    progress_path: &Path,
+    client: &ConfiguredDBClient,
+) -> Result<(), Error> {
+    if !batch.is_empty() {
+        olap::clickhouse::insert_json_rows(query.to_string(), batch, client)
+            .await
+            .map_err(|e| {
+                Error::new(
+                    ErrorKind::Other,
+                    format!("Failed to insert rows in clickhouse: {}", e),
+                )
+            })?;
+        progress.inserted_rows += batch.len() as u64;
+        batch.clear();
+
+        show_message!(
+            MessageType::Info,
+            Message {
+                action: "Replayed".to_string(),
+                details: format!("{} rows", progress.inserted_rows),
+            }
+        );
+    }
+
+    save_progress(progress_path, progress)
+}
+
+fn finished_partitions(
+    consumer: &StreamConsumer,
+    pending: &HashSet<i32>,
+    progress: &ReplayProgress,
+) -> Result<Vec<i32>, Error> {
+    //! Partitions whose remaining offsets don't hold records anymore, such as deleted segments.
+    let positions = consumer.position().map_err(|e| {
+        Error::new(
+            ErrorKind::Other,
+            format!("Failed to read the consumer position: {}", e),
+        )
+    })?;
+
+    Ok(positions
+        .elements()
+        .iter()
+        .filter(|elem| pending.contains(&elem.partition()))
+        .filter(|elem| match elem.offset() {
+            Offset::Offset(position) => progress
+                .end_offsets
+                .get(&elem.partition())
+                .map_or(true, |end| position >= *end),
+            _ => false,
+        })
+        .map(|elem| elem.partition())
+        .collect())
+}
+
+pub async fn replay(
+    project: &Project,
+    model_name: &str,
+    from: &str,
+    to: Option<&str>,
+) -> Result<u64, Error> {
+    //! Replays the records of the topic from `from` up to, but not including, `to`. Both are
+    //! `earliest`, `latest`, an offset or an RFC 3339 timestamp. Without `to` the replay stops at
+    //! the end of the topic as it was when the replay started.
+    if project.stream_config.engine != StreamingEngine::Redpanda {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            "Replaying requires the redpanda streaming engine",
+        ));
+    }
+
+    let fo = get_framework_object_by_name(project, model_name)?;
+    let progress_path = progress_file_path(project, &fo.data_model.name)?;
+
+    let mut progress = match load_progress(&progress_path, from, to) {
+        Some(progress) => {
+            show_message!(
+                MessageType::Info,
+                Message {
+                    action: "Resuming".to_string(),
+                    details: format!(
+                        "replay of {} after {} rows",
+                        fo.data_model.name, progress.inserted_rows
+                    ),
+                }
+            );
+            progress
+        }
+        None => new_progress(project, &fo, from, to)?,
+    };
+    save_progress(&progress_path, &progress)?;
+
+    let view = ClickhouseView::new(
+        fo.table.db_name.clone(),
+        format!("{}_view", fo.table.name),
+        fo.table.clone(),
+    );
+    let query = view.insert_json_rows_query().map_err(|e| {
+        Error::new(
+            ErrorKind::Other,
+            format!("Failed to get clickhouse query: {:?}", e),
+        )
+    })?;
+    let client = olap::clickhouse::create_client(project.clickhouse_config.clone());
+
+    let mut pending = progress.pending_partitions();
+    let consumer = redpanda::create_consumer_at_offsets(
+        &project.redpanda_config,
+        &fo.topic,
+        &progress.next_offsets,
+    )
+    .map_err(|e| {
+        Error::new(
+            ErrorKind::Other,
+            format!("Failed to consume from topic {}: {}", fo.topic, e),
+        )
+    })?;
+
+    let mut batch = vec![];
+    let mut skipped_records = 0;
+
+    while !pending.is_empty() {
+        let message = match tokio::time::timeout(REPLAY_IDLE_TIMEOUT, consumer.recv()).await {
+            Ok(message) => message.map_err(|e| {
+                Error::new(
+                    ErrorKind::Other,
+                    format!("Failed to consume from topic {}: {}", fo.topic, e),
+                )
+            })?,
+            Err(_) => {
+                flush(&query, &mut batch, &mut progress, &progress_path, &client).await?;
+                for partition in finished_partitions(&consumer, &pending, &progress)? {
+                    pending.remove(&partition);
+                }
+                continue;
+            }
+        };
+
+        let partition = message.partition();
+        let end = match progress.end_offsets.get(&partition) {
+            Some(end) if pending.contains(&partition) => *end,
+            _ => continue,
+        };
+        if message.offset() >= end {
+            pending.remove(&partition);
+            continue;
+        }
+
+        match ingest::decode_payload(
+            &fo.codec,
+            &fo.data_model,
+            message.payload().unwrap_or_default(),
+        ) {
+            Ok(record) => batch.push(record),
+            Err(e) => {
+                skipped_records += 1;
+                show_message!(
+                    MessageType::Error,
+                    Message {
+                        action: "Skipped".to_string(),
+                        details: format!(
+                            "partition {} offset {}: {}",
+                            partition,
+                            message.offset(),
+                            e
+                        ),
+                    }
+                );
+            }
+        }
+
+        progress
+            .next_offsets
+            .insert(partition, message.offset() + 1);
+        if message.offset() + 1 >= end {
+            pending.remove(&partition);
+        }
+
+        if batch.len() >= REPLAY_BATCH_SIZE {
+            flush(&query, &mut batch, &mut progress, &progress_path, &client).await?;
+        }
+    }
+
+    flush(&query, &mut batch, &mut progress, &progress_path, &client).await?;
+    std::fs::remove_file(&progress_path)?;
+
+    show_message!(
+        MessageType::Success,
+        Message {
+            action: "Replayed".to_string(),
+            details: format!(
+                "{} rows into {}, skipped {} records",
+                progress.inserted_rows, view.name, skipped_records
+            ),
+        }
+    );
+
+    Ok(progress.inserted_rows)
+}
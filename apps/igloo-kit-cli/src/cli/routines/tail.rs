@@ -0,0 +1,199 @@
+//! # Tail
+//! Prints the records of a data model as they arrive, either straight from its topic or from the
+//! view that stores them in ClickHouse. Records are decoded from the wire format of the model and
+//! printed as pretty JSON.
+//!
+//! Rows of the view are tailed by the time they were inserted at, which the view records in its
+//! `_ingested_at` column.
+//!
+//! ## Suggested Improvements
+//! - filter records with a JSONPath expression
+//! - stream new rows from ClickHouse instead of polling the view
+
+use std::{
+    io::{Error, ErrorKind},
+    time::Duration,
+};
+
+use rdkafka::Message as KafkaMessage;
+
+use crate::{
+    cli::display::{Message, MessageType},
+    framework::controller::get_framework_object_by_name,
+    infrastructure::{
+        ingest,
+        olap::{self, clickhouse::ConfiguredDBClient},
+        stream::{
+            redpanda::{self, StartOffset},
+            StreamingEngine,
+        },
+    },
+    project::Project,
+};
+
+const TABLE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const TABLE_PAGE_SIZE: u64 = 1000;
+
+fn print_record(header: String, record: &serde_json::Value) {
+    show_message!(
+        MessageType::Info,
+        Message {
+            action: "Record".to_string(),
+            details: header,
+        }
+    );
+    match serde_json::to_string_pretty(record) {
+        Ok(pretty) => println!("{}", pretty),
+        Err(_) => println!("{}", record),
+    }
+}
+
+pub async fn tail_topic(
+    project: &Project,
+    model_name: &str,
+    from: StartOffset,
+) -> Result<(), Error> {
+    //! Consumes every partition of the topic of the model without committing offsets, so tailing
+    //! never moves the consumer groups of ClickHouse or of the flows.
+    if project.stream_config.engine != StreamingEngine::Redpanda {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            "Tailing topics requires the redpanda streaming engine",
+        ));
+    }
+
+    let fo = get_framework_object_by_name(project, model_name)?;
+
+    let consumer = redpanda::create_assigned_consumer(&project.redpanda_config, &fo.topic, from)
+        .map_err(|e| {
+            Error::new(
+                ErrorKind::Other,
+                format!("Failed to consume from topic {}: {}", fo.topic, e),
+            )
+        })?;
+
+    show_message!(
+        MessageType::Info,
+        Message {
+            action: "Tailing".to_string(),
+            details: format!("topic {} ({})", fo.topic, fo.codec.wire_format()),
+        }
+    );
+
+    loop {
+        let message = consumer.recv().await.map_err(|e| {
+            Error::new(
+                ErrorKind::Other,
+                format!("Failed to consume from topic {}: {}", fo.topic, e),
+            )
+        })?;
+
+        let header = format!(
+            "partition {} offset {} timestamp {}",
+            message.partition(),
+            message.offset(),
+            message
+                .timestamp()
+                .to_millis()
+                .and_then(ingest::millis_to_datetime)
+                .and_then(|time| time.as_str().map(|s| s.to_string()))
+                .unwrap_or_else(|| "unknown".to_string()),
+        );
+
+        match ingest::decode_payload(
+            &fo.codec,
+            &fo.data_model,
+            message.payload().unwrap_or_default(),
+        ) {
+            Ok(record) => print_record(header, &record),
+            Err(e) => {
+                show_message!(
+                    MessageType::Error,
+                    Message {
+                        action: "Undecodable".to_string(),
+                        details: format!("{}: {}", header, e),
+                    }
+                );
+            }
+        }
+    }
+}
+
+async fn latest_ingested_at(view_name: &str, client: &ConfiguredDBClient) -> Result<i64, Error> {
+    olap::clickhouse::latest_ingested_at(view_name, client)
+        .await
+        .map_err(|e| {
+            Error::new(
+                ErrorKind::Other,
+                format!("Failed to read rows of {}: {}", view_name, e),
+            )
+        })
+}
+
+pub async fn tail_table(project: &Project, model_name: &str) -> Result<(), Error> {
+    //! Polls the view of the model and prints the rows inserted since the tail started.
+    if !project.stream_config.engine.feeds_clickhouse() {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            format!(
+                "The {:?} streaming engine doesn't store data models in ClickHouse",
+                project.stream_config.engine
+            ),
+        ));
+    }
+
+    let fo = get_framework_object_by_name(project, model_name)?;
+    let view_name = format!("{}_view", fo.table.name);
+    let client = olap::clickhouse::create_client(project.clickhouse_config.clone());
+
+    let mut ingested_after = latest_ingested_at(&view_name, &client).await?;
+
+    show_message!(
+        MessageType::Info,
+        Message {
+            action: "Tailing".to_string(),
+            details: format!("table {}", view_name),
+        }
+    );
+
+    loop {
+        // The view was recreated, start over from its first row
+        if latest_ingested_at(&view_name, &client).await? < ingested_after {
+            ingested_after = 0;
+        }
+
+        loop {
+            let rows = olap::clickhouse::fetch_rows_ingested_after(
+                &view_name,
+                ingested_after,
+                TABLE_PAGE_SIZE,
+                &client,
+            )
+            .await
+            .map_err(|e| {
+                Error::new(
+                    ErrorKind::Other,
+                    format!("Failed to read rows of {}: {}", view_name, e),
+                )
+            })?;
+
+            if rows.is_empty() {
+                break;
+            }
+
+            for (ingested_at, row) in rows.iter() {
+                let header = format!(
+                    "ingested at {}",
+                    ingest::millis_to_datetime(ingested_at / 1000)
+                        .as_ref()
+                        .and_then(serde_json::Value::as_str)
+                        .unwrap_or("unknown")
+                );
+                print_record(header, row);
+                ingested_after = *ingested_at;
+            }
+        }
+
+        tokio::time::sleep(TABLE_POLL_INTERVAL).await;
+    }
+}
@@ -6,20 +6,25 @@ use std::{
 };
 
 use notify::{event::ModifyKind, Config, RecommendedWatcher, RecursiveMode, Watcher};
-use tokio::sync::Mutex;
+use tokio::{
+    sync::{Mutex, RwLock},
+    task::JoinHandle,
+};
 
 use crate::{
     framework::{
         controller::{
-            create_language_objects, create_or_replace_table, create_or_replace_view,
-            get_framework_objects, remove_table_and_topics_from_schema_file_path, FrameworkObject,
-            RouteMeta,
+            create_format_schema, create_language_objects, create_or_replace_table,
+            create_or_replace_view, get_framework_objects,
+            remove_table_and_topics_from_schema_file_path, FrameworkObject, RouteMeta,
         },
+        flows::FlowRegistry,
+        ingestion_points::load_ingestion_point_routes,
         sdks::{generate_ts_sdk, TypescriptObjects},
     },
     infrastructure::{
         olap::{self, clickhouse::ConfiguredDBClient},
-        stream,
+        stream::StreamingBackend,
     },
     project::Project,
     utilities::constants::SCHEMAS_DIR,
@@ -27,6 +32,7 @@ use crate::{
 };
 
 use super::display::{Message, MessageType};
+use super::local_webserver::events::{EventBus, ServerEvent};
 use log::{debug, info};
 
 fn schema_file_path_to_ingest_route(app_dir: PathBuf, path: &Path, table_name: String) -> PathBuf {
@@ -42,11 +48,24 @@ fn schema_file_path_to_ingest_route(app_dir: PathBuf, path: &Path, table_name: S
     PathBuf::from("ingest").join(route)
 }
 
+/// Everything a schema file is reloaded with: the services its topics, tables and routes are
+/// created in, and the bus the changes are published on.
+pub struct ReloadContext<'a> {
+    pub project: &'a Project,
+    pub configured_client: &'a ConfiguredDBClient,
+    pub streaming_backend: &'a dyn StreamingBackend,
+    pub route_table: &'a Arc<RwLock<HashMap<PathBuf, Arc<RouteMeta>>>>,
+    pub events: &'a EventBus,
+}
+
 async fn process_event(
     project: Project,
     event: notify::Event,
-    route_table: Arc<Mutex<HashMap<PathBuf, RouteMeta>>>,
+    route_table: Arc<RwLock<HashMap<PathBuf, Arc<RouteMeta>>>>,
     configured_client: &ConfiguredDBClient,
+    streaming_backend: Arc<dyn StreamingBackend>,
+    flow_registry: Arc<Mutex<FlowRegistry>>,
+    events: &EventBus,
 ) -> Result<(), Error> {
     debug!(
         "File Watcher Event Received: {:?}, with Route Table {:?}",
@@ -55,48 +74,73 @@ async fn process_event(
 
     let route = event.paths[0].clone();
 
-    match event.kind {
+    if route.starts_with(project.flows_dir()) {
+        // Any change to a flow file restarts the flow, or stops it if the file was removed
+        flow_registry
+            .lock()
+            .await
+            .reload(&route, &project, route_table, streaming_backend);
+        return Ok(());
+    }
+
+    if route.starts_with(project.ingestion_points_dir()) {
+        reload_ingestion_points(&project, &route_table, events).await;
+        return Ok(());
+    }
+
+    let context = ReloadContext {
+        project: &project,
+        configured_client,
+        streaming_backend: streaming_backend.as_ref(),
+        route_table: &route_table,
+        events,
+    };
+    let result = match event.kind {
         notify::EventKind::Create(_) => {
             // Only create tables and topics from prisma files in the datamodels directory
-            create_framework_objects_from_schema_file_path(
-                &project,
-                &route,
-                route_table,
-                configured_client,
-            )
-            .await
+            create_framework_objects_from_schema_file_path(&route, &context).await
         }
         notify::EventKind::Modify(mk) => {
             match mk {
                 ModifyKind::Name(_) => {
                     // remove the file from the routes if they don't exist in the file directory
                     if route.exists() {
-                        create_framework_objects_from_schema_file_path(
-                            &project,
-                            &route,
-                            route_table,
-                            configured_client,
-                        )
-                        .await
+                        create_framework_objects_from_schema_file_path(&route, &context).await
                     } else {
+                        let removed_routes = route_table
+                            .read()
+                            .await
+                            .iter()
+                            .filter(|(_, meta)| meta.original_file_path == route)
+                            .map(|(ingest_route, _)| ingest_route.display().to_string())
+                            .collect::<Vec<String>>();
+
                         remove_table_and_topics_from_schema_file_path(
+                            &project,
                             &route,
-                            route_table,
+                            Arc::clone(&route_table),
                             configured_client,
+                            streaming_backend.as_ref(),
                         )
-                        .await
+                        .await?;
+
+                        if !removed_routes.is_empty() {
+                            events.publish(ServerEvent::SchemaChanged {
+                                file: route.display().to_string(),
+                            });
+                        }
+                        for removed_route in removed_routes {
+                            events.publish(ServerEvent::RouteRemoved {
+                                route: removed_route,
+                            });
+                        }
+                        Ok(())
                     }
                 }
 
                 ModifyKind::Data(_) => {
                     if route.exists() {
-                        create_framework_objects_from_schema_file_path(
-                            &project,
-                            &route,
-                            route_table,
-                            configured_client,
-                        )
-                        .await?
+                        create_framework_objects_from_schema_file_path(&route, &context).await?
                     }
                     Ok(())
                 }
@@ -105,20 +149,87 @@ async fn process_event(
         }
         notify::EventKind::Remove(_) => Ok(()),
         _ => Ok(()),
+    };
+
+    // Ingestion points are rebound so that they follow the models they target
+    if result.is_ok() && route.starts_with(project.schemas_dir()) {
+        reload_ingestion_points(&project, &route_table, events).await;
     }
+    result
 }
 
-async fn create_framework_objects_from_schema_file_path(
+pub fn ingestion_point_routes(
+    project: &Project,
+    routes: &HashMap<PathBuf, Arc<RouteMeta>>,
+    events: &EventBus,
+) -> Vec<(PathBuf, Arc<RouteMeta>)> {
+    //! The routes of the ingestion points bound to the data model routes in `routes`. Invalid
+    //! ingestion points are reported and left out, so that they don't take the others down.
+    let (ingestion_point_routes, errors) = load_ingestion_point_routes(project, routes);
+    for (file_path, e) in errors {
+        show_message!(
+            MessageType::Error,
+            Message {
+                action: "Ingestion point".to_string(),
+                details: e.to_string(),
+            }
+        );
+        events.publish(ServerEvent::WatcherError {
+            file: file_path.display().to_string(),
+            message: e.to_string(),
+        });
+    }
+    ingestion_point_routes
+}
+
+pub async fn reload_ingestion_points(
     project: &Project,
+    route_table: &Arc<RwLock<HashMap<PathBuf, Arc<RouteMeta>>>>,
+    events: &EventBus,
+) {
+    //! Replaces the routes of the ingestion points with the ones of their files as they are now.
+    let loaded = ingestion_point_routes(project, &*route_table.read().await, events);
+
+    let mut table = route_table.write().await;
+    let previous = table
+        .iter()
+        .filter(|(_, meta)| meta.ingestion_point.is_some())
+        .map(|(route, _)| route.clone())
+        .collect::<Vec<PathBuf>>();
+    for route in &previous {
+        table.remove(route);
+    }
+    let current = loaded
+        .iter()
+        .map(|(route, _)| route.clone())
+        .collect::<Vec<PathBuf>>();
+    table.extend(loaded);
+    drop(table);
+
+    for route in previous.iter().filter(|route| !current.contains(route)) {
+        events.publish(ServerEvent::RouteRemoved {
+            route: route.display().to_string(),
+        });
+    }
+    for route in current.iter().filter(|route| !previous.contains(route)) {
+        events.publish(ServerEvent::RouteAdded {
+            route: route.display().to_string(),
+        });
+    }
+}
+
+async fn create_framework_objects_from_schema_file_path(
     schema_file_path: &Path,
-    route_table: Arc<Mutex<HashMap<PathBuf, RouteMeta>>>,
-    configured_client: &ConfiguredDBClient,
+    context: &ReloadContext<'_>,
 ) -> Result<(), Error> {
     //! Creates the route, topics and tables from a path to the schema file
 
     if let Some(ext) = schema_file_path.extension() {
         if ext == "prisma" && schema_file_path.to_str().unwrap().contains(SCHEMAS_DIR) {
-            process_schema_file(schema_file_path, project, configured_client, route_table).await?;
+            context.events.publish(ServerEvent::SchemaChanged {
+                file: schema_file_path.display().to_string(),
+            });
+            process_schema_file(schema_file_path, context).await?;
         }
     } else {
         info!("No primsa extension found. Likely created unsupported file type")
@@ -128,19 +239,16 @@ async fn create_framework_objects_from_schema_file_path(
 
 pub async fn process_schema_file(
     schema_file_path: &Path,
-    project: &Project,
-    configured_client: &ConfiguredDBClient,
-    route_table: Arc<Mutex<HashMap<PathBuf, RouteMeta>>>,
+    context: &ReloadContext<'_>,
 ) -> Result<(), Error> {
-    let framework_objects = get_framework_objects(schema_file_path)?;
+    let project = context.project;
+    let framework_objects = get_framework_objects(schema_file_path, project)?;
     let mut compilable_objects: Vec<TypescriptObjects> = Vec::new();
     process_objects(
         framework_objects,
-        project,
         schema_file_path,
-        configured_client,
         &mut compilable_objects,
-        route_table,
+        context,
     )
     .await?;
     debug!("All objects created, generating sdk...");
@@ -149,60 +257,132 @@ pub async fn process_schema_file(
     package_managers::install_packages(&sdk_location, &package_manager)?;
     package_managers::run_build(&sdk_location, &package_manager)?;
     package_managers::link_sdk(&sdk_location, None, &package_manager)?;
+    context.events.publish(ServerEvent::SdkRebuilt {
+        location: sdk_location.display().to_string(),
+    });
     Ok(())
 }
 
+fn route_meta(
+    fo: &FrameworkObject,
+    schema_file_path: &Path,
+    view_name: Option<String>,
+) -> RouteMeta {
+    RouteMeta {
+        original_file_path: schema_file_path.to_path_buf(),
+        table_name: fo.table.name.clone(),
+        view_name,
+        data_model: fo.data_model.clone(),
+        codec: fo.codec.clone(),
+        metadata_columns: fo.metadata_columns.clone(),
+        dedup_field: fo.dedup.as_ref().map(|dedup| dedup.field.clone()),
+        delivery: fo.delivery,
+        ingestion_point: None,
+    }
+}
+
+pub fn load_schema_file_routes(
+    schema_file_path: &Path,
+    project: &Project,
+) -> Result<Vec<(PathBuf, Arc<RouteMeta>)>, Error> {
+    //! The routes of the data models in the schema file, without creating their topics, tables or
+    //! SDK, which are expected to exist already.
+    let framework_objects = get_framework_objects(schema_file_path, project)?;
+
+    Ok(framework_objects
+        .iter()
+        .map(|fo| {
+            let ingest_route = schema_file_path_to_ingest_route(
+                project.app_dir().clone(),
+                schema_file_path,
+                fo.table.name.clone(),
+            );
+            let view_name = project
+                .stream_config
+                .engine
+                .feeds_clickhouse()
+                .then(|| format!("{}_view", fo.table.name));
+            (
+                ingest_route,
+                Arc::new(route_meta(fo, schema_file_path, view_name)),
+            )
+        })
+        .collect())
+}
+
 async fn process_objects(
     framework_objects: Vec<FrameworkObject>,
-    project: &Project,
     schema_file_path: &Path,
-    configured_client: &ConfiguredDBClient,
     compilable_objects: &mut Vec<TypescriptObjects>, // Objects that require compilation after processing
-    route_table: Arc<Mutex<HashMap<PathBuf, RouteMeta>>>,
+    context: &ReloadContext<'_>,
 ) -> Result<(), Error> {
-    let mut route_table = route_table.lock().await;
-
+    let ReloadContext {
+        project,
+        configured_client,
+        streaming_backend,
+        route_table,
+        events,
+    } = context;
+    // The route table is only locked to insert each route, so requests keep flowing while the
+    // topics and tables are created
     for fo in framework_objects {
         let ingest_route = schema_file_path_to_ingest_route(
             project.app_dir().clone(),
             schema_file_path,
             fo.table.name.clone(),
         );
-        stream::redpanda::create_topic_from_name(fo.topic.clone())?;
+        streaming_backend.create_topic(&fo.topic).await?;
 
-        debug!("Creating table & view: {:?}", fo.table.name);
+        // ClickHouse reads the topics with its kafka engine, which only works with some streaming engines
+        let view_name = if project.stream_config.engine.feeds_clickhouse() {
+            debug!("Creating table & view: {:?}", fo.table.name);
 
-        let view_name = format!("{}_view", fo.table.name);
+            let view_name = format!("{}_view", fo.table.name);
 
-        create_or_replace_table(&fo, configured_client).await?;
-        create_or_replace_view(&fo, view_name.clone(), configured_client).await?;
+            create_format_schema(&fo, project)?;
+            create_or_replace_table(&fo, configured_client).await?;
+            create_or_replace_view(&fo, view_name.clone(), configured_client).await?;
 
-        debug!("Table created: {:?}", fo.table.name);
+            debug!("Table created: {:?}", fo.table.name);
+            events.publish(ServerEvent::TableRecreated {
+                table: fo.table.name.clone(),
+            });
+            Some(view_name)
+        } else {
+            None
+        };
 
         let typescript_objects = create_language_objects(&fo, &ingest_route, project)?;
         compilable_objects.push(typescript_objects);
 
-        route_table.insert(
+        let route_path = ingest_route.display().to_string();
+        let previous = route_table.write().await.insert(
             ingest_route,
-            RouteMeta {
-                original_file_path: schema_file_path.to_path_buf(),
-                table_name: fo.table.name.clone(),
-                view_name: Some(view_name),
-            },
+            Arc::new(route_meta(&fo, schema_file_path, view_name)),
         );
+        if previous.is_none() {
+            events.publish(ServerEvent::RouteAdded { route: route_path });
+        }
     }
     Ok(())
 }
 
 async fn watch(
     project: &Project,
-    route_table: Arc<Mutex<HashMap<PathBuf, RouteMeta>>>,
+    route_table: Arc<RwLock<HashMap<PathBuf, Arc<RouteMeta>>>>,
+    streaming_backend: Arc<dyn StreamingBackend>,
+    flow_registry: Arc<Mutex<FlowRegistry>>,
+    events: EventBus,
 ) -> Result<(), Error> {
     let configured_client = olap::clickhouse::create_client(project.clickhouse_config.clone());
 
-    let (tx, rx) = std::sync::mpsc::channel();
+    // Events are received on an async channel so that aborting the task stops the watcher right away
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
 
-    let mut watcher = RecommendedWatcher::new(tx, Config::default()).map_err(|e| {
+    let event_handler = move |res| {
+        let _ = tx.send(res);
+    };
+    let mut watcher = RecommendedWatcher::new(event_handler, Config::default()).map_err(|e| {
         Error::new(
             ErrorKind::Other,
             format!("Failed to create file watcher: {}", e),
@@ -213,7 +393,7 @@ async fn watch(
         .watch(project.app_dir().as_ref(), RecursiveMode::Recursive)
         .map_err(|e| Error::new(ErrorKind::Other, format!("Failed to watch file: {}", e)))?;
 
-    for res in rx {
+    while let Some(res) = rx.recv().await {
         match res {
             Ok(event) => {
                 process_event(
@@ -221,9 +401,16 @@ async fn watch(
                     event.clone(),
                     Arc::clone(&route_table),
                     &configured_client,
+                    Arc::clone(&streaming_backend),
+                    Arc::clone(&flow_registry),
+                    &events,
                 )
                 .await
                 .map_err(|e| {
+                    events.publish(ServerEvent::WatcherError {
+                        file: event.paths[0].display().to_string(),
+                        message: e.to_string(),
+                    });
                     Error::new(ErrorKind::Other, format!("Processing error occured: {}", e))
                 })?;
             }
@@ -249,8 +436,12 @@ impl FileWatcher {
     pub fn start(
         &self,
         project: &Project,
-        route_table: Arc<Mutex<HashMap<PathBuf, RouteMeta>>>,
-    ) -> Result<(), Error> {
+        route_table: Arc<RwLock<HashMap<PathBuf, Arc<RouteMeta>>>>,
+        streaming_backend: Arc<dyn StreamingBackend>,
+        flow_registry: Arc<Mutex<FlowRegistry>>,
+        events: EventBus,
+    ) -> Result<JoinHandle<()>, Error> {
+        //! Watches the project in a task, which stops the watcher when aborted.
         show_message!(MessageType::Info, {
             Message {
                 action: "Watching".to_string(),
@@ -259,12 +450,20 @@ impl FileWatcher {
         });
         let project = project.clone();
 
-        tokio::spawn(async move {
-            if let Err(error) = watch(&project, Arc::clone(&route_table)).await {
+        let handle = tokio::spawn(async move {
+            if let Err(error) = watch(
+                &project,
+                Arc::clone(&route_table),
+                streaming_backend,
+                flow_registry,
+                events,
+            )
+            .await
+            {
                 println!("Error: {error:?}");
             }
         });
 
-        Ok(())
+        Ok(handle)
     }
 }
@@ -2,6 +2,8 @@ use clap::Subcommand;
 
 pub mod client_app;
 pub mod controller;
+pub mod flows;
+pub mod ingestion_points;
 pub mod languages;
 pub mod schema;
 pub mod sdks;
@@ -1,6 +1,12 @@
 use crate::framework::languages::CodeGenerator;
+use crate::infrastructure::ingest::dedup::ModelDeduplication;
+use crate::infrastructure::ingest::metadata::{self, MetadataColumn};
+use crate::infrastructure::ingest::protobuf::ProtoSchema;
+use crate::infrastructure::ingest::DeliveryMode;
+use crate::infrastructure::ingest::RecordCodec;
+use crate::infrastructure::ingest::WireFormat;
 use crate::infrastructure::olap::clickhouse::ClickhouseTable;
-use crate::infrastructure::stream;
+use crate::infrastructure::stream::StreamingBackend;
 
 use std::collections::HashMap;
 use std::path::Path;
@@ -12,7 +18,8 @@ use crate::framework;
 
 use log::debug;
 use log::info;
-use tokio::sync::Mutex;
+use log::warn;
+use tokio::sync::RwLock;
 
 use crate::framework::typescript::get_typescript_models_dir;
 
@@ -28,12 +35,13 @@ use crate::infrastructure::olap;
 
 use std::io::ErrorKind;
 
-use crate::infrastructure::olap::clickhouse::ClickhouseView;
+use crate::infrastructure::olap::clickhouse::{ClickhouseView, ClickhouseViewEngine};
 
 use std::io::Error;
 
 use crate::infrastructure::olap::clickhouse::ConfiguredDBClient;
 
+use super::ingestion_points::{IngestionPoint, IngestionPointAuth};
 use super::schema::parse_schema_file;
 use super::schema::MatViewOps;
 use super::schema::Table;
@@ -41,17 +49,40 @@ use super::schema::TableOps;
 use super::typescript::TypescriptInterface;
 
 pub struct FrameworkObject {
+    pub data_model: Table,
     pub table: ClickhouseTable,
     pub topic: String,
     pub ts_interface: TypescriptInterface,
+    pub codec: RecordCodec,
+    pub metadata_columns: Vec<MetadataColumn>,
+    pub dedup: Option<ModelDeduplication>,
+    pub delivery: DeliveryMode,
+}
+
+impl FrameworkObject {
+    pub fn set_codec(&mut self, codec: RecordCodec) {
+        //! Changes the format records are produced in, and the table that reads them, whose input
+        //! format and DateTime precision follow the wire format.
+        self.table = olap::clickhouse::mapper::std_table_to_clickhouse_table(
+            self.data_model.clone(),
+            codec.wire_format(),
+        );
+        self.codec = codec;
+    }
 }
 
 pub fn framework_object_mapper(t: Table) -> FrameworkObject {
-    let clickhouse_table = olap::clickhouse::mapper::std_table_to_clickhouse_table(t.clone());
+    let clickhouse_table =
+        olap::clickhouse::mapper::std_table_to_clickhouse_table(t.clone(), WireFormat::Json);
     FrameworkObject {
+        data_model: t.clone(),
         table: clickhouse_table.clone(),
         topic: t.name.clone(),
         ts_interface: framework::typescript::mapper::std_table_to_typescript_interface(t),
+        codec: RecordCodec::Json,
+        metadata_columns: vec![],
+        dedup: None,
+        delivery: DeliveryMode::default(),
     }
 }
 
@@ -60,17 +91,163 @@ pub struct RouteMeta {
     pub original_file_path: PathBuf,
     pub table_name: String,
     pub view_name: Option<String>,
+    pub data_model: Table,
+    pub codec: RecordCodec,
+    pub metadata_columns: Vec<MetadataColumn>,
+    pub dedup_field: Option<String>,
+    pub delivery: DeliveryMode,
+    // Set on the routes of ingestion points, which share the rest with the route of their model
+    pub ingestion_point: Option<IngestionPoint>,
 }
 
-pub fn get_framework_objects(route: &Path) -> Result<Vec<FrameworkObject>, Error> {
-    let framework_objects = parse_schema_file::<FrameworkObject>(route, framework_object_mapper)
-        .map_err(|e| {
-            Error::new(
-                ErrorKind::Other,
-                format!("Failed to parse schema file. Error {}", e),
-            )
-        })?;
-    Ok(framework_objects)
+impl RouteMeta {
+    pub fn methods(&self) -> Vec<String> {
+        //! The methods the route takes, only POST unless an ingestion point declares others.
+        match &self.ingestion_point {
+            Some(ingestion_point) => ingestion_point.methods.clone(),
+            None => vec!["POST".to_string()],
+        }
+    }
+
+    pub fn is_public(&self) -> bool {
+        self.ingestion_point
+            .as_ref()
+            .map_or(false, |ingestion_point| {
+                ingestion_point.auth == IngestionPointAuth::None
+            })
+    }
+}
+
+fn read_proto_definition(project: &Project, data_model: &Table) -> Result<Option<String>, Error> {
+    //! Reads the protobuf definition last generated for the data model, if there is one.
+    match std::fs::read_to_string(
+        project
+            .format_schemas_dir()
+            .join(ProtoSchema::file_name(data_model)),
+    ) {
+        Ok(definition) => Ok(Some(definition)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn get_framework_objects(
+    route: &Path,
+    project: &Project,
+) -> Result<Vec<FrameworkObject>, Error> {
+    let ingest_config = &project.ingest_config;
+    let tables = parse_schema_file::<Table>(route, |t| t).map_err(|e| {
+        Error::new(
+            ErrorKind::Other,
+            format!("Failed to parse schema file. Error {}", e),
+        )
+    })?;
+
+    tables
+        .into_iter()
+        .map(|mut t| {
+            // The metadata columns are part of the model so that every generated object declares them
+            metadata::add_metadata_columns(&mut t, &ingest_config.metadata_columns);
+
+            let dedup = ingest_config.deduplication.model(&t.name).cloned();
+            if let Some(dedup) = &dedup {
+                if !t.columns.iter().any(|column| column.name == dedup.field) {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!(
+                            "The dedup field {} is not a field of the {} data model",
+                            dedup.field, t.name
+                        ),
+                    ));
+                }
+            }
+
+            let mut fo = framework_object_mapper(t);
+            fo.metadata_columns = ingest_config.metadata_columns.clone();
+            fo.dedup = dedup;
+            fo.delivery = ingest_config.delivery_mode(&fo.data_model.name);
+
+            let wire_format = ingest_config.wire_format(&fo.data_model.name);
+            let proto_definition = match wire_format {
+                WireFormat::Protobuf => read_proto_definition(project, &fo.data_model)?,
+                _ => None,
+            };
+            let codec = RecordCodec::new(wire_format, &fo.data_model, proto_definition.as_deref())
+                .map_err(|e| {
+                    Error::new(
+                        ErrorKind::InvalidInput,
+                        format!(
+                            "Failed to build the {} schema of the {} data model: {}",
+                            wire_format, fo.data_model.name, e
+                        ),
+                    )
+                })?;
+            fo.set_codec(codec);
+            Ok(fo)
+        })
+        .collect()
+}
+
+pub fn get_framework_object_by_name(
+    project: &Project,
+    model_name: &str,
+) -> Result<FrameworkObject, Error> {
+    //! Finds a data model by name in the schema files of the project.
+    find_framework_object(&project.schemas_dir(), project, model_name)?.ok_or_else(|| {
+        Error::new(
+            ErrorKind::NotFound,
+            format!("Data model {} not found in the schema files", model_name),
+        )
+    })
+}
+
+fn find_framework_object(
+    dir: &Path,
+    project: &Project,
+    model_name: &str,
+) -> Result<Option<FrameworkObject>, Error> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            if let Some(fo) = find_framework_object(&path, project, model_name)? {
+                return Ok(Some(fo));
+            }
+        } else if path.extension().map_or(false, |ext| ext == "prisma") {
+            let found = get_framework_objects(&path, project)?
+                .into_iter()
+                .find(|fo| fo.data_model.name.eq_ignore_ascii_case(model_name));
+            if found.is_some() {
+                return Ok(found);
+            }
+        }
+    }
+    Ok(None)
+}
+
+pub(crate) fn create_format_schema(fo: &FrameworkObject, project: &Project) -> Result<(), Error> {
+    //! Writes the schema file ClickHouse needs to decode the topic, if the wire format requires one.
+    //!
+    //! The clickhouse data volume is mounted at `/var/lib/clickhouse`, so files written to the
+    //! `format_schemas` directory of the volume are picked up by the server. The definition is kept
+    //! in the app directory as well, where the next build reads its field numbers from.
+    let proto_schema = match &fo.codec {
+        RecordCodec::Protobuf(proto_schema) => proto_schema,
+        _ => return Ok(()),
+    };
+
+    let format_schemas_dir = project
+        .internal_dir()?
+        .join(".clickhouse/data/format_schemas");
+
+    debug!("Writing protobuf schema for: {:?}", fo.data_model.name);
+    for dir in [format_schemas_dir, project.format_schemas_dir()] {
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(
+            dir.join(ProtoSchema::file_name(&fo.data_model)),
+            proto_schema.definition(),
+        )?;
+    }
+    Ok(())
 }
 
 pub(crate) async fn create_or_replace_view(
@@ -78,7 +255,12 @@ pub(crate) async fn create_or_replace_view(
     view_name: String,
     configured_client: &ConfiguredDBClient,
 ) -> Result<(), Error> {
-    let view = ClickhouseView::new(fo.table.db_name.clone(), view_name, fo.table.clone());
+    let mut view = ClickhouseView::new(fo.table.db_name.clone(), view_name, fo.table.clone());
+    if let Some(dedup) = fo.dedup.as_ref().filter(|dedup| dedup.replacing_merge_tree) {
+        view.engine = ClickhouseViewEngine::ReplacingMergeTree {
+            order_by: dedup.field.clone(),
+        };
+    }
     let create_view_query = view.create_materialized_view_query().map_err(|e| {
         Error::new(
             ErrorKind::Other,
@@ -86,21 +268,45 @@ pub(crate) async fn create_or_replace_view(
         )
     })?;
 
-    // Clickhouse doesn't support dropping a view if it doesn't exist so we need to drop it first in case the schema has changed
-    let drop_view_query = view.drop_materialized_view_query().map_err(|e| {
-        Error::new(
-            ErrorKind::Other,
-            format!("Failed to get clickhouse query: {:?}", e),
-        )
-    })?;
-    olap::clickhouse::run_query(drop_view_query, configured_client)
+    let existing = olap::clickhouse::fetch_view_definition(&view.name, configured_client)
         .await
         .map_err(|e| {
             Error::new(
                 ErrorKind::Other,
-                format!("Failed to drop view in clickhouse: {}", e),
+                format!("Failed to read view {} from clickhouse: {}", view.name, e),
             )
         })?;
+
+    match existing {
+        // Kept as is so that the rows it stores survive restarts
+        Some(definition) if view.matches_definition(&definition) => {
+            debug!("View {} is up to date", view.name);
+            return Ok(());
+        }
+        // The engine, the dedup field or the columns changed, which the view can't be altered for
+        Some(_) => {
+            warn!(
+                "Recreating view {} since its definition changed, its rows are dropped",
+                view.name
+            );
+            let drop_view_query = view.drop_materialized_view_query().map_err(|e| {
+                Error::new(
+                    ErrorKind::Other,
+                    format!("Failed to get clickhouse query: {:?}", e),
+                )
+            })?;
+            olap::clickhouse::run_query(drop_view_query, configured_client)
+                .await
+                .map_err(|e| {
+                    Error::new(
+                        ErrorKind::Other,
+                        format!("Failed to drop view in clickhouse: {}", e),
+                    )
+                })?;
+        }
+        None => {}
+    }
+
     olap::clickhouse::run_query(create_view_query, configured_client)
         .await
         .map_err(|e| {
@@ -208,39 +414,52 @@ pub(crate) fn create_language_objects(
 }
 
 pub async fn remove_table_and_topics_from_schema_file_path(
+    project: &Project,
     shcema_file_path: &Path,
-    route_table: Arc<Mutex<HashMap<PathBuf, RouteMeta>>>,
+    route_table: Arc<RwLock<HashMap<PathBuf, Arc<RouteMeta>>>>,
     configured_client: &ConfiguredDBClient,
+    streaming_backend: &dyn StreamingBackend,
 ) -> Result<(), Error> {
     //need to get the path of the file, scan the route table and remove all the files that need to be deleted.
     // This doesn't have to be as fast as the scanning for routes in the web server so we're ok with the scan here.
-    let mut route_table = route_table.lock().await;
+    // The routes are only locked to be read and removed, so requests keep flowing while the infrastructure is deleted.
+    // Every route is removed before its infrastructure so that no request is accepted for a topic that's going away.
+    let routes = {
+        let mut route_table = route_table.write().await;
+        let paths = route_table
+            .iter()
+            .filter(|(_, meta)| meta.original_file_path == shcema_file_path)
+            .map(|(k, _)| k.clone())
+            .collect::<Vec<PathBuf>>();
+        paths
+            .into_iter()
+            .filter_map(|k| route_table.remove(&k))
+            .collect::<Vec<Arc<RouteMeta>>>()
+    };
 
-    for (k, meta) in route_table.clone().into_iter() {
-        if meta.original_file_path == shcema_file_path {
-            stream::redpanda::delete_topic(meta.table_name.clone())?;
+    for meta in routes {
+        streaming_backend.delete_topic(&meta.table_name).await?;
 
-            olap::clickhouse::delete_table_or_view(meta.table_name, configured_client)
+        if project.stream_config.engine.feeds_clickhouse() {
+            olap::clickhouse::delete_table_or_view(meta.table_name.clone(), configured_client)
                 .await
                 .map_err(|e| {
                     Error::new(
                         ErrorKind::Other,
-                        format!("Failed to create table in clickhouse: {}", e),
+                        format!("Failed to delete table in clickhouse: {}", e),
                     )
                 })?;
 
-            if let Some(view_name) = meta.view_name {
+            if let Some(view_name) = meta.view_name.clone() {
                 olap::clickhouse::delete_table_or_view(view_name, configured_client)
                     .await
                     .map_err(|e| {
                         Error::new(
                             ErrorKind::Other,
-                            format!("Failed to create table in clickhouse: {}", e),
+                            format!("Failed to delete view in clickhouse: {}", e),
                         )
                     })?;
             }
-
-            route_table.remove(&k);
         }
     }
     Ok(())
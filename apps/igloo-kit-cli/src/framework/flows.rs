@@ -0,0 +1,461 @@
+//! # Flows
+//! Flows are user defined transformations that take records from the topic of one data model and
+//! produce them to the topic of another data model.
+//!
+//! A flow lives in `app/flows/<SourceModel>/<TargetModel>/flow.ts` and default exports a function
+//! typed with the interfaces of the generated SDK:
+//!
+//! ```ts
+//! import { UserActivity, ParsedActivity } from 'my-app-sdk';
+//!
+//! export default function run(event: UserActivity): ParsedActivity | null {
+//!     return { ...event, parsed: true };
+//! }
+//! ```
+//!
+//! Returning `null` drops the record and returning an array produces every element. During
+//! `igloo dev` each flow runs as a consume -> transform -> produce loop, where the transformation
+//! happens in a node process fed one JSON record per line.
+//!
+//! Flows are type checked with `tsc --noEmit` and run with the `tsx` pinned in the generated SDK.
+//! A record is committed once its outputs are produced, and a flow that fails is restarted with
+//! an exponential backoff from the last committed record.
+//!
+//! ## Suggested Improvements
+//! - support flows reading from topics that are not encoded as JSON
+//! - batch records sent to the node process
+
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind},
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use log::{debug, error, info, warn};
+use serde_json::Value;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    process::Command,
+    sync::RwLock,
+    task::JoinHandle,
+};
+
+use crate::{
+    infrastructure::{
+        ingest::{self, WireFormat},
+        stream::StreamingBackend,
+    },
+    project::Project,
+    utilities::constants::FLOW_FILE,
+};
+
+use super::{controller::RouteMeta, sdks};
+
+pub static FLOW_RUNNER_TEMPLATE: &str = r#"
+import { createInterface } from 'node:readline';
+import { pathToFileURL } from 'node:url';
+
+const flow = (await import(pathToFileURL(process.argv[2]).href)).default;
+
+for await (const line of createInterface({ input: process.stdin })) {
+    try {
+        const output = await flow(JSON.parse(line));
+        process.stdout.write(JSON.stringify(output ?? null) + '\n');
+    } catch (e) {
+        process.stderr.write(`${e}\n`);
+        process.stdout.write('null\n');
+    }
+}
+"#;
+
+const FLOW_RUNNER_FILE: &str = "flow-runner.mts";
+
+const MIN_RESTART_DELAY: Duration = Duration::from_secs(1);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+// A flow that ran for this long before failing restarts with the minimum delay again
+const HEALTHY_RUN: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct Flow {
+    pub source_model: String,
+    pub target_model: String,
+    pub file_path: PathBuf,
+}
+
+impl Flow {
+    pub fn from_file_path(flows_dir: &Path, file_path: &Path) -> Option<Flow> {
+        //! Reads the source and target models from a `<SourceModel>/<TargetModel>/flow.ts` path.
+        let relative = file_path.strip_prefix(flows_dir).ok()?;
+        let parts = relative
+            .iter()
+            .map(|part| part.to_str())
+            .collect::<Option<Vec<&str>>>()?;
+
+        match parts[..] {
+            [source_model, target_model, file_name] if file_name == FLOW_FILE => Some(Flow {
+                source_model: source_model.to_string(),
+                target_model: target_model.to_string(),
+                file_path: file_path.to_path_buf(),
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> String {
+        format!("{} -> {}", self.source_model, self.target_model)
+    }
+
+    pub fn consumer_group(&self) -> String {
+        format!("flow-{}-{}", self.source_model, self.target_model)
+    }
+}
+
+pub fn get_all_flows(flows_dir: &Path) -> Result<Vec<Flow>, Error> {
+    let mut flows = vec![];
+
+    if !flows_dir.is_dir() {
+        return Ok(flows);
+    }
+
+    for source_entry in std::fs::read_dir(flows_dir)? {
+        let source_path = source_entry?.path();
+        if !source_path.is_dir() {
+            continue;
+        }
+        for target_entry in std::fs::read_dir(&source_path)? {
+            let flow_file = target_entry?.path().join(FLOW_FILE);
+            if let Some(flow) = Flow::from_file_path(flows_dir, &flow_file) {
+                if flow_file.exists() {
+                    flows.push(flow);
+                }
+            }
+        }
+    }
+
+    Ok(flows)
+}
+
+fn write_flow_runner(project: &Project) -> Result<PathBuf, Error> {
+    let runner_dir = project.internal_dir()?.join("flows");
+    std::fs::create_dir_all(&runner_dir)?;
+
+    let runner_path = runner_dir.join(FLOW_RUNNER_FILE);
+    std::fs::write(&runner_path, FLOW_RUNNER_TEMPLATE)?;
+    Ok(runner_path)
+}
+
+async fn find_route_meta(
+    route_table: &Arc<RwLock<HashMap<PathBuf, Arc<RouteMeta>>>>,
+    model_name: &str,
+) -> Option<Arc<RouteMeta>> {
+    //! Finds the route of the data model itself, ignoring the ingestion points that feed it.
+    route_table
+        .read()
+        .await
+        .values()
+        .find(|meta| meta.ingestion_point.is_none() && meta.data_model.name == model_name)
+        .cloned()
+}
+
+fn node_bin(sdk_dir: &Path, bin: &str) -> PathBuf {
+    sdk_dir.join("node_modules").join(".bin").join(bin)
+}
+
+async fn type_check(flow: &Flow, project: &Project, sdk_dir: &Path) -> Result<(), Error> {
+    //! Type checks the flow against the interfaces of the SDK before it gets to run.
+    let output = Command::new(node_bin(sdk_dir, "tsc"))
+        .arg("--noEmit")
+        .arg("--strict")
+        .arg("--skipLibCheck")
+        .arg("--module")
+        .arg("nodenext")
+        .arg("--moduleResolution")
+        .arg("nodenext")
+        .arg(&flow.file_path)
+        .current_dir(project.app_dir())
+        .output()
+        .await?;
+
+    if output.status.success() {
+        return Ok(());
+    }
+
+    Err(Error::new(
+        ErrorKind::InvalidData,
+        format!(
+            "Flow {} failed to type check:\n{}{}",
+            flow.name(),
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        ),
+    ))
+}
+
+async fn run_flow(
+    flow: Flow,
+    project: Project,
+    route_table: Arc<RwLock<HashMap<PathBuf, Arc<RouteMeta>>>>,
+    streaming_backend: Arc<dyn StreamingBackend>,
+) -> Result<(), Error> {
+    let source = find_route_meta(&route_table, &flow.source_model)
+        .await
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!("Source data model {} not found", flow.source_model),
+            )
+        })?;
+
+    if source.codec.wire_format() != WireFormat::Json {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            format!(
+                "Flows can only read JSON topics, {} uses {}",
+                flow.source_model,
+                source.codec.wire_format()
+            ),
+        ));
+    }
+
+    let sdk_dir = sdks::sdk_dir(&project)?;
+    type_check(&flow, &project, &sdk_dir).await?;
+
+    let runner_path = write_flow_runner(&project)?;
+
+    let mut subscription = streaming_backend
+        .subscribe(&source.table_name, &flow.consumer_group())
+        .await?;
+
+    let mut child = Command::new(node_bin(&sdk_dir, "tsx"))
+        .arg(&runner_path)
+        .arg(&flow.file_path)
+        .current_dir(project.app_dir())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    let mut stdin = child
+        .stdin
+        .take()
+        .ok_or_else(|| Error::new(ErrorKind::Other, "Failed to open flow stdin"))?;
+    let mut stdout = BufReader::new(
+        child
+            .stdout
+            .take()
+            .ok_or_else(|| Error::new(ErrorKind::Other, "Failed to open flow stdout"))?,
+    )
+    .lines();
+
+    info!("Started flow: {}", flow.name());
+
+    loop {
+        let record = subscription.recv().await?;
+
+        // Records are re-serialized so that every record fits on a single line
+        let input = match serde_json::from_slice::<Value>(&record.payload) {
+            Ok(input) => input,
+            Err(_) => {
+                debug!(
+                    "Skipping record {} of partition {} that isn't JSON in flow {}",
+                    record.offset,
+                    record.partition,
+                    flow.name()
+                );
+                subscription.commit(&record).await?;
+                continue;
+            }
+        };
+
+        stdin.write_all(format!("{}\n", input).as_bytes()).await?;
+
+        let output_line = stdout.next_line().await?.ok_or_else(|| {
+            Error::new(
+                ErrorKind::BrokenPipe,
+                format!("Flow {} stopped unexpectedly", flow.name()),
+            )
+        })?;
+
+        let outputs = match serde_json::from_str::<Value>(&output_line) {
+            Ok(Value::Null) => vec![],
+            Ok(Value::Array(outputs)) => outputs,
+            Ok(output) => vec![output],
+            Err(e) => {
+                error!("Flow {} returned invalid JSON: {}", flow.name(), e);
+                subscription.commit(&record).await?;
+                continue;
+            }
+        };
+
+        if outputs.is_empty() {
+            subscription.commit(&record).await?;
+            continue;
+        }
+
+        // Looked up for every record since the target model can be changed while the flow runs
+        let target = find_route_meta(&route_table, &flow.target_model)
+            .await
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::NotFound,
+                    format!("Target data model {} not found", flow.target_model),
+                )
+            })?;
+
+        for output in outputs {
+            let payload = match ingest::encode_record(&target.codec, &target.data_model, &output) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    error!("Flow {} produced an invalid record: {}", flow.name(), e);
+                    continue;
+                }
+            };
+
+            // The record is consumed again after the restart since it isn't committed
+            streaming_backend
+                .produce(&target.table_name, &target.table_name, &payload)
+                .await
+                .map_err(|e| {
+                    Error::new(
+                        e.kind(),
+                        format!("Flow {} failed to produce a record: {}", flow.name(), e),
+                    )
+                })?;
+        }
+
+        subscription.commit(&record).await?;
+    }
+}
+
+async fn supervise_flow(
+    flow: Flow,
+    project: Project,
+    route_table: Arc<RwLock<HashMap<PathBuf, Arc<RouteMeta>>>>,
+    streaming_backend: Arc<dyn StreamingBackend>,
+) {
+    //! Runs the flow until it fails with an error that a restart can't fix, restarting it with an
+    //! exponential backoff otherwise.
+    let mut delay = MIN_RESTART_DELAY;
+
+    loop {
+        let started = Instant::now();
+        let result = run_flow(
+            flow.clone(),
+            project.clone(),
+            Arc::clone(&route_table),
+            Arc::clone(&streaming_backend),
+        )
+        .await;
+
+        let e = match result {
+            Ok(()) => return,
+            Err(e) => e,
+        };
+
+        // The flow only changes when its file does, which starts it again
+        if matches!(e.kind(), ErrorKind::InvalidData | ErrorKind::Unsupported) {
+            error!("Flow {} stopped: {}", flow.name(), e);
+            return;
+        }
+
+        if started.elapsed() >= HEALTHY_RUN {
+            delay = MIN_RESTART_DELAY;
+        }
+
+        warn!(
+            "Flow {} stopped: {}. Restarting in {}s",
+            flow.name(),
+            e,
+            delay.as_secs()
+        );
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_RESTART_DELAY);
+    }
+}
+
+#[derive(Default)]
+pub struct FlowRegistry {
+    running: HashMap<PathBuf, JoinHandle<()>>,
+}
+
+impl FlowRegistry {
+    pub fn new() -> Self {
+        Self {
+            running: HashMap::new(),
+        }
+    }
+
+    pub fn start(
+        &mut self,
+        flow: Flow,
+        project: &Project,
+        route_table: Arc<RwLock<HashMap<PathBuf, Arc<RouteMeta>>>>,
+        streaming_backend: Arc<dyn StreamingBackend>,
+    ) {
+        //! Starts the flow, replacing the running instance of the same flow if there is one.
+        self.stop(&flow.file_path);
+
+        let file_path = flow.file_path.clone();
+        let project = project.clone();
+
+        let handle = tokio::spawn(supervise_flow(
+            flow,
+            project,
+            route_table,
+            streaming_backend,
+        ));
+
+        self.running.insert(file_path, handle);
+    }
+
+    pub fn stop(&mut self, file_path: &Path) {
+        //! Stops the flow. Aborting the task drops the node process, which kills it.
+        if let Some(handle) = self.running.remove(file_path) {
+            info!("Stopping flow: {:?}", file_path);
+            handle.abort();
+        }
+    }
+
+    pub fn stop_all(&mut self) {
+        for (file_path, handle) in self.running.drain() {
+            info!("Stopping flow: {:?}", file_path);
+            handle.abort();
+        }
+    }
+
+    pub fn reload(
+        &mut self,
+        file_path: &Path,
+        project: &Project,
+        route_table: Arc<RwLock<HashMap<PathBuf, Arc<RouteMeta>>>>,
+        streaming_backend: Arc<dyn StreamingBackend>,
+    ) {
+        //! Restarts the flow defined in the file, or stops it if the file doesn't exist anymore.
+        match Flow::from_file_path(&project.flows_dir(), file_path) {
+            Some(flow) if file_path.exists() => {
+                self.start(flow, project, route_table, streaming_backend)
+            }
+            _ => self.stop(file_path),
+        }
+    }
+}
+
+pub fn start_all_flows(
+    project: &Project,
+    registry: &mut FlowRegistry,
+    route_table: Arc<RwLock<HashMap<PathBuf, Arc<RouteMeta>>>>,
+    streaming_backend: Arc<dyn StreamingBackend>,
+) -> Result<(), Error> {
+    for flow in get_all_flows(&project.flows_dir())? {
+        registry.start(
+            flow,
+            project,
+            Arc::clone(&route_table),
+            Arc::clone(&streaming_backend),
+        );
+    }
+    Ok(())
+}
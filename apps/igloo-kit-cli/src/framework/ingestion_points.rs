@@ -0,0 +1,425 @@
+//! # Ingestion Points
+//! Ingestion points bind a path of the webserver to a data model, for clients that can't send
+//! records shaped like the model to its `ingest/<Model>` route, such as webhooks. Each one is a
+//! TOML file in `app/ingestion_points`:
+//!
+//! ```toml
+//! # app/ingestion_points/signup.toml
+//! path = "webhooks/signup"
+//! model = "UserActivity"
+//! # Only POST by default, PUT and PATCH can be taken as well
+//! methods = ["POST", "PUT"]
+//! # "api_key" follows the API keys of the project, "none" makes the route public
+//! auth = "none"
+//! # Overrides the delivery mode of the model, "wait_for_ack" or "fire_and_forget"
+//! delivery = "fire_and_forget"
+//!
+//! # Model fields read from other fields of the request, dots reach into nested objects
+//! [fields]
+//! userId = "user.id"
+//! eventType = "type"
+//! ```
+//!
+//! Once mapped, records sent to an ingestion point are handled like the ones sent to the route of
+//! their model: they're validated, enriched and deduplicated the same way and produced to the same
+//! topic. The watcher reloads every ingestion point when one of their files or a data model changes.
+//!
+//! ## Suggested Improvements
+//! - support constant fields and simple conversions in the mapping
+//! - generate SDK functions for ingestion points
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{Error, ErrorKind},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use log::debug;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use crate::{infrastructure::ingest::DeliveryMode, project::Project};
+
+use super::controller::RouteMeta;
+
+// Methods that carry a body, and so records
+const INGEST_METHODS: [&str; 3] = ["POST", "PUT", "PATCH"];
+
+// First segments of the paths the webserver serves itself
+const RESERVED_PATHS: [&str; 6] = [
+    "console",
+    "openapi.json",
+    "metrics",
+    "health",
+    "ready",
+    "events",
+];
+
+// Routes of the valid ingestion points, and the error of each file that isn't
+type LoadedRoutes = (Vec<(PathBuf, Arc<RouteMeta>)>, Vec<(PathBuf, Error)>);
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum IngestionPointAuth {
+    #[default]
+    ApiKey,
+    None,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct IngestionPointFile {
+    path: String,
+    model: String,
+    #[serde(default = "default_methods")]
+    methods: Vec<String>,
+    #[serde(default)]
+    auth: IngestionPointAuth,
+    #[serde(default)]
+    fields: BTreeMap<String, String>,
+    #[serde(default)]
+    delivery: Option<DeliveryMode>,
+}
+
+fn default_methods() -> Vec<String> {
+    vec!["POST".to_string()]
+}
+
+#[derive(Debug, Clone)]
+pub struct IngestionPoint {
+    pub methods: Vec<String>,
+    pub auth: IngestionPointAuth,
+    pub delivery: Option<DeliveryMode>,
+    // Model fields with the path of the request field they're read from
+    fields: Vec<(String, Vec<String>)>,
+}
+
+fn take_field(object: &mut Map<String, Value>, path: &[String]) -> Option<Value> {
+    match path {
+        [] => None,
+        [field] => object.remove(field),
+        [parent, rest @ ..] => {
+            let nested = object.get_mut(parent)?.as_object_mut()?;
+            let value = take_field(nested, rest);
+            // Objects emptied by the mapping aren't sent on
+            if nested.is_empty() {
+                object.remove(parent);
+            }
+            value
+        }
+    }
+}
+
+impl IngestionPoint {
+    pub fn maps_fields(&self) -> bool {
+        !self.fields.is_empty()
+    }
+
+    pub fn map_record(&self, record: &mut Value) {
+        //! Moves the mapped fields of a record to the model fields they're read into. Fields that
+        //! aren't mapped are kept as sent.
+        let object = match record {
+            Value::Object(object) => object,
+            _ => return,
+        };
+
+        // Every field is taken before any is written, so that fields can swap names
+        let mapped = self
+            .fields
+            .iter()
+            .filter_map(|(field, source)| Some((field.clone(), take_field(object, source)?)))
+            .collect::<Vec<_>>();
+        object.extend(mapped);
+    }
+}
+
+fn parse_file(file_path: &Path) -> Result<(PathBuf, String, IngestionPoint), Error> {
+    let invalid = |message: String| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Invalid ingestion point {}: {}",
+                file_path.display(),
+                message
+            ),
+        )
+    };
+
+    let contents = std::fs::read_to_string(file_path)?;
+    let file: IngestionPointFile = toml::from_str(&contents).map_err(|e| invalid(e.to_string()))?;
+
+    let path = file.path.trim_matches('/');
+    if path.is_empty() || path.split('/').any(|segment| segment.is_empty()) {
+        return Err(invalid(format!("{:?} is not a valid path", file.path)));
+    }
+    if RESERVED_PATHS
+        .iter()
+        .any(|reserved| path.split('/').next() == Some(*reserved))
+    {
+        return Err(invalid(format!(
+            "/{} is served by the webserver itself",
+            path
+        )));
+    }
+
+    let methods = file
+        .methods
+        .iter()
+        .map(|method| method.to_uppercase())
+        .collect::<Vec<String>>();
+    if methods.is_empty() {
+        return Err(invalid("at least one method is required".to_string()));
+    }
+    if let Some(method) = methods
+        .iter()
+        .find(|method| !INGEST_METHODS.contains(&method.as_str()))
+    {
+        return Err(invalid(format!(
+            "{} is not supported, use {}",
+            method,
+            INGEST_METHODS.join(", ")
+        )));
+    }
+
+    let fields = file
+        .fields
+        .into_iter()
+        .map(|(field, source)| (field, source.split('.').map(str::to_string).collect()))
+        .collect();
+
+    Ok((
+        PathBuf::from(path),
+        file.model,
+        IngestionPoint {
+            methods,
+            auth: file.auth,
+            delivery: file.delivery,
+            fields,
+        },
+    ))
+}
+
+fn ingestion_point_files(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut files = vec![];
+    if dir.is_dir() {
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                files.extend(ingestion_point_files(&path)?);
+            } else if path.extension().map_or(false, |ext| ext == "toml") {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+pub fn load_ingestion_point_routes(
+    project: &Project,
+    routes: &HashMap<PathBuf, Arc<RouteMeta>>,
+) -> LoadedRoutes {
+    //! Binds every ingestion point of the project to the route of its data model in `routes`.
+    //! Returns the routes of the valid ingestion points, and the error of each file that isn't.
+    let files = match ingestion_point_files(&project.ingestion_points_dir()) {
+        Ok(files) => files,
+        Err(e) => return (vec![], vec![(project.ingestion_points_dir(), e)]),
+    };
+
+    let mut loaded: Vec<(PathBuf, Arc<RouteMeta>)> = vec![];
+    let mut errors = vec![];
+    for file_path in files {
+        debug!("Loading ingestion point: {:?}", file_path);
+        let route = parse_file(&file_path).and_then(|(path, model, ingestion_point)| {
+            let conflict = |owner: String| {
+                Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!(
+                        "Invalid ingestion point {}: /{} is already served by {}",
+                        file_path.display(),
+                        path.display(),
+                        owner
+                    ),
+                )
+            };
+            if let Some(route_meta) = routes
+                .get(&path)
+                .filter(|meta| meta.ingestion_point.is_none())
+            {
+                return Err(conflict(format!(
+                    "the {} data model",
+                    route_meta.table_name
+                )));
+            }
+            if let Some((_, route_meta)) = loaded.iter().find(|(route, _)| *route == path) {
+                return Err(conflict(
+                    route_meta.original_file_path.display().to_string(),
+                ));
+            }
+
+            // The ingestion point takes everything but its path, methods and mapping from its model
+            let model_route = routes
+                .values()
+                .find(|meta| meta.ingestion_point.is_none() && meta.data_model.name == model)
+                .ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::NotFound,
+                        format!(
+                            "Invalid ingestion point {}: there is no {} data model",
+                            file_path.display(),
+                            model
+                        ),
+                    )
+                })?;
+            let mut route_meta = RouteMeta::clone(model_route);
+            route_meta.original_file_path = file_path.clone();
+            route_meta.delivery = ingestion_point.delivery.unwrap_or(model_route.delivery);
+            route_meta.ingestion_point = Some(ingestion_point);
+            Ok((path, Arc::new(route_meta)))
+        });
+
+        match route {
+            Ok(route) => loaded.push(route),
+            Err(e) => errors.push((file_path, e)),
+        }
+    }
+    (loaded, errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_fs::TempDir;
+    use serde_json::json;
+
+    use super::*;
+
+    fn parse(contents: &str) -> Result<(PathBuf, String, IngestionPoint), Error> {
+        let dir = TempDir::new().unwrap();
+        let file_path = dir.path().join("point.toml");
+        std::fs::write(&file_path, contents).unwrap();
+        parse_file(&file_path)
+    }
+
+    fn ingestion_point(fields: &[(&str, &str)]) -> IngestionPoint {
+        IngestionPoint {
+            methods: default_methods(),
+            auth: IngestionPointAuth::default(),
+            delivery: None,
+            fields: fields
+                .iter()
+                .map(|(field, source)| {
+                    (
+                        field.to_string(),
+                        source.split('.').map(str::to_string).collect(),
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn parses_a_file_with_defaults() {
+        let (path, model, point) =
+            parse("path = \"/webhooks/signup/\"\nmodel = \"UserActivity\"").unwrap();
+
+        assert_eq!(path, PathBuf::from("webhooks/signup"));
+        assert_eq!(model, "UserActivity");
+        assert_eq!(point.methods, vec!["POST".to_string()]);
+        assert_eq!(point.auth, IngestionPointAuth::ApiKey);
+        assert_eq!(point.delivery, None);
+        assert!(!point.maps_fields());
+    }
+
+    #[test]
+    fn parses_every_option() {
+        let (_, _, point) = parse(
+            r#"
+            path = "webhooks/signup"
+            model = "UserActivity"
+            methods = ["post", "Patch"]
+            auth = "none"
+            delivery = "fire_and_forget"
+
+            [fields]
+            userId = "user.id"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(point.methods, vec!["POST".to_string(), "PATCH".to_string()]);
+        assert_eq!(point.auth, IngestionPointAuth::None);
+        assert_eq!(point.delivery, Some(DeliveryMode::FireAndForget));
+        assert_eq!(
+            point.fields,
+            vec![(
+                "userId".to_string(),
+                vec!["user".to_string(), "id".to_string()]
+            )]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_files() {
+        for contents in [
+            // Paths
+            "path = \"/\"\nmodel = \"M\"",
+            "path = \"webhooks//signup\"\nmodel = \"M\"",
+            "path = \"health/signup\"\nmodel = \"M\"",
+            // Methods
+            "path = \"hook\"\nmodel = \"M\"\nmethods = []",
+            "path = \"hook\"\nmodel = \"M\"\nmethods = [\"GET\"]",
+            // Unknown and missing keys
+            "path = \"hook\"\nmodel = \"M\"\nmodle = \"M\"",
+            "path = \"hook\"",
+            "path = \"hook\"\nmodel = \"M\"\nauth = \"basic\"",
+        ] {
+            let e = parse(contents).unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::InvalidData, "{}", contents);
+            assert!(
+                e.to_string().starts_with("Invalid ingestion point"),
+                "{}",
+                e
+            );
+        }
+    }
+
+    #[test]
+    fn maps_nested_fields_and_keeps_the_others() {
+        let point = ingestion_point(&[("userId", "user.id"), ("eventType", "type")]);
+        let mut record = json!({
+            "user": {"id": 7},
+            "type": "signup",
+            "source": "web",
+        });
+        point.map_record(&mut record);
+
+        // The emptied `user` object is dropped
+        assert_eq!(
+            record,
+            json!({"userId": 7, "eventType": "signup", "source": "web"})
+        );
+    }
+
+    #[test]
+    fn maps_fields_that_swap_names() {
+        let point = ingestion_point(&[("a", "b"), ("b", "a"), ("c", "user.name")]);
+        let mut record = json!({"a": 1, "b": 2, "user": {"name": "x", "id": 3}});
+        point.map_record(&mut record);
+
+        assert_eq!(record, json!({"a": 2, "b": 1, "c": "x", "user": {"id": 3}}));
+    }
+
+    #[test]
+    fn leaves_missing_fields_and_non_objects_alone() {
+        let point = ingestion_point(&[("userId", "user.id")]);
+
+        let mut record = json!({"user": "not an object"});
+        point.map_record(&mut record);
+        assert_eq!(record, json!({"user": "not an object"}));
+
+        let mut record = json!([1, 2]);
+        point.map_record(&mut record);
+        assert_eq!(record, json!([1, 2]));
+    }
+}
@@ -48,6 +48,13 @@ impl TypescriptPackage {
     }
 }
 
+pub fn sdk_dir(project: &Project) -> Result<PathBuf, std::io::Error> {
+    //! The directory the Typescript SDK of the project is generated in.
+    Ok(project
+        .internal_dir()?
+        .join(TypescriptPackage::from_project(project).name))
+}
+
 fn write_config_to_file(path: PathBuf, code: String) -> Result<(), std::io::Error> {
     let mut file = File::create(path)?;
     file.write_all(code.as_bytes())
@@ -67,15 +74,13 @@ pub fn generate_ts_sdk(
     //! # Returns
     //! - `Result<PathBuf, std::io::Error>` - A result containing the path where the SDK was generated.
     //!
-    let igloo_dir = project.internal_dir()?;
-
     let package = TypescriptPackage::from_project(project);
     let package_json_code = PackageJsonTemplate::build(&package);
     let ts_config_code = TsConfigTemplate::build();
     let index_code = IndexTemplate::build(&ts_objects);
 
     // This needs to write to the root of the NPM folder... creating in the current project location for now
-    let sdk_dir = igloo_dir.join(package.name);
+    let sdk_dir = sdk_dir(project)?;
     std::fs::create_dir_all(sdk_dir.clone())?;
 
     write_config_to_file(sdk_dir.join("package.json"), package_json_code)?;
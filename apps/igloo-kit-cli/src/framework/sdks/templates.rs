@@ -19,6 +19,7 @@ pub static PACKAGE_JSON_TEMPLATE: &str = r#"
     "license": "ISC",
     "devDependencies": \{
         "@types/node": "^18.*.*",
+        "tsx": "4.7.0",
         "typescript": "^5.*.*"
     },
     "dependencies": \{
@@ -1,7 +1,9 @@
 use serde::Serialize;
 use tinytemplate::TinyTemplate;
 
+use crate::cli::auth::API_KEY_ENV_VAR;
 use crate::framework::sdks::TypescriptObjects;
+use crate::infrastructure::ingest::metadata::SDK_VERSION_HEADER;
 
 use super::{InterfaceField, TypescriptInterface};
 
@@ -64,17 +66,36 @@ impl InterfaceTemplate {
     }
 }
 
+// Small payloads aren't worth the time it takes to compress them
+const COMPRESSION_THRESHOLD_BYTES: usize = 1024;
+
 pub static SEND_FUNC_TEMPLATE: &str = r#"
 import \{ {interface_context.name} } from './{interface_context.file_name}';
 
-export async function {declaration_name}({interface_context.var_name}: {interface_context.name}) \{
+// With `compress: true`, bodies larger than this are gzipped when the runtime supports it
+const COMPRESSION_THRESHOLD_BYTES = {compression_threshold_bytes};
+
+export async function {declaration_name}({interface_context.var_name}: {interface_context.name}, options: \{ compress?: boolean } = \{}) \{
+    const headers: Record<string, string> = \{
+        'Content-Type': 'application/json',
+        '{sdk_version_header}': '{sdk_version}'
+    };
+    // Read at runtime so that the key never ends up in the generated code
+    const apiKey = typeof process !== 'undefined' ? process.env.{api_key_env_var} : undefined;
+    if (apiKey) \{
+        headers['Authorization'] = 'Bearer ' + apiKey;
+    }
+    let body: any = JSON.stringify({interface_context.var_name});
+    const CompressionStreamImpl = (globalThis as any).CompressionStream;
+    if (options.compress === true && CompressionStreamImpl && body.length > COMPRESSION_THRESHOLD_BYTES) \{
+        const compressed = new Blob([body]).stream().pipeThrough(new CompressionStreamImpl('gzip'));
+        body = await new Response(compressed).arrayBuffer();
+        headers['Content-Encoding'] = 'gzip';
+    }
     return fetch('{server_url}/{api_route_name}', \{
         method: 'POST',
-        mode: 'no-cors',
-        headers: \{
-            'Content-Type': 'application/json'
-        },
-        body: JSON.stringify({interface_context.var_name})
+        headers,
+        body
     })
 }
 "#;
@@ -86,6 +107,10 @@ pub struct SendFunctionContext {
     file_name: String,
     server_url: String,
     api_route_name: String,
+    api_key_env_var: &'static str,
+    sdk_version_header: &'static str,
+    sdk_version: &'static str,
+    compression_threshold_bytes: usize,
 }
 
 impl SendFunctionContext {
@@ -100,6 +125,10 @@ impl SendFunctionContext {
             file_name: interface.send_function_file_name(),
             server_url,
             api_route_name,
+            api_key_env_var: API_KEY_ENV_VAR,
+            sdk_version_header: SDK_VERSION_HEADER,
+            sdk_version: env!("CARGO_PKG_VERSION"),
+            compression_threshold_bytes: COMPRESSION_THRESHOLD_BYTES,
         }
     }
 }
@@ -1 +1,552 @@
+//! # Ingest
+//! Module to handle how validated records are put on the wire before they are produced to a topic.
+//!
+//! Every data model defaults to JSON, which ClickHouse reads with `JSONEachRow`. A project can pick a
+//! more compact wire format per model in its `project.toml`:
+//!
+//! ```toml
+//! [ingest_config]
+//! default_format = "json"
+//!
+//! [ingest_config.formats]
+//! userevent = "avro"
+//! pageview = "protobuf"
+//! ```
+//!
+//! Model names are matched case-insensitively since the config loader lowercases keys.
+//!
+//! DateTimes are RFC 3339 strings in JSON, `timestamp-millis` longs in Avro and int64 milliseconds
+//! in Protobuf. The tables of Avro and Protobuf models read them into `DateTime64(3)` columns, while
+//! JSON models keep `DateTime` columns, which drop the milliseconds. Records can send them as
+//! milliseconds since the epoch, which the JSON format rewrites to RFC 3339.
+//!
+//! Routes answer once the stream acknowledged their records by default. Models whose clients don't
+//! need to know can be sent fire-and-forget, where the route answers with a 202 as soon as the
+//! records are valid and produces them in the background:
+//!
+//! ```toml
+//! [ingest_config]
+//! default_delivery = "wait_for_ack"
+//!
+//! [ingest_config.delivery]
+//! pageview = "fire_and_forget"
+//! ```
+//!
+//! The schema of the wire format is built once per route, as the `RecordCodec` of its data model.
+//!
+//! ## Suggested Improvements
+//! - use a schema registry instead of shipping the avro schema with every message
 
+pub mod adapters;
+pub mod avro;
+pub mod dedup;
+pub mod metadata;
+pub mod protobuf;
+pub mod validation;
+
+use std::{collections::HashMap, fmt, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::framework::schema::{ColumnType, Table, UnsupportedDataTypeError};
+
+use self::{
+    avro::AvroSchema, dedup::DeduplicationConfig, metadata::MetadataColumn, protobuf::ProtoSchema,
+    validation::FieldError,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum WireFormat {
+    #[default]
+    Json,
+    Avro,
+    Protobuf,
+}
+
+impl fmt::Display for WireFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WireFormat::Json => write!(f, "json"),
+            WireFormat::Avro => write!(f, "avro"),
+            WireFormat::Protobuf => write!(f, "protobuf"),
+        }
+    }
+}
+
+// Encodes the records of a data model into its wire format, with the schema built for the route
+#[derive(Debug, Clone, Default)]
+pub enum RecordCodec {
+    #[default]
+    Json,
+    Avro(Arc<AvroSchema>),
+    Protobuf(Arc<ProtoSchema>),
+}
+
+impl RecordCodec {
+    pub fn new(
+        format: WireFormat,
+        data_model: &Table,
+        proto_definition: Option<&str>,
+    ) -> Result<RecordCodec, EncodingError> {
+        //! `proto_definition` is the protobuf definition last generated for the data model, whose
+        //! field numbers are kept.
+        match format {
+            WireFormat::Json => Ok(RecordCodec::Json),
+            WireFormat::Avro => Ok(RecordCodec::Avro(Arc::new(AvroSchema::new(data_model)?))),
+            WireFormat::Protobuf => Ok(RecordCodec::Protobuf(Arc::new(ProtoSchema::new(
+                data_model,
+                proto_definition,
+            )?))),
+        }
+    }
+
+    pub fn wire_format(&self) -> WireFormat {
+        match self {
+            RecordCodec::Json => WireFormat::Json,
+            RecordCodec::Avro(_) => WireFormat::Avro,
+            RecordCodec::Protobuf(_) => WireFormat::Protobuf,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryMode {
+    #[default]
+    WaitForAck,
+    FireAndForget,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct IngestConfig {
+    #[serde(default)]
+    pub default_format: WireFormat,
+    #[serde(default)]
+    pub formats: HashMap<String, WireFormat>,
+    // Added to every data model and filled in by the ingest routes
+    #[serde(default)]
+    pub metadata_columns: Vec<MetadataColumn>,
+    #[serde(default)]
+    pub deduplication: DeduplicationConfig,
+    #[serde(default)]
+    pub default_delivery: DeliveryMode,
+    #[serde(default)]
+    pub delivery: HashMap<String, DeliveryMode>,
+}
+
+impl IngestConfig {
+    pub fn wire_format(&self, model_name: &str) -> WireFormat {
+        self.formats
+            .get(&model_name.to_lowercase())
+            .copied()
+            .unwrap_or(self.default_format)
+    }
+
+    pub fn delivery_mode(&self, model_name: &str) -> DeliveryMode {
+        self.delivery
+            .get(&model_name.to_lowercase())
+            .copied()
+            .unwrap_or(self.default_delivery)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum EncodingError {
+    MalformedPayload {
+        reason: String,
+    },
+    MalformedCsv {
+        reason: String,
+    },
+    InvalidRecord(Vec<FieldError>),
+    MissingField {
+        field_name: String,
+    },
+    InvalidValue {
+        field_name: String,
+        expected: String,
+    },
+    UnsupportedDataType(UnsupportedDataTypeError),
+    Serialization {
+        reason: String,
+    },
+}
+
+impl fmt::Display for EncodingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EncodingError::MalformedPayload { reason } => {
+                write!(f, "The payload is not valid JSON: {}", reason)
+            }
+            EncodingError::MalformedCsv { reason } => {
+                write!(f, "The payload is not valid CSV: {}", reason)
+            }
+            EncodingError::InvalidRecord(errors) => {
+                let errors = errors
+                    .iter()
+                    .map(|error| match &error.field {
+                        Some(field) => format!("{} {}", field, error.message),
+                        None => error.message.clone(),
+                    })
+                    .collect::<Vec<String>>();
+                write!(f, "The record is invalid: {}", errors.join(", "))
+            }
+            EncodingError::MissingField { field_name } => {
+                write!(f, "The field {} is required", field_name)
+            }
+            EncodingError::InvalidValue {
+                field_name,
+                expected,
+            } => write!(f, "The field {} should be of type {}", field_name, expected),
+            EncodingError::UnsupportedDataType(e) => {
+                write!(f, "The following type is unsupported: {}", e.type_name)
+            }
+            EncodingError::Serialization { reason } => {
+                write!(f, "Failed to serialize the record: {}", reason)
+            }
+        }
+    }
+}
+
+impl EncodingError {
+    pub fn field_errors(&self) -> Vec<FieldError> {
+        //! The errors to report back to the client, one per field when the error is about fields.
+        match self {
+            EncodingError::InvalidRecord(errors) => errors.clone(),
+            EncodingError::MissingField { field_name } => {
+                vec![FieldError::new(field_name, "is required".to_string())]
+            }
+            EncodingError::InvalidValue {
+                field_name,
+                expected,
+            } => vec![FieldError::new(
+                field_name,
+                format!("expected a value of type {}", expected),
+            )],
+            _ => vec![FieldError::record(self.to_string())],
+        }
+    }
+}
+
+impl From<UnsupportedDataTypeError> for EncodingError {
+    fn from(e: UnsupportedDataTypeError) -> Self {
+        EncodingError::UnsupportedDataType(e)
+    }
+}
+
+pub fn encode_payload(
+    codec: &RecordCodec,
+    data_model: &Table,
+    payload: &[u8],
+) -> Result<Vec<u8>, EncodingError> {
+    //! Validates a raw JSON payload and encodes it into the wire format configured for the data model.
+    //!
+    //! Valid JSON payloads are forwarded untouched since ClickHouse reads them as is, unless they
+    //! carry DateTimes as milliseconds.
+    let record = parse_record(payload, None)?;
+
+    match codec {
+        RecordCodec::Json if !has_millis_datetimes(data_model, &record) => {
+            validation::validate_record(data_model, &record)
+                .map_err(EncodingError::InvalidRecord)?;
+            Ok(payload.to_vec())
+        }
+        _ => encode_record(codec, data_model, &record),
+    }
+}
+
+fn datetime_columns(data_model: &Table) -> impl Iterator<Item = &str> {
+    data_model
+        .columns
+        .iter()
+        .filter(|column| matches!(column.data_type, ColumnType::DateTime))
+        .map(|column| column.name.as_str())
+}
+
+fn has_millis_datetimes(data_model: &Table, record: &Value) -> bool {
+    datetime_columns(data_model).any(|name| match record.get(name) {
+        Some(Value::Number(_)) => true,
+        Some(Value::Array(items)) => items.iter().any(Value::is_number),
+        _ => false,
+    })
+}
+
+fn millis_datetimes_to_rfc3339(data_model: &Table, record: &mut Value) {
+    //! Rewrites the DateTimes sent as milliseconds to RFC 3339 strings, since ClickHouse would read
+    //! integers as seconds.
+    let millis_to_rfc3339 = |value: &mut Value| {
+        if let Some(datetime) = value.as_i64().and_then(millis_to_datetime) {
+            *value = datetime;
+        }
+    };
+
+    for name in datetime_columns(data_model) {
+        match record.get_mut(name) {
+            Some(Value::Array(items)) => items.iter_mut().for_each(millis_to_rfc3339),
+            Some(value) => millis_to_rfc3339(value),
+            None => {}
+        }
+    }
+}
+
+pub const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+fn mime_type(content_type: Option<&str>) -> Option<&str> {
+    content_type
+        .and_then(|content_type| content_type.split(';').next())
+        .map(str::trim)
+}
+
+pub fn is_text_encoded(content_type: Option<&str>) -> bool {
+    //! Whether the body is CSV or form encoded, so that its records have to be coerced with
+    //! `adapters::coerce_record` once they're in the shape of their data model.
+    matches!(
+        mime_type(content_type),
+        Some(adapters::CSV_CONTENT_TYPE | adapters::FORM_CONTENT_TYPE)
+    )
+}
+
+pub fn parse_record(payload: &[u8], content_type: Option<&str>) -> Result<Value, EncodingError> {
+    if mime_type(content_type) == Some(adapters::FORM_CONTENT_TYPE) {
+        return Ok(adapters::parse_form(payload));
+    }
+    serde_json::from_slice(payload).map_err(|e| EncodingError::MalformedPayload {
+        reason: e.to_string(),
+    })
+}
+
+pub fn parse_batch(
+    payload: &[u8],
+    content_type: Option<&str>,
+) -> Result<Option<Vec<Result<Value, EncodingError>>>, EncodingError> {
+    //! Splits a batch body into its records, or returns `None` when the body is a single record.
+    //!
+    //! A batch is either a JSON array, newline delimited JSON sent as `application/x-ndjson` or
+    //! CSV sent as `text/csv`. Every NDJSON line and CSV row is parsed on its own so that a bad
+    //! line only fails its own record.
+    match mime_type(content_type) {
+        Some(adapters::CSV_CONTENT_TYPE) => return adapters::parse_csv(payload).map(Some),
+        // Form bodies are always a single record
+        Some(adapters::FORM_CONTENT_TYPE) => return Ok(None),
+        Some(NDJSON_CONTENT_TYPE) => {
+            let records = payload
+                .split(|byte| *byte == b'\n')
+                .filter(|line| !line.iter().all(u8::is_ascii_whitespace))
+                .map(|line| {
+                    serde_json::from_slice(line).map_err(|e| EncodingError::MalformedPayload {
+                        reason: e.to_string(),
+                    })
+                })
+                .collect();
+            return Ok(Some(records));
+        }
+        _ => {}
+    }
+
+    match payload.iter().find(|byte| !byte.is_ascii_whitespace()) {
+        Some(b'[') => {
+            let records: Vec<Value> =
+                serde_json::from_slice(payload).map_err(|e| EncodingError::MalformedPayload {
+                    reason: e.to_string(),
+                })?;
+            Ok(Some(records.into_iter().map(Ok).collect()))
+        }
+        _ => Ok(None),
+    }
+}
+
+pub fn encode_record(
+    codec: &RecordCodec,
+    data_model: &Table,
+    record: &Value,
+) -> Result<Vec<u8>, EncodingError> {
+    validation::validate_record(data_model, record).map_err(EncodingError::InvalidRecord)?;
+
+    match codec {
+        RecordCodec::Json => {
+            let mut record = record.clone();
+            millis_datetimes_to_rfc3339(data_model, &mut record);
+            serde_json::to_vec(&record).map_err(|e| EncodingError::Serialization {
+                reason: e.to_string(),
+            })
+        }
+        RecordCodec::Avro(schema) => schema.encode(data_model, record),
+        RecordCodec::Protobuf(schema) => schema.encode(data_model, record),
+    }
+}
+
+pub fn decode_payload(
+    codec: &RecordCodec,
+    data_model: &Table,
+    payload: &[u8],
+) -> Result<Value, EncodingError> {
+    //! Decodes a record read from a topic back into JSON, the inverse of `encode_payload`.
+    match codec {
+        RecordCodec::Json => {
+            serde_json::from_slice(payload).map_err(|e| EncodingError::MalformedPayload {
+                reason: e.to_string(),
+            })
+        }
+        RecordCodec::Avro(_) => avro::decode(payload),
+        RecordCodec::Protobuf(schema) => schema.decode(data_model, payload),
+    }
+}
+
+pub(crate) fn record_fields(
+    record: &Value,
+) -> Result<&serde_json::Map<String, Value>, EncodingError> {
+    record
+        .as_object()
+        .ok_or_else(|| EncodingError::MalformedPayload {
+            reason: "expected a JSON object".to_string(),
+        })
+}
+
+pub(crate) fn datetime_to_millis(field_name: &str, value: &Value) -> Result<i64, EncodingError> {
+    //! Reads a datetime either as an RFC 3339 string or as milliseconds since the epoch.
+    let invalid = || EncodingError::InvalidValue {
+        field_name: field_name.to_string(),
+        expected: "DateTime".to_string(),
+    };
+
+    match value {
+        Value::Number(n) => n.as_i64().filter(|millis| *millis >= 0).ok_or_else(invalid),
+        Value::String(s) => {
+            let time = humantime::parse_rfc3339_weak(s).map_err(|_| invalid())?;
+            let since_epoch = time
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_err(|_| invalid())?;
+            Ok(since_epoch.as_millis() as i64)
+        }
+        _ => Err(invalid()),
+    }
+}
+
+pub(crate) fn millis_to_datetime(millis: i64) -> Option<Value> {
+    //! Writes milliseconds since the epoch as an RFC 3339 string. Times before the epoch or after
+    //! the year 9999 can't be written and are `None`.
+    use std::fmt::Write;
+
+    let millis = u64::try_from(millis).ok()?;
+    let time = std::time::UNIX_EPOCH.checked_add(std::time::Duration::from_millis(millis))?;
+    let mut datetime = String::new();
+    write!(datetime, "{}", humantime::format_rfc3339_millis(time)).ok()?;
+    Some(Value::String(datetime))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use schema_ast::ast::FieldArity;
+    use serde_json::json;
+
+    use crate::framework::schema::{Column, TableType};
+
+    use super::*;
+
+    pub(crate) fn table(name: &str, columns: &[(&str, ColumnType, FieldArity)]) -> Table {
+        Table {
+            db_name: "local".to_string(),
+            table_type: TableType::Table,
+            name: name.to_string(),
+            columns: columns
+                .iter()
+                .map(|(name, data_type, arity)| Column {
+                    name: name.to_string(),
+                    data_type: data_type.clone(),
+                    arity: *arity,
+                    unique: false,
+                    primary_key: false,
+                    default: None,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn keeps_datetime_milliseconds_in_every_format() {
+        let data_model = table(
+            "Event",
+            &[("at", ColumnType::DateTime, FieldArity::Required)],
+        );
+        let record = json!({"at": "2024-01-02T03:04:05.678Z"});
+
+        for format in [WireFormat::Json, WireFormat::Avro, WireFormat::Protobuf] {
+            let codec = RecordCodec::new(format, &data_model, None).unwrap();
+            let payload = encode_record(&codec, &data_model, &record).unwrap();
+            assert_eq!(
+                decode_payload(&codec, &data_model, &payload).unwrap(),
+                record,
+                "{}",
+                format
+            );
+        }
+    }
+
+    #[test]
+    fn rewrites_millisecond_datetimes_on_the_json_path() {
+        let data_model = table(
+            "Event",
+            &[
+                ("at", ColumnType::DateTime, FieldArity::Required),
+                ("seen", ColumnType::DateTime, FieldArity::List),
+            ],
+        );
+        let codec = RecordCodec::Json;
+
+        let payload = br#"{"at":1704164645678,"seen":["2024-01-02T03:04:05Z",0]}"#;
+        let encoded = encode_payload(&codec, &data_model, payload).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<Value>(&encoded).unwrap(),
+            json!({
+                "at": "2024-01-02T03:04:05.678Z",
+                "seen": ["2024-01-02T03:04:05Z", "1970-01-01T00:00:00.000Z"],
+            })
+        );
+
+        // Payloads without milliseconds are forwarded untouched
+        let payload = br#"{ "at": "2024-01-02T03:04:05Z", "seen": [] }"#;
+        assert_eq!(
+            encode_payload(&codec, &data_model, payload).unwrap(),
+            payload.to_vec()
+        );
+    }
+
+    #[test]
+    fn reads_datetimes_as_milliseconds() {
+        assert_eq!(
+            datetime_to_millis("at", &json!("2024-01-02T03:04:05.678Z")).unwrap(),
+            1_704_164_645_678
+        );
+        assert_eq!(
+            millis_to_datetime(1_704_164_645_678),
+            Some(json!("2024-01-02T03:04:05.678Z"))
+        );
+    }
+
+    #[test]
+    fn rejects_datetimes_before_the_epoch() {
+        assert_eq!(
+            millis_to_datetime(0),
+            Some(json!("1970-01-01T00:00:00.000Z"))
+        );
+        assert_eq!(millis_to_datetime(-1), None);
+        assert!(datetime_to_millis("at", &json!(-1)).is_err());
+        assert!(datetime_to_millis("at", &json!("1969-12-31T23:59:59.999Z")).is_err());
+
+        // Rather than being written as the epoch
+        let data_model = table(
+            "Event",
+            &[("at", ColumnType::DateTime, FieldArity::Required)],
+        );
+        for format in [WireFormat::Avro, WireFormat::Protobuf] {
+            let codec = RecordCodec::new(format, &data_model, None).unwrap();
+            assert!(
+                encode_record(&codec, &data_model, &json!({"at": -1})).is_err(),
+                "{}",
+                format
+            );
+        }
+    }
+}
@@ -0,0 +1,269 @@
+//! # Adapters
+//! Turns bodies that aren't JSON into the same JSON records the ingest routes take, for sources
+//! such as spreadsheets and legacy webhooks that can't send anything else.
+//!
+//! - `text/csv` bodies are a batch: the header row names the fields and every other row is a record
+//! - `application/x-www-form-urlencoded` bodies are a single record, where a field sent several
+//!   times becomes a list
+//!
+//! Every value of these bodies is a string, so records are coerced with the column types of their
+//! data model before they're validated. Values that don't read as their column type are left as
+//! strings for validation to report, and empty values are left out like missing fields.
+//!
+//! ## Suggested Improvements
+//! - accept other CSV delimiters with a `delimiter` parameter on the content type
+//! - support multipart form bodies
+
+use schema_ast::ast::FieldArity;
+use serde_json::{Map, Number, Value};
+
+use crate::framework::schema::{ColumnType, Table};
+
+use super::{millis_to_datetime, EncodingError};
+
+pub const CSV_CONTENT_TYPE: &str = "text/csv";
+pub const FORM_CONTENT_TYPE: &str = "application/x-www-form-urlencoded";
+
+fn malformed(e: csv::Error) -> EncodingError {
+    EncodingError::MalformedCsv {
+        reason: e.to_string(),
+    }
+}
+
+pub fn parse_csv(payload: &[u8]) -> Result<Vec<Result<Value, EncodingError>>, EncodingError> {
+    //! Reads every row of a CSV body into a record. A row that can't be read only fails its own
+    //! record, while a bad header row fails the whole body.
+    let mut reader = csv::Reader::from_reader(payload);
+    let headers = reader.headers().map_err(malformed)?.clone();
+
+    Ok(reader
+        .records()
+        .map(|row| {
+            let row = row.map_err(malformed)?;
+            Ok(Value::Object(
+                headers
+                    .iter()
+                    .zip(row.iter())
+                    .filter(|(_, value)| !value.is_empty())
+                    .map(|(field, value)| (field.to_string(), Value::String(value.to_string())))
+                    .collect(),
+            ))
+        })
+        .collect())
+}
+
+pub fn parse_form(payload: &[u8]) -> Value {
+    //! Reads a form encoded body into a record.
+    let mut record = Map::new();
+    for (field, value) in form_urlencoded::parse(payload) {
+        if value.is_empty() {
+            continue;
+        }
+        let value = Value::String(value.into_owned());
+        match record.get_mut(field.as_ref()) {
+            Some(Value::Array(values)) => values.push(value),
+            Some(previous) => *previous = Value::Array(vec![previous.take(), value]),
+            None => {
+                record.insert(field.into_owned(), value);
+            }
+        }
+    }
+    Value::Object(record)
+}
+
+fn coerce_value(data_type: &ColumnType, value: &str) -> Option<Value> {
+    match data_type {
+        ColumnType::Boolean => match value.to_lowercase().as_str() {
+            "true" | "1" | "yes" => Some(Value::Bool(true)),
+            "false" | "0" | "no" => Some(Value::Bool(false)),
+            _ => None,
+        },
+        ColumnType::Int | ColumnType::BigInt => value.parse::<i64>().ok().map(Value::from),
+        ColumnType::Float | ColumnType::Decimal => value
+            .parse::<f64>()
+            .ok()
+            .and_then(Number::from_f64)
+            .map(Value::Number),
+        // Milliseconds since the epoch are written as RFC 3339 like the other DateTimes, which
+        // ClickHouse reads without mistaking them for seconds. Other strings are validated as sent.
+        ColumnType::DateTime => value.parse::<i64>().ok().and_then(millis_to_datetime),
+        ColumnType::Json => serde_json::from_str::<Value>(value)
+            .ok()
+            .filter(Value::is_object),
+        ColumnType::String | ColumnType::Bytes | ColumnType::Unsupported => None,
+    }
+}
+
+fn coerce_item(data_type: &ColumnType, value: &mut Value) {
+    if let Value::String(text) = value {
+        if let Some(coerced) = coerce_value(data_type, text) {
+            *value = coerced;
+        }
+    }
+}
+
+pub fn coerce_record(data_model: &Table, record: &mut Value) {
+    //! Converts the string values of a record to the types of their columns.
+    let fields = match record {
+        Value::Object(fields) => fields,
+        _ => return,
+    };
+
+    for column in &data_model.columns {
+        let value = match fields.get_mut(&column.name) {
+            Some(value) => value,
+            None => continue,
+        };
+
+        if column.arity != FieldArity::List {
+            coerce_item(&column.data_type, value);
+            continue;
+        }
+
+        // A list is either repeated in a form or written as a JSON array in a CSV cell, and a
+        // single value is a list of one
+        if let Some(text) = value.as_str() {
+            *value = match serde_json::from_str(text) {
+                Ok(Value::Array(items)) => Value::Array(items),
+                _ => Value::Array(vec![Value::String(text.to_string())]),
+            };
+        }
+        if let Value::Array(items) = value {
+            for item in items {
+                coerce_item(&column.data_type, item);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::infrastructure::ingest::tests::table;
+
+    use super::*;
+
+    #[test]
+    fn maps_csv_rows_to_the_header() {
+        let records = parse_csv(b"id,name,note\n1,Ada,\n2,Grace,admiral\n").unwrap();
+
+        assert_eq!(records.len(), 2);
+        // Empty cells are left out like missing fields
+        assert_eq!(
+            records[0].as_ref().unwrap(),
+            &json!({"id": "1", "name": "Ada"})
+        );
+        assert_eq!(
+            records[1].as_ref().unwrap(),
+            &json!({"id": "2", "name": "Grace", "note": "admiral"})
+        );
+    }
+
+    #[test]
+    fn fails_only_the_bad_csv_row() {
+        let records = parse_csv(b"id,name\n1,Ada\n2,Grace,extra\n3,Edsger\n").unwrap();
+
+        assert_eq!(records.len(), 3);
+        assert_eq!(
+            records[0].as_ref().unwrap(),
+            &json!({"id": "1", "name": "Ada"})
+        );
+        assert!(matches!(
+            records[1],
+            Err(EncodingError::MalformedCsv { .. })
+        ));
+        assert_eq!(
+            records[2].as_ref().unwrap(),
+            &json!({"id": "3", "name": "Edsger"})
+        );
+    }
+
+    #[test]
+    fn fails_the_body_on_a_bad_header() {
+        assert!(matches!(
+            parse_csv(b"id,\xff\n1,2\n"),
+            Err(EncodingError::MalformedCsv { .. })
+        ));
+    }
+
+    #[test]
+    fn reads_form_fields_and_repeated_fields_as_lists() {
+        let record = parse_form(b"name=Ada+Lovelace&tag=a&tag=b&tag=c&empty=&note=%3F");
+
+        assert_eq!(
+            record,
+            json!({"name": "Ada Lovelace", "tag": ["a", "b", "c"], "note": "?"})
+        );
+    }
+
+    #[test]
+    fn coerces_values_to_their_column_types() {
+        let data_model = table(
+            "Users",
+            &[
+                ("active", ColumnType::Boolean, FieldArity::Required),
+                ("age", ColumnType::Int, FieldArity::Required),
+                ("score", ColumnType::Float, FieldArity::Required),
+                ("signedUpAt", ColumnType::DateTime, FieldArity::Required),
+                ("seenAt", ColumnType::DateTime, FieldArity::Required),
+                ("meta", ColumnType::Json, FieldArity::Required),
+                ("name", ColumnType::String, FieldArity::Required),
+                ("tags", ColumnType::Int, FieldArity::List),
+                ("ids", ColumnType::Int, FieldArity::List),
+            ],
+        );
+        let mut record = json!({
+            "active": "Yes",
+            "age": "42",
+            "score": "1.5",
+            "signedUpAt": "1700000000000",
+            "seenAt": "2024-01-01T00:00:00Z",
+            "meta": "{\"plan\": \"pro\"}",
+            "name": "007",
+            "tags": "[1, \"2\"]",
+            "ids": "3",
+        });
+        coerce_record(&data_model, &mut record);
+
+        assert_eq!(
+            record,
+            json!({
+                "active": true,
+                "age": 42,
+                "score": 1.5,
+                "signedUpAt": "2023-11-14T22:13:20.000Z",
+                "seenAt": "2024-01-01T00:00:00Z",
+                "meta": {"plan": "pro"},
+                "name": "007",
+                "tags": [1, 2],
+                "ids": [3],
+            })
+        );
+    }
+
+    #[test]
+    fn leaves_values_that_dont_read_as_their_type() {
+        let data_model = table(
+            "Users",
+            &[
+                ("active", ColumnType::Boolean, FieldArity::Required),
+                ("age", ColumnType::Int, FieldArity::Required),
+                ("signedUpAt", ColumnType::DateTime, FieldArity::Required),
+                ("meta", ColumnType::Json, FieldArity::Required),
+            ],
+        );
+        let mut record = json!({
+            "active": "maybe",
+            "age": "forty",
+            "signedUpAt": "-1",
+            "meta": "[1]",
+        });
+        coerce_record(&data_model, &mut record);
+
+        assert_eq!(
+            record,
+            json!({"active": "maybe", "age": "forty", "signedUpAt": "-1", "meta": "[1]"})
+        );
+    }
+}
@@ -0,0 +1,507 @@
+//! # Avro
+//! Encodes records as Avro object container files. ClickHouse reads every Kafka message with the
+//! `Avro` input format, which expects the writer schema in the header of each message.
+//!
+//! The header only depends on the data model, so `AvroSchema` builds it once per route and every
+//! record is written after it as a block of one. DateTimes are `timestamp-millis` longs.
+
+use schema_ast::ast::FieldArity;
+use serde_json::{json, Value};
+
+use crate::framework::schema::{Column, ColumnType, Table, UnsupportedDataTypeError};
+
+use super::{datetime_to_millis, millis_to_datetime, record_fields, EncodingError};
+
+const MAGIC: &[u8] = b"Obj\x01";
+const SYNC_MARKER_LENGTH: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AvroType {
+    String,
+    Boolean,
+    Int,
+    Long,
+    Float,
+    Double,
+    TimestampMillis,
+}
+
+#[derive(Debug, Clone)]
+struct AvroField {
+    name: String,
+    avro_type: AvroType,
+    arity: FieldArity,
+}
+
+#[derive(Debug, Clone)]
+pub struct AvroSchema {
+    fields: Vec<AvroField>,
+    // Written before every record, with the schema and the sync marker of the route
+    header: Vec<u8>,
+    sync_marker: [u8; SYNC_MARKER_LENGTH],
+}
+
+fn column_type_to_avro_type(data_type: &ColumnType) -> Result<AvroType, UnsupportedDataTypeError> {
+    match data_type {
+        ColumnType::String => Ok(AvroType::String),
+        ColumnType::Boolean => Ok(AvroType::Boolean),
+        ColumnType::Int | ColumnType::BigInt => Ok(AvroType::Long),
+        ColumnType::Float | ColumnType::Decimal => Ok(AvroType::Double),
+        ColumnType::DateTime => Ok(AvroType::TimestampMillis),
+        _ => Err(UnsupportedDataTypeError {
+            type_name: data_type.to_string(),
+        }),
+    }
+}
+
+fn avro_type_to_json(avro_type: AvroType) -> Value {
+    match avro_type {
+        AvroType::String => json!("string"),
+        AvroType::Boolean => json!("boolean"),
+        AvroType::Int => json!("int"),
+        AvroType::Long => json!("long"),
+        AvroType::Float => json!("float"),
+        AvroType::Double => json!("double"),
+        AvroType::TimestampMillis => json!({"type": "long", "logicalType": "timestamp-millis"}),
+    }
+}
+
+fn field_to_json(field: &AvroField) -> Value {
+    let avro_type = avro_type_to_json(field.avro_type);
+    let avro_type = match field.arity {
+        FieldArity::Required => avro_type,
+        FieldArity::Optional => json!(["null", avro_type]),
+        FieldArity::List => json!({"type": "array", "items": avro_type}),
+    };
+    json!({ "name": field.name, "type": avro_type })
+}
+
+fn write_long(value: i64, buf: &mut Vec<u8>) {
+    // Longs are zig-zag encoded so that small negative numbers stay short
+    let mut value = ((value << 1) ^ (value >> 63)) as u64;
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn write_bytes(bytes: &[u8], buf: &mut Vec<u8>) {
+    write_long(bytes.len() as i64, buf);
+    buf.extend_from_slice(bytes);
+}
+
+impl AvroSchema {
+    pub fn new(data_model: &Table) -> Result<AvroSchema, UnsupportedDataTypeError> {
+        //! Builds the record schema matching the columns of the data model, and the header every
+        //! record of the route is written with.
+        let fields = data_model
+            .columns
+            .iter()
+            .map(|column| {
+                Ok(AvroField {
+                    name: column.name.clone(),
+                    avro_type: column_type_to_avro_type(&column.data_type)?,
+                    arity: column.arity,
+                })
+            })
+            .collect::<Result<Vec<AvroField>, UnsupportedDataTypeError>>()?;
+
+        let schema = json!({
+            "type": "record",
+            "name": data_model.name,
+            "fields": fields.iter().map(field_to_json).collect::<Vec<Value>>(),
+        });
+        let sync_marker = *uuid::Uuid::new_v4().as_bytes();
+
+        let mut header = MAGIC.to_vec();
+        write_long(2, &mut header);
+        write_bytes(b"avro.schema", &mut header);
+        write_bytes(schema.to_string().as_bytes(), &mut header);
+        write_bytes(b"avro.codec", &mut header);
+        write_bytes(b"null", &mut header);
+        write_long(0, &mut header);
+        header.extend_from_slice(&sync_marker);
+
+        Ok(AvroSchema {
+            fields,
+            header,
+            sync_marker,
+        })
+    }
+
+    pub fn encode(&self, data_model: &Table, record: &Value) -> Result<Vec<u8>, EncodingError> {
+        let fields = record_fields(record)?;
+
+        let mut datum = Vec::new();
+        for (field, column) in self.fields.iter().zip(&data_model.columns) {
+            write_field(field, column, fields.get(&field.name), &mut datum)?;
+        }
+
+        let mut buf = self.header.clone();
+        write_long(1, &mut buf);
+        write_bytes(&datum, &mut buf);
+        buf.extend_from_slice(&self.sync_marker);
+        Ok(buf)
+    }
+}
+
+fn write_value(
+    field: &AvroField,
+    column: &Column,
+    value: &Value,
+    buf: &mut Vec<u8>,
+) -> Result<(), EncodingError> {
+    let invalid = || EncodingError::InvalidValue {
+        field_name: column.name.clone(),
+        expected: column.data_type.to_string(),
+    };
+
+    match field.avro_type {
+        AvroType::String => write_bytes(value.as_str().ok_or_else(invalid)?.as_bytes(), buf),
+        AvroType::Boolean => buf.push(value.as_bool().ok_or_else(invalid)? as u8),
+        AvroType::Int | AvroType::Long => write_long(value.as_i64().ok_or_else(invalid)?, buf),
+        AvroType::Float => {
+            buf.extend_from_slice(&(value.as_f64().ok_or_else(invalid)? as f32).to_le_bytes())
+        }
+        AvroType::Double => {
+            buf.extend_from_slice(&value.as_f64().ok_or_else(invalid)?.to_le_bytes())
+        }
+        AvroType::TimestampMillis => write_long(datetime_to_millis(&column.name, value)?, buf),
+    }
+    Ok(())
+}
+
+fn write_field(
+    field: &AvroField,
+    column: &Column,
+    value: Option<&Value>,
+    buf: &mut Vec<u8>,
+) -> Result<(), EncodingError> {
+    match (field.arity, value) {
+        // The index of the branch of the ["null", type] union comes first
+        (FieldArity::Optional, None | Some(Value::Null)) => write_long(0, buf),
+        (FieldArity::Optional, Some(value)) => {
+            write_long(1, buf);
+            write_value(field, column, value, buf)?;
+        }
+        (FieldArity::List, None | Some(Value::Null)) => write_long(0, buf),
+        // Arrays are written as a single block of items, followed by an empty block
+        (FieldArity::List, Some(Value::Array(items))) => {
+            if !items.is_empty() {
+                write_long(items.len() as i64, buf);
+                for item in items {
+                    write_value(field, column, item, buf)?;
+                }
+            }
+            write_long(0, buf);
+        }
+        (FieldArity::List, Some(_)) => {
+            return Err(EncodingError::InvalidValue {
+                field_name: column.name.clone(),
+                expected: format!("{}[]", column.data_type),
+            })
+        }
+        (FieldArity::Required, Some(value)) if !value.is_null() => {
+            write_value(field, column, value, buf)?
+        }
+        _ => {
+            return Err(EncodingError::MissingField {
+                field_name: column.name.clone(),
+            })
+        }
+    }
+    Ok(())
+}
+
+fn malformed(reason: &str) -> EncodingError {
+    EncodingError::MalformedPayload {
+        reason: format!("invalid avro payload, {}", reason),
+    }
+}
+
+fn read_long(buf: &[u8], pos: &mut usize) -> Result<i64, EncodingError> {
+    let mut value: u64 = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = *buf.get(*pos).ok_or_else(|| malformed("truncated long"))?;
+        *pos += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte < 0x80 {
+            return Ok((value >> 1) as i64 ^ -((value & 1) as i64));
+        }
+    }
+    Err(malformed("long is too long"))
+}
+
+fn read_slice<'a>(buf: &'a [u8], pos: &mut usize, len: usize) -> Result<&'a [u8], EncodingError> {
+    let end = pos
+        .checked_add(len)
+        .ok_or_else(|| malformed("value is too long"))?;
+    let bytes = buf
+        .get(*pos..end)
+        .ok_or_else(|| malformed("truncated value"))?;
+    *pos = end;
+    Ok(bytes)
+}
+
+fn read_bytes<'a>(buf: &'a [u8], pos: &mut usize) -> Result<&'a [u8], EncodingError> {
+    let len = usize::try_from(read_long(buf, pos)?).map_err(|_| malformed("negative length"))?;
+    read_slice(buf, pos, len)
+}
+
+fn read_block_count(buf: &[u8], pos: &mut usize) -> Result<usize, EncodingError> {
+    //! Blocks of maps and arrays may have a negative count, followed by their size in bytes.
+    let count = read_long(buf, pos)?;
+    if count < 0 {
+        read_long(buf, pos)?;
+    }
+    usize::try_from(count.unsigned_abs()).map_err(|_| malformed("block is too long"))
+}
+
+fn avro_type_from_json(value: &Value) -> Result<AvroType, EncodingError> {
+    match value {
+        Value::String(name) => match name.as_str() {
+            "string" => Ok(AvroType::String),
+            "boolean" => Ok(AvroType::Boolean),
+            "int" => Ok(AvroType::Int),
+            "long" => Ok(AvroType::Long),
+            "float" => Ok(AvroType::Float),
+            "double" => Ok(AvroType::Double),
+            _ => Err(malformed(&format!("unsupported type {}", name))),
+        },
+        Value::Object(object) if object.get("logicalType") == Some(&json!("timestamp-millis")) => {
+            Ok(AvroType::TimestampMillis)
+        }
+        Value::Object(object) => avro_type_from_json(object.get("type").unwrap_or(&Value::Null)),
+        _ => Err(malformed(&format!("unsupported type {}", value))),
+    }
+}
+
+fn field_from_json(value: &Value) -> Result<AvroField, EncodingError> {
+    //! Reads the fields of the writer schema, which are the ones `AvroSchema` writes.
+    let name = value["name"]
+        .as_str()
+        .ok_or_else(|| malformed("schema field without a name"))?
+        .to_string();
+    let (avro_type, arity) = match &value["type"] {
+        Value::Array(branches) => match branches.as_slice() {
+            [null, avro_type] if null == "null" => {
+                (avro_type_from_json(avro_type)?, FieldArity::Optional)
+            }
+            _ => return Err(malformed("unsupported union")),
+        },
+        Value::Object(object) if object.get("type") == Some(&json!("array")) => (
+            avro_type_from_json(object.get("items").unwrap_or(&Value::Null))?,
+            FieldArity::List,
+        ),
+        avro_type => (avro_type_from_json(avro_type)?, FieldArity::Required),
+    };
+    Ok(AvroField {
+        name,
+        avro_type,
+        arity,
+    })
+}
+
+fn read_value(avro_type: AvroType, buf: &[u8], pos: &mut usize) -> Result<Value, EncodingError> {
+    match avro_type {
+        AvroType::String => Ok(Value::String(
+            String::from_utf8_lossy(read_bytes(buf, pos)?).to_string(),
+        )),
+        AvroType::Boolean => Ok(Value::Bool(read_slice(buf, pos, 1)?[0] != 0)),
+        AvroType::Int | AvroType::Long => Ok(Value::from(read_long(buf, pos)?)),
+        AvroType::Float => {
+            let bytes: [u8; 4] = read_slice(buf, pos, 4)?.try_into().unwrap_or_default();
+            Ok(Value::from(f32::from_le_bytes(bytes) as f64))
+        }
+        AvroType::Double => {
+            let bytes: [u8; 8] = read_slice(buf, pos, 8)?.try_into().unwrap_or_default();
+            Ok(Value::from(f64::from_le_bytes(bytes)))
+        }
+        AvroType::TimestampMillis => millis_to_datetime(read_long(buf, pos)?)
+            .ok_or_else(|| malformed("timestamp out of range")),
+    }
+}
+
+fn read_field(field: &AvroField, buf: &[u8], pos: &mut usize) -> Result<Value, EncodingError> {
+    match field.arity {
+        FieldArity::Required => read_value(field.avro_type, buf, pos),
+        FieldArity::Optional => match read_long(buf, pos)? {
+            0 => Ok(Value::Null),
+            1 => read_value(field.avro_type, buf, pos),
+            _ => Err(malformed("unknown union branch")),
+        },
+        FieldArity::List => {
+            let mut items = vec![];
+            loop {
+                let count = read_block_count(buf, pos)?;
+                if count == 0 {
+                    return Ok(Value::Array(items));
+                }
+                for _ in 0..count {
+                    items.push(read_value(field.avro_type, buf, pos)?);
+                }
+            }
+        }
+    }
+}
+
+pub fn decode(payload: &[u8]) -> Result<Value, EncodingError> {
+    //! Decodes the first record of an object container file. The writer schema is read from the
+    //! header, so records written before the data model changed are read with their own schema.
+    let mut pos = 0;
+    if read_slice(payload, &mut pos, MAGIC.len())? != MAGIC {
+        return Err(malformed("not an object container file"));
+    }
+
+    let mut schema = None;
+    loop {
+        let count = read_block_count(payload, &mut pos)?;
+        if count == 0 {
+            break;
+        }
+        for _ in 0..count {
+            let key = read_bytes(payload, &mut pos)?;
+            let value = read_bytes(payload, &mut pos)?;
+            match key {
+                b"avro.schema" => schema = Some(value),
+                b"avro.codec" if value != b"null" => {
+                    return Err(malformed("compressed blocks are not supported"))
+                }
+                _ => {}
+            }
+        }
+    }
+    let schema: Value = serde_json::from_slice(schema.ok_or_else(|| malformed("no schema"))?)
+        .map_err(|e| malformed(&e.to_string()))?;
+    let fields = schema["fields"]
+        .as_array()
+        .ok_or_else(|| malformed("the schema is not a record"))?
+        .iter()
+        .map(field_from_json)
+        .collect::<Result<Vec<AvroField>, EncodingError>>()?;
+    read_slice(payload, &mut pos, SYNC_MARKER_LENGTH)?;
+
+    if read_block_count(payload, &mut pos)? == 0 {
+        return Err(malformed("the payload has no records"));
+    }
+    let block = read_bytes(payload, &mut pos)?;
+
+    let mut block_pos = 0;
+    let mut record = serde_json::Map::new();
+    for field in &fields {
+        record.insert(
+            field.name.clone(),
+            read_field(field, block, &mut block_pos)?,
+        );
+    }
+    Ok(Value::Object(record))
+}
+
+#[cfg(test)]
+mod tests {
+    use schema_ast::ast::FieldArity;
+    use serde_json::json;
+
+    use crate::framework::schema::ColumnType;
+    use crate::infrastructure::ingest::tests::table;
+
+    use super::*;
+
+    fn data_model() -> Table {
+        table(
+            "Event",
+            &[
+                ("id", ColumnType::String, FieldArity::Required),
+                ("active", ColumnType::Boolean, FieldArity::Required),
+                ("count", ColumnType::Int, FieldArity::Required),
+                ("total", ColumnType::BigInt, FieldArity::Required),
+                ("ratio", ColumnType::Float, FieldArity::Required),
+                ("price", ColumnType::Decimal, FieldArity::Required),
+                ("at", ColumnType::DateTime, FieldArity::Required),
+                ("note", ColumnType::String, FieldArity::Optional),
+                ("tags", ColumnType::String, FieldArity::List),
+                ("scores", ColumnType::Int, FieldArity::List),
+            ],
+        )
+    }
+
+    fn round_trip(record: &Value) -> Value {
+        let data_model = data_model();
+        let schema = AvroSchema::new(&data_model).unwrap();
+        decode(&schema.encode(&data_model, record).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn round_trips_every_column_type() {
+        let record = json!({
+            "id": "a",
+            "active": true,
+            "count": -3,
+            "total": i64::MIN,
+            "ratio": -0.5,
+            "price": 10.25,
+            "at": "2024-01-02T03:04:05.678Z",
+            "note": "hello",
+            "tags": ["x", "y"],
+            "scores": [-1, 0, 300],
+        });
+        assert_eq!(round_trip(&record), record);
+    }
+
+    #[test]
+    fn decodes_missing_fields_as_null_and_empty_lists() {
+        let record = json!({
+            "id": "a",
+            "active": false,
+            "count": 0,
+            "total": 0,
+            "ratio": 0.0,
+            "price": 0.0,
+            "at": "1970-01-01T00:00:00.000Z",
+        });
+        let decoded = round_trip(&record);
+        assert_eq!(decoded["note"], Value::Null);
+        assert_eq!(decoded["tags"], json!([]));
+        assert_eq!(decoded["scores"], json!([]));
+
+        let mut explicit_nulls = record.clone();
+        explicit_nulls["note"] = Value::Null;
+        explicit_nulls["tags"] = Value::Null;
+        assert_eq!(round_trip(&explicit_nulls), decoded);
+    }
+
+    #[test]
+    fn reuses_the_header_of_the_route() {
+        let data_model = data_model();
+        let schema = AvroSchema::new(&data_model).unwrap();
+        let record = json!({
+            "id": "a", "active": true, "count": 1, "total": 2, "ratio": 0.5, "price": 1.5,
+            "at": "2024-01-02T03:04:05.678Z",
+        });
+        let first = schema.encode(&data_model, &record).unwrap();
+        let second = schema.encode(&data_model, &record).unwrap();
+        assert!(first.starts_with(&schema.header));
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn rejects_missing_required_fields() {
+        let data_model = data_model();
+        let schema = AvroSchema::new(&data_model).unwrap();
+        assert!(matches!(
+            schema.encode(&data_model, &json!({"id": "a"})),
+            Err(EncodingError::MissingField { field_name }) if field_name == "active"
+        ));
+    }
+
+    #[test]
+    fn rejects_truncated_payloads() {
+        let data_model = data_model();
+        let schema = AvroSchema::new(&data_model).unwrap();
+        assert!(matches!(
+            decode(&schema.header),
+            Err(EncodingError::MalformedPayload { .. })
+        ));
+    }
+}
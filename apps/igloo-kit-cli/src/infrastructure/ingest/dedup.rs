@@ -0,0 +1,278 @@
+//! # Dedup
+//! Keeps client retries from producing the same records twice. The ingest routes remember the keys
+//! of the records they produced for a while, and acknowledge records whose key they already saw
+//! without producing them again. A key is either the `Idempotency-Key` header of the request, which
+//! covers the whole request, or the value of a field declared per data model:
+//!
+//! ```toml
+//! [ingest_config.deduplication]
+//! window_secs = 3600
+//! max_keys = 100000
+//!
+//! [ingest_config.deduplication.models.userevent]
+//! field = "event_id"
+//! replacing_merge_tree = true
+//! ```
+//!
+//! A key is taken when its records start being produced and committed once they were. A request
+//! whose key is still being produced is answered with a 409 so that the client retries it later,
+//! and only a committed key makes a request a duplicate.
+//!
+//! A request key only covers requests that fully succeeded. A batch answered with a 207 forgets its
+//! key, so retrying it produces the records that made it again. Batches that can partially fail
+//! should declare a dedup field instead, which deduplicates every record on its own.
+//!
+//! Keys are only remembered by the running server. With `replacing_merge_tree` the model's view
+//! stores its rows in a `ReplacingMergeTree` ordered by the field, so duplicates that got through
+//! anyway, e.g. after a restart or from a replay, are dropped by ClickHouse when parts are merged.
+//! Queries that can't wait for merges need `FINAL`.
+//!
+//! ## Suggested Improvements
+//! - persist the keys so that they survive restarts
+//! - support composite dedup keys
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ModelDeduplication {
+    pub field: String,
+    #[serde(default)]
+    pub replacing_merge_tree: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeduplicationConfig {
+    // How long keys are remembered
+    #[serde(default = "default_window_secs")]
+    pub window_secs: u64,
+    // The oldest keys are forgotten early past this many
+    #[serde(default = "default_max_keys")]
+    pub max_keys: usize,
+    #[serde(default)]
+    pub models: HashMap<String, ModelDeduplication>,
+}
+
+fn default_window_secs() -> u64 {
+    3600
+}
+
+fn default_max_keys() -> usize {
+    100_000
+}
+
+impl Default for DeduplicationConfig {
+    fn default() -> Self {
+        Self {
+            window_secs: default_window_secs(),
+            max_keys: default_max_keys(),
+            models: HashMap::new(),
+        }
+    }
+}
+
+impl DeduplicationConfig {
+    pub fn model(&self, model_name: &str) -> Option<&ModelDeduplication> {
+        self.models.get(&model_name.to_lowercase())
+    }
+}
+
+pub fn dedup_value(record: &Value, field: &str) -> Option<String> {
+    //! The dedup key of a record, absent when the record doesn't have a string or number in the field.
+    match record.get(field)? {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyStatus {
+    // The key wasn't seen, it's now in flight until it's committed or forgotten
+    New,
+    // The records of the key are being produced
+    InFlight,
+    // The records of the key were produced
+    Committed,
+}
+
+struct SeenKey {
+    seen_at: Instant,
+    committed: bool,
+}
+
+#[derive(Default)]
+struct SeenKeys {
+    seen: HashMap<String, SeenKey>,
+    // Keys in the order they were first seen, to expire them
+    order: VecDeque<(String, Instant)>,
+}
+
+impl SeenKeys {
+    fn pop_oldest(&mut self) {
+        if let Some((key, seen_at)) = self.order.pop_front() {
+            // The key may have been forgotten and seen again since
+            if self.seen.get(&key).map(|seen| seen.seen_at) == Some(seen_at) {
+                self.seen.remove(&key);
+            }
+        }
+    }
+}
+
+pub struct IdempotencyCache {
+    window: Duration,
+    max_keys: usize,
+    keys: Mutex<SeenKeys>,
+}
+
+impl IdempotencyCache {
+    pub fn new(config: &DeduplicationConfig) -> Self {
+        Self {
+            window: Duration::from_secs(config.window_secs),
+            max_keys: config.max_keys.max(1),
+            keys: Mutex::new(SeenKeys::default()),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, SeenKeys> {
+        match self.keys.lock() {
+            Ok(keys) => keys,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    pub fn begin(&self, key: &str) -> KeyStatus {
+        //! Takes a key before its records are produced. The records must only be produced when the
+        //! key is `New`, the key being in flight until it's committed or forgotten.
+        self.begin_at(key, Instant::now())
+    }
+
+    fn begin_at(&self, key: &str, now: Instant) -> KeyStatus {
+        let mut keys = self.lock();
+
+        while keys.order.front().map_or(false, |(_, seen_at)| {
+            now.saturating_duration_since(*seen_at) >= self.window
+        }) {
+            keys.pop_oldest();
+        }
+
+        if let Some(seen) = keys.seen.get(key) {
+            return if seen.committed {
+                KeyStatus::Committed
+            } else {
+                KeyStatus::InFlight
+            };
+        }
+
+        while keys.seen.len() >= self.max_keys && !keys.order.is_empty() {
+            keys.pop_oldest();
+        }
+
+        keys.seen.insert(
+            key.to_string(),
+            SeenKey {
+                seen_at: now,
+                committed: false,
+            },
+        );
+        keys.order.push_back((key.to_string(), now));
+        KeyStatus::New
+    }
+
+    pub fn commit(&self, key: &str) {
+        //! Marks a key whose records were produced, so that the next requests with it are duplicates.
+        if let Some(seen) = self.lock().seen.get_mut(key) {
+            seen.committed = true;
+        }
+    }
+
+    pub fn forget(&self, key: &str) {
+        //! Forgets a key whose records failed to be produced, so that the client can retry them.
+        self.lock().seen.remove(key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(window_secs: u64, max_keys: usize) -> IdempotencyCache {
+        IdempotencyCache::new(&DeduplicationConfig {
+            window_secs,
+            max_keys,
+            models: HashMap::new(),
+        })
+    }
+
+    #[test]
+    fn reports_keys_in_flight_until_they_are_committed() {
+        let cache = cache(60, 10);
+
+        assert_eq!(cache.begin("a"), KeyStatus::New);
+        assert_eq!(cache.begin("a"), KeyStatus::InFlight);
+
+        cache.commit("a");
+        assert_eq!(cache.begin("a"), KeyStatus::Committed);
+    }
+
+    #[test]
+    fn forgotten_keys_can_be_retried() {
+        let cache = cache(60, 10);
+
+        assert_eq!(cache.begin("a"), KeyStatus::New);
+        cache.forget("a");
+        assert_eq!(cache.begin("a"), KeyStatus::New);
+
+        // Committing a forgotten key doesn't bring it back
+        cache.forget("a");
+        cache.commit("a");
+        assert_eq!(cache.begin("a"), KeyStatus::New);
+    }
+
+    #[test]
+    fn expires_keys_after_the_window() {
+        let cache = cache(60, 10);
+        let now = Instant::now();
+
+        assert_eq!(cache.begin_at("a", now), KeyStatus::New);
+        cache.commit("a");
+        assert_eq!(
+            cache.begin_at("a", now + Duration::from_secs(59)),
+            KeyStatus::Committed
+        );
+        assert_eq!(
+            cache.begin_at("a", now + Duration::from_secs(60)),
+            KeyStatus::New
+        );
+    }
+
+    #[test]
+    fn forgets_the_oldest_keys_past_the_limit() {
+        let cache = cache(60, 2);
+
+        for key in ["a", "b", "c"] {
+            assert_eq!(cache.begin(key), KeyStatus::New);
+            cache.commit(key);
+        }
+
+        assert_eq!(cache.begin("a"), KeyStatus::New);
+        assert_eq!(cache.begin("c"), KeyStatus::Committed);
+    }
+
+    #[test]
+    fn reads_dedup_values_from_strings_and_numbers() {
+        let record = serde_json::json!({ "id": "a", "n": 1, "flag": true });
+        assert_eq!(dedup_value(&record, "id"), Some("a".to_string()));
+        assert_eq!(dedup_value(&record, "n"), Some("1".to_string()));
+        assert_eq!(dedup_value(&record, "flag"), None);
+        assert_eq!(dedup_value(&record, "missing"), None);
+    }
+}
@@ -0,0 +1,115 @@
+//! # Metadata
+//! Columns that describe how a record arrived, filled in by the ingest routes before the record is
+//! produced. They're opted into per project and added to every data model, so they show up in the
+//! ClickHouse tables and in the generated TypeScript types as optional fields:
+//!
+//! ```toml
+//! [ingest_config]
+//! metadata_columns = ["ingested_at", "request_id", "source_ip", "user_agent", "sdk_version"]
+//! ```
+//!
+//! Values sent by clients for these columns are always overwritten by the server.
+//!
+//! ## Suggested Improvements
+//! - let projects rename the columns
+//! - add the partition and offset the record was produced at
+
+use std::time::SystemTime;
+
+use schema_ast::ast::FieldArity;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::framework::schema::{Column, ColumnType, Table};
+
+// Sent by the generated SDK with the version of the CLI that generated it
+pub const SDK_VERSION_HEADER: &str = "X-Igloo-Sdk-Version";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MetadataColumn {
+    IngestedAt,
+    RequestId,
+    SourceIp,
+    UserAgent,
+    SdkVersion,
+}
+
+impl MetadataColumn {
+    pub fn column_name(&self) -> &'static str {
+        match self {
+            MetadataColumn::IngestedAt => "_ingested_at",
+            MetadataColumn::RequestId => "_request_id",
+            MetadataColumn::SourceIp => "_source_ip",
+            MetadataColumn::UserAgent => "_user_agent",
+            MetadataColumn::SdkVersion => "_sdk_version",
+        }
+    }
+
+    fn column(&self) -> Column {
+        Column {
+            name: self.column_name().to_string(),
+            data_type: match self {
+                MetadataColumn::IngestedAt => ColumnType::DateTime,
+                _ => ColumnType::String,
+            },
+            // Optional so that clients never have to send them
+            arity: FieldArity::Optional,
+            unique: false,
+            primary_key: false,
+            default: None,
+        }
+    }
+}
+
+pub fn add_metadata_columns(data_model: &mut Table, metadata_columns: &[MetadataColumn]) {
+    //! Appends the metadata columns to the data model, skipping columns the model already declares.
+    for metadata_column in metadata_columns {
+        let name = metadata_column.column_name();
+        if !data_model.columns.iter().any(|column| column.name == name) {
+            data_model.columns.push(metadata_column.column());
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct IngestMetadata {
+    pub ingested_at: SystemTime,
+    pub request_id: String,
+    pub source_ip: String,
+    pub user_agent: Option<String>,
+    pub sdk_version: Option<String>,
+}
+
+impl IngestMetadata {
+    fn value(&self, metadata_column: MetadataColumn) -> Value {
+        let optional = |value: &Option<String>| {
+            value
+                .as_ref()
+                .map_or(Value::Null, |value| Value::String(value.clone()))
+        };
+
+        match metadata_column {
+            MetadataColumn::IngestedAt => {
+                Value::String(humantime::format_rfc3339_millis(self.ingested_at).to_string())
+            }
+            MetadataColumn::RequestId => Value::String(self.request_id.clone()),
+            MetadataColumn::SourceIp => Value::String(self.source_ip.clone()),
+            MetadataColumn::UserAgent => optional(&self.user_agent),
+            MetadataColumn::SdkVersion => optional(&self.sdk_version),
+        }
+    }
+
+    pub fn enrich(&self, record: &mut Value, metadata_columns: &[MetadataColumn]) {
+        //! Sets the metadata columns of a record. Records that aren't objects are left for the
+        //! validation to reject.
+        if let Some(fields) = record.as_object_mut() {
+            for metadata_column in metadata_columns {
+                fields.insert(
+                    metadata_column.column_name().to_string(),
+                    self.value(*metadata_column),
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,683 @@
+//! # Protobuf
+//! Encodes records as single Protobuf messages. ClickHouse reads every Kafka message with the
+//! `ProtobufSingle` input format and needs the matching `.proto` file in its format schema directory.
+//!
+//! Field numbers are assigned in the order of the columns of the data model, starting at 1. Once a
+//! definition was generated, it's kept in `app/format_schemas` with the project and its numbers are
+//! reused, so that messages already on the topic keep decoding after the data model changes:
+//!
+//! - columns keep their number as long as their name, type and arity don't change
+//! - new and changed columns take the next unused number
+//! - numbers of removed columns are declared `reserved` and never handed out again
+
+use schema_ast::ast::FieldArity;
+use serde::Serialize;
+use serde_json::Value;
+use tinytemplate::TinyTemplate;
+
+use crate::framework::schema::{Column, ColumnType, Table, UnsupportedDataTypeError};
+
+use super::{datetime_to_millis, millis_to_datetime, record_fields, EncodingError};
+
+pub static PROTO_SCHEMA_TEMPLATE: &str = r#"
+syntax = "proto3";
+
+message {message_name} \{
+{{if reserved}}    reserved {reserved};
+{{endif}}{{for field in fields}}    {field.declaration} {field.field_name} = {field.field_number};
+{{endfor}}}
+"#;
+
+#[derive(Debug, Clone)]
+pub struct ProtoSchema {
+    // The field number of each column, in the order of the columns of the data model
+    field_numbers: Vec<u32>,
+    definition: String,
+}
+
+impl ProtoSchema {
+    pub fn new(
+        data_model: &Table,
+        previous_definition: Option<&str>,
+    ) -> Result<ProtoSchema, EncodingError> {
+        //! Builds the message definition of the data model, which is rendered once per route.
+        //! `previous_definition` is the definition last generated for the data model, if any.
+        let (previous_fields, mut reserved) = previous_definition
+            .map(parse_definition)
+            .unwrap_or_default();
+
+        let mut next_number = previous_fields
+            .iter()
+            .map(|field| field.number)
+            .chain(reserved.iter().copied())
+            .max()
+            .unwrap_or(0)
+            + 1;
+        let mut field_numbers = vec![];
+        for column in data_model.columns.iter() {
+            let declaration = field_declaration(column)?;
+            let previous = previous_fields
+                .iter()
+                .find(|field| field.name == column.name && field.declaration == declaration);
+            match previous {
+                Some(field) => field_numbers.push(field.number),
+                None => {
+                    field_numbers.push(next_number);
+                    next_number += 1;
+                }
+            }
+        }
+        reserved.extend(
+            previous_fields
+                .iter()
+                .map(|field| field.number)
+                .filter(|number| !field_numbers.contains(number)),
+        );
+        reserved.sort_unstable();
+        reserved.dedup();
+
+        let context = ProtoSchemaContext::new(data_model, &field_numbers, &reserved)?;
+
+        let serialization_error = |e: tinytemplate::error::Error| EncodingError::Serialization {
+            reason: format!("failed to render the protobuf schema, {}", e),
+        };
+        let mut tt = TinyTemplate::new();
+        tt.add_template("proto_schema", PROTO_SCHEMA_TEMPLATE)
+            .map_err(serialization_error)?;
+        let definition = tt
+            .render("proto_schema", &context)
+            .map_err(serialization_error)?;
+
+        Ok(ProtoSchema {
+            field_numbers,
+            definition,
+        })
+    }
+
+    pub fn definition(&self) -> &str {
+        //! The `.proto` file ClickHouse decodes the messages of the topic with.
+        &self.definition
+    }
+
+    pub fn file_name(data_model: &Table) -> String {
+        format!("{}.proto", data_model.name)
+    }
+
+    pub fn format_schema(data_model: &Table) -> String {
+        //! The `schema_file:MessageName` reference ClickHouse uses to find the message definition.
+        format!("{}:{}", Self::file_name(data_model), data_model.name)
+    }
+
+    fn column<'a>(&self, data_model: &'a Table, field_number: u64) -> Option<&'a Column> {
+        self.field_numbers
+            .iter()
+            .position(|number| *number as u64 == field_number)
+            .and_then(|i| data_model.columns.get(i))
+    }
+}
+
+// A field declared by a previously generated definition
+#[derive(Debug, Clone, PartialEq)]
+struct DeclaredField {
+    // The label and the type, such as `repeated int64`
+    declaration: String,
+    name: String,
+    number: u32,
+}
+
+fn parse_definition(definition: &str) -> (Vec<DeclaredField>, Vec<u32>) {
+    //! Reads back the fields and the reserved numbers of a definition rendered from
+    //! `PROTO_SCHEMA_TEMPLATE`. Lines that aren't field declarations are skipped.
+    let mut fields = vec![];
+    let mut reserved = vec![];
+
+    for line in definition.lines() {
+        let line = line.trim().trim_end_matches(';');
+        if let Some(numbers) = line.strip_prefix("reserved ") {
+            reserved.extend(
+                numbers
+                    .split(',')
+                    .filter_map(|number| number.trim().parse::<u32>().ok()),
+            );
+        } else if let Some((declaration, number)) = line.split_once('=') {
+            let mut tokens = declaration.split_whitespace().collect::<Vec<&str>>();
+            match (tokens.pop(), number.trim().parse::<u32>()) {
+                (Some(name), Ok(number)) if !tokens.is_empty() => fields.push(DeclaredField {
+                    declaration: tokens.join(" "),
+                    name: name.to_string(),
+                    number,
+                }),
+                _ => {}
+            }
+        }
+    }
+
+    (fields, reserved)
+}
+
+fn field_declaration(column: &Column) -> Result<String, UnsupportedDataTypeError> {
+    let label = match column.arity {
+        FieldArity::Required => "",
+        FieldArity::Optional => "optional ",
+        FieldArity::List => "repeated ",
+    };
+    Ok(format!(
+        "{}{}",
+        label,
+        column_type_to_proto_type(&column.data_type)?
+    ))
+}
+
+#[derive(Serialize)]
+struct ProtoSchemaContext {
+    message_name: String,
+    // The numbers of removed fields, joined with commas
+    reserved: String,
+    fields: Vec<ProtoFieldContext>,
+}
+
+impl ProtoSchemaContext {
+    fn new(
+        data_model: &Table,
+        field_numbers: &[u32],
+        reserved: &[u32],
+    ) -> Result<ProtoSchemaContext, UnsupportedDataTypeError> {
+        Ok(ProtoSchemaContext {
+            message_name: data_model.name.clone(),
+            reserved: reserved
+                .iter()
+                .map(u32::to_string)
+                .collect::<Vec<String>>()
+                .join(", "),
+            fields: data_model
+                .columns
+                .iter()
+                .zip(field_numbers)
+                .map(|(column, number)| ProtoFieldContext::new(column, *number))
+                .collect::<Result<Vec<ProtoFieldContext>, UnsupportedDataTypeError>>()?,
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct ProtoFieldContext {
+    declaration: String,
+    field_name: String,
+    field_number: u32,
+}
+
+impl ProtoFieldContext {
+    fn new(
+        column: &Column,
+        field_number: u32,
+    ) -> Result<ProtoFieldContext, UnsupportedDataTypeError> {
+        Ok(ProtoFieldContext {
+            declaration: field_declaration(column)?,
+            field_name: column.name.clone(),
+            field_number,
+        })
+    }
+}
+
+fn column_type_to_proto_type(
+    data_type: &ColumnType,
+) -> Result<&'static str, UnsupportedDataTypeError> {
+    match data_type {
+        ColumnType::String => Ok("string"),
+        ColumnType::Boolean => Ok("bool"),
+        ColumnType::Int => Ok("int64"),
+        ColumnType::BigInt => Ok("int64"),
+        ColumnType::Float => Ok("double"),
+        ColumnType::Decimal => Ok("double"),
+        // Milliseconds since the epoch, read into the DateTime64(3) column of the table
+        ColumnType::DateTime => Ok("int64"),
+        _ => Err(UnsupportedDataTypeError {
+            type_name: data_type.to_string(),
+        }),
+    }
+}
+
+const WIRE_TYPE_VARINT: u64 = 0;
+const WIRE_TYPE_FIXED64: u64 = 1;
+const WIRE_TYPE_LENGTH_DELIMITED: u64 = 2;
+const WIRE_TYPE_FIXED32: u64 = 5;
+
+fn write_varint(mut value: u64, buf: &mut Vec<u8>) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn write_key(field_number: u32, wire_type: u64, buf: &mut Vec<u8>) {
+    write_varint(((field_number as u64) << 3) | wire_type, buf);
+}
+
+fn write_value(
+    column: &Column,
+    field_number: u32,
+    value: &Value,
+    buf: &mut Vec<u8>,
+) -> Result<(), EncodingError> {
+    let invalid = || EncodingError::InvalidValue {
+        field_name: column.name.clone(),
+        expected: column.data_type.to_string(),
+    };
+
+    match column.data_type {
+        ColumnType::String => {
+            let s = value.as_str().ok_or_else(invalid)?;
+            write_key(field_number, WIRE_TYPE_LENGTH_DELIMITED, buf);
+            write_varint(s.len() as u64, buf);
+            buf.extend_from_slice(s.as_bytes());
+        }
+        ColumnType::Boolean => {
+            let b = value.as_bool().ok_or_else(invalid)?;
+            write_key(field_number, WIRE_TYPE_VARINT, buf);
+            write_varint(b as u64, buf);
+        }
+        ColumnType::Int | ColumnType::BigInt => {
+            let i = value.as_i64().ok_or_else(invalid)?;
+            write_key(field_number, WIRE_TYPE_VARINT, buf);
+            write_varint(i as u64, buf);
+        }
+        ColumnType::Float | ColumnType::Decimal => {
+            let f = value.as_f64().ok_or_else(invalid)?;
+            write_key(field_number, WIRE_TYPE_FIXED64, buf);
+            buf.extend_from_slice(&f.to_le_bytes());
+        }
+        ColumnType::DateTime => {
+            let millis = datetime_to_millis(&column.name, value)?;
+            write_key(field_number, WIRE_TYPE_VARINT, buf);
+            write_varint(millis as u64, buf);
+        }
+        _ => {
+            return Err(EncodingError::UnsupportedDataType(
+                UnsupportedDataTypeError {
+                    type_name: column.data_type.to_string(),
+                },
+            ))
+        }
+    }
+    Ok(())
+}
+
+impl ProtoSchema {
+    pub fn encode(&self, data_model: &Table, record: &Value) -> Result<Vec<u8>, EncodingError> {
+        let fields = record_fields(record)?;
+        let mut buf = Vec::new();
+
+        for (column, field_number) in data_model
+            .columns
+            .iter()
+            .zip(self.field_numbers.iter().copied())
+        {
+            match (column.arity, fields.get(&column.name)) {
+                (FieldArity::Required, None | Some(Value::Null)) => {
+                    return Err(EncodingError::MissingField {
+                        field_name: column.name.clone(),
+                    })
+                }
+                // Absent optional fields and lists are simply left out of the message
+                (_, None | Some(Value::Null)) => {}
+                (FieldArity::List, Some(Value::Array(items))) => {
+                    for item in items {
+                        write_value(column, field_number, item, &mut buf)?;
+                    }
+                }
+                (FieldArity::List, Some(_)) => {
+                    return Err(EncodingError::InvalidValue {
+                        field_name: column.name.clone(),
+                        expected: format!("{}[]", column.data_type),
+                    })
+                }
+                (_, Some(value)) => write_value(column, field_number, value, &mut buf)?,
+            }
+        }
+
+        Ok(buf)
+    }
+}
+
+fn malformed(reason: &str) -> EncodingError {
+    EncodingError::MalformedPayload {
+        reason: format!("invalid protobuf message, {}", reason),
+    }
+}
+
+fn read_varint(buf: &[u8], pos: &mut usize) -> Result<u64, EncodingError> {
+    let mut value: u64 = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = *buf.get(*pos).ok_or_else(|| malformed("truncated varint"))?;
+        *pos += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte < 0x80 {
+            return Ok(value);
+        }
+    }
+    Err(malformed("varint is too long"))
+}
+
+fn read_bytes<'a>(buf: &'a [u8], pos: &mut usize, len: usize) -> Result<&'a [u8], EncodingError> {
+    let end = pos
+        .checked_add(len)
+        .ok_or_else(|| malformed("field is too long"))?;
+    let bytes = buf
+        .get(*pos..end)
+        .ok_or_else(|| malformed("truncated field"))?;
+    *pos = end;
+    Ok(bytes)
+}
+
+fn value_from_varint(column: &Column, value: u64) -> Result<Value, EncodingError> {
+    match column.data_type {
+        ColumnType::Boolean => Ok(Value::Bool(value != 0)),
+        ColumnType::DateTime => {
+            millis_to_datetime(value as i64).ok_or_else(|| malformed("timestamp out of range"))
+        }
+        _ => Ok(Value::from(value as i64)),
+    }
+}
+
+fn value_from_fixed64(bytes: &[u8]) -> Result<Value, EncodingError> {
+    let bytes: [u8; 8] = bytes
+        .try_into()
+        .map_err(|_| malformed("truncated double"))?;
+    Ok(Value::from(f64::from_le_bytes(bytes)))
+}
+
+fn read_values(
+    column: &Column,
+    wire_type: u64,
+    buf: &[u8],
+    pos: &mut usize,
+) -> Result<Vec<Value>, EncodingError> {
+    match wire_type {
+        WIRE_TYPE_VARINT => Ok(vec![value_from_varint(column, read_varint(buf, pos)?)?]),
+        WIRE_TYPE_FIXED64 => Ok(vec![value_from_fixed64(read_bytes(buf, pos, 8)?)?]),
+        WIRE_TYPE_LENGTH_DELIMITED => {
+            let len = read_varint(buf, pos)? as usize;
+            let bytes = read_bytes(buf, pos, len)?;
+
+            match column.data_type {
+                ColumnType::String => Ok(vec![Value::String(
+                    String::from_utf8_lossy(bytes).to_string(),
+                )]),
+                // Repeated scalars may be packed into a single length delimited field
+                ColumnType::Float | ColumnType::Decimal => bytes
+                    .chunks(8)
+                    .map(value_from_fixed64)
+                    .collect::<Result<Vec<Value>, EncodingError>>(
+                ),
+                _ => {
+                    let mut packed_pos = 0;
+                    let mut values = vec![];
+                    while packed_pos < bytes.len() {
+                        values.push(value_from_varint(
+                            column,
+                            read_varint(bytes, &mut packed_pos)?,
+                        )?);
+                    }
+                    Ok(values)
+                }
+            }
+        }
+        _ => Err(malformed("unsupported wire type")),
+    }
+}
+
+fn skip_field(wire_type: u64, buf: &[u8], pos: &mut usize) -> Result<(), EncodingError> {
+    match wire_type {
+        WIRE_TYPE_VARINT => read_varint(buf, pos).map(|_| ()),
+        WIRE_TYPE_FIXED64 => read_bytes(buf, pos, 8).map(|_| ()),
+        WIRE_TYPE_LENGTH_DELIMITED => {
+            let len = read_varint(buf, pos)? as usize;
+            read_bytes(buf, pos, len).map(|_| ())
+        }
+        WIRE_TYPE_FIXED32 => read_bytes(buf, pos, 4).map(|_| ()),
+        _ => Err(malformed("unsupported wire type")),
+    }
+}
+
+impl ProtoSchema {
+    pub fn decode(&self, data_model: &Table, payload: &[u8]) -> Result<Value, EncodingError> {
+        //! Decodes a message written by `encode`, fields whose number isn't in the schema are skipped.
+        let mut record = serde_json::Map::new();
+
+        for column in data_model.columns.iter() {
+            if column.arity == FieldArity::List {
+                record.insert(column.name.clone(), Value::Array(vec![]));
+            }
+        }
+
+        let mut pos = 0;
+        while pos < payload.len() {
+            let key = read_varint(payload, &mut pos)?;
+            let wire_type = key & 0x7;
+            let column = match self.column(data_model, key >> 3) {
+                Some(column) => column,
+                None => {
+                    skip_field(wire_type, payload, &mut pos)?;
+                    continue;
+                }
+            };
+
+            let values = read_values(column, wire_type, payload, &mut pos)?;
+
+            match record.get_mut(&column.name) {
+                Some(Value::Array(items)) if column.arity == FieldArity::List => {
+                    items.extend(values)
+                }
+                _ => {
+                    if let Some(value) = values.into_iter().last() {
+                        record.insert(column.name.clone(), value);
+                    }
+                }
+            }
+        }
+
+        Ok(Value::Object(record))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use schema_ast::ast::FieldArity;
+    use serde_json::json;
+
+    use crate::framework::schema::ColumnType;
+    use crate::infrastructure::ingest::tests::table;
+
+    use super::*;
+
+    #[test]
+    fn keeps_field_numbers_when_the_data_model_changes() {
+        let before = table(
+            "Event",
+            &[
+                ("id", ColumnType::String, FieldArity::Required),
+                ("count", ColumnType::Int, FieldArity::Required),
+                ("tag", ColumnType::String, FieldArity::Optional),
+            ],
+        );
+        let before_schema = ProtoSchema::new(&before, None).unwrap();
+        let payload = before_schema
+            .encode(&before, &json!({"id": "a", "count": 3, "tag": "x"}))
+            .unwrap();
+
+        // count is removed and source is added in front of the other columns
+        let after = table(
+            "Event",
+            &[
+                ("source", ColumnType::String, FieldArity::Optional),
+                ("id", ColumnType::String, FieldArity::Required),
+                ("tag", ColumnType::String, FieldArity::Optional),
+            ],
+        );
+        let after_schema = ProtoSchema::new(&after, Some(before_schema.definition())).unwrap();
+
+        assert_eq!(after_schema.field_numbers, vec![4, 1, 3]);
+        assert!(after_schema.definition().contains("    reserved 2;\n"));
+        assert_eq!(
+            after_schema.decode(&after, &payload).unwrap(),
+            json!({"id": "a", "tag": "x"})
+        );
+    }
+
+    #[test]
+    fn changed_columns_take_a_new_number() {
+        let before = table(
+            "Event",
+            &[
+                ("id", ColumnType::String, FieldArity::Required),
+                ("count", ColumnType::Int, FieldArity::Required),
+            ],
+        );
+        let before_schema = ProtoSchema::new(&before, None).unwrap();
+        let payload = before_schema
+            .encode(&before, &json!({"id": "a", "count": 3}))
+            .unwrap();
+
+        let after = table(
+            "Event",
+            &[
+                ("id", ColumnType::String, FieldArity::Required),
+                ("count", ColumnType::Int, FieldArity::List),
+            ],
+        );
+        let after_schema = ProtoSchema::new(&after, Some(before_schema.definition())).unwrap();
+
+        assert_eq!(after_schema.field_numbers, vec![1, 3]);
+        assert_eq!(
+            after_schema.decode(&after, &payload).unwrap(),
+            json!({"id": "a", "count": []})
+        );
+    }
+
+    #[test]
+    fn never_hands_out_reserved_numbers() {
+        let previous = r#"
+syntax = "proto3";
+
+message Event {
+    reserved 2, 5;
+    string id = 1;
+}
+"#;
+        let data_model = table(
+            "Event",
+            &[
+                ("id", ColumnType::String, FieldArity::Required),
+                ("name", ColumnType::String, FieldArity::Required),
+            ],
+        );
+        let schema = ProtoSchema::new(&data_model, Some(previous)).unwrap();
+
+        assert_eq!(schema.field_numbers, vec![1, 6]);
+        assert!(schema
+            .definition()
+            .contains("    reserved 2, 5;\n    string id = 1;\n    string name = 6;\n"));
+    }
+
+    fn data_model() -> Table {
+        table(
+            "Event",
+            &[
+                ("id", ColumnType::String, FieldArity::Required),
+                ("active", ColumnType::Boolean, FieldArity::Required),
+                ("count", ColumnType::Int, FieldArity::Required),
+                ("total", ColumnType::BigInt, FieldArity::Required),
+                ("ratio", ColumnType::Float, FieldArity::Required),
+                ("price", ColumnType::Decimal, FieldArity::Required),
+                ("at", ColumnType::DateTime, FieldArity::Required),
+                ("note", ColumnType::String, FieldArity::Optional),
+                ("tags", ColumnType::String, FieldArity::List),
+                ("scores", ColumnType::Int, FieldArity::List),
+            ],
+        )
+    }
+
+    fn round_trip(record: &Value) -> Value {
+        let data_model = data_model();
+        let schema = ProtoSchema::new(&data_model, None).unwrap();
+        let payload = schema.encode(&data_model, record).unwrap();
+        schema.decode(&data_model, &payload).unwrap()
+    }
+
+    #[test]
+    fn round_trips_every_column_type() {
+        let record = json!({
+            "id": "a",
+            "active": true,
+            "count": -3,
+            "total": i64::MIN,
+            "ratio": -0.5,
+            "price": 10.25,
+            "at": "2024-01-02T03:04:05.678Z",
+            "note": "hello",
+            "tags": ["x", "y"],
+            "scores": [-1, 0, 300],
+        });
+        assert_eq!(round_trip(&record), record);
+    }
+
+    #[test]
+    fn leaves_missing_optional_fields_out() {
+        let record = json!({
+            "id": "a",
+            "active": false,
+            "count": 0,
+            "total": 0,
+            "ratio": 0.0,
+            "price": 0.0,
+            "at": "1970-01-01T00:00:00.000Z",
+            "note": null,
+        });
+        let decoded = round_trip(&record);
+        assert_eq!(decoded.get("note"), None);
+        assert_eq!(decoded["tags"], json!([]));
+        assert_eq!(decoded["scores"], json!([]));
+    }
+
+    #[test]
+    fn decodes_packed_repeated_fields() {
+        let data_model = data_model();
+        let schema = ProtoSchema::new(&data_model, None).unwrap();
+        // scores is field 10, packed into a single length delimited field
+        let mut payload = vec![];
+        write_key(10, WIRE_TYPE_LENGTH_DELIMITED, &mut payload);
+        let mut packed = vec![];
+        for score in [-1i64, 2] {
+            write_varint(score as u64, &mut packed);
+        }
+        write_varint(packed.len() as u64, &mut payload);
+        payload.extend(packed);
+
+        assert_eq!(
+            schema.decode(&data_model, &payload).unwrap()["scores"],
+            json!([-1, 2])
+        );
+    }
+
+    #[test]
+    fn rejects_missing_required_fields() {
+        let data_model = data_model();
+        let schema = ProtoSchema::new(&data_model, None).unwrap();
+        assert!(matches!(
+            schema.encode(&data_model, &json!({"id": "a"})),
+            Err(EncodingError::MissingField { field_name }) if field_name == "active"
+        ));
+    }
+
+    #[test]
+    fn renders_the_definition_of_every_column_type() {
+        let schema = ProtoSchema::new(&data_model(), None).unwrap();
+        assert!(schema.definition().contains(
+            "message Event {\n    string id = 1;\n    bool active = 2;\n    int64 count = 3;\n"
+        ));
+        assert!(schema
+            .definition()
+            .contains("    optional string note = 8;\n    repeated string tags = 9;\n"));
+    }
+}
@@ -0,0 +1,230 @@
+//! # Validation
+//! Checks records against the columns of their data model before they are produced, so that bad
+//! rows are rejected at the route instead of failing silently inside ClickHouse.
+//!
+//! A record is valid when every required column without a default is present, every value matches
+//! the type and arity of its column, and it doesn't contain fields that aren't part of the model.
+//! All the problems of a record are reported at once.
+
+use schema_ast::ast::FieldArity;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::framework::schema::{Column, ColumnType, Table};
+
+use super::datetime_to_millis;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct FieldError {
+    // Not set when the error is about the record as a whole
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: &str, message: String) -> Self {
+        Self {
+            field: Some(field.to_string()),
+            message,
+        }
+    }
+
+    pub fn record(message: String) -> Self {
+        Self {
+            field: None,
+            message,
+        }
+    }
+}
+
+fn matches_type(column: &Column, value: &Value) -> bool {
+    match column.data_type {
+        ColumnType::String => value.is_string(),
+        ColumnType::Boolean => value.is_boolean(),
+        // Ints are stored as Int64
+        ColumnType::Int => value.as_i64().is_some(),
+        ColumnType::BigInt => value.is_i64() || value.is_u64(),
+        ColumnType::Float | ColumnType::Decimal => value.is_number(),
+        ColumnType::DateTime => datetime_to_millis(&column.name, value).is_ok(),
+        ColumnType::Json => value.is_object(),
+        ColumnType::Bytes | ColumnType::Unsupported => false,
+    }
+}
+
+fn validate_column(column: &Column, value: Option<&Value>, errors: &mut Vec<FieldError>) {
+    let expected = match column.arity {
+        FieldArity::List => format!("{}[]", column.data_type),
+        _ => column.data_type.to_string(),
+    };
+    let invalid = || {
+        FieldError::new(
+            &column.name,
+            format!("expected a value of type {}", expected),
+        )
+    };
+
+    match (column.arity, value) {
+        (FieldArity::Required, None | Some(Value::Null)) => {
+            if column.default.is_none() {
+                errors.push(FieldError::new(&column.name, "is required".to_string()));
+            }
+        }
+        (_, None | Some(Value::Null)) => {}
+        (FieldArity::List, Some(Value::Array(items))) => {
+            if !items.iter().all(|item| matches_type(column, item)) {
+                errors.push(invalid());
+            }
+        }
+        (FieldArity::List, Some(_)) => errors.push(invalid()),
+        (_, Some(value)) => {
+            if !matches_type(column, value) {
+                errors.push(invalid());
+            }
+        }
+    }
+}
+
+pub fn validate_record(data_model: &Table, record: &Value) -> Result<(), Vec<FieldError>> {
+    let fields = match record.as_object() {
+        Some(fields) => fields,
+        None => {
+            return Err(vec![FieldError::record(
+                "expected a JSON object".to_string(),
+            )])
+        }
+    };
+
+    let mut errors = vec![];
+
+    for column in data_model.columns.iter() {
+        validate_column(column, fields.get(&column.name), &mut errors);
+    }
+
+    for field_name in fields.keys() {
+        if !data_model
+            .columns
+            .iter()
+            .any(|column| &column.name == field_name)
+        {
+            errors.push(FieldError::new(
+                field_name,
+                format!("is not part of the {} data model", data_model.name),
+            ));
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use schema_ast::ast::FieldArity;
+    use serde_json::json;
+
+    use crate::{
+        framework::schema::{ColumnDefaults, ColumnType, Table},
+        infrastructure::ingest::tests::table,
+    };
+
+    use super::*;
+
+    fn users() -> Table {
+        table(
+            "Users",
+            &[
+                ("id", ColumnType::Int, FieldArity::Required),
+                ("name", ColumnType::String, FieldArity::Optional),
+                ("tags", ColumnType::String, FieldArity::List),
+                ("signed_up", ColumnType::DateTime, FieldArity::Optional),
+            ],
+        )
+    }
+
+    fn invalid_fields(data_model: &Table, record: Value) -> Vec<Option<String>> {
+        validate_record(data_model, &record)
+            .unwrap_err()
+            .into_iter()
+            .map(|error| error.field)
+            .collect()
+    }
+
+    #[test]
+    fn accepts_a_valid_record() {
+        let record = json!({
+            "id": -1,
+            "name": null,
+            "tags": ["a", "b"],
+            "signed_up": "2024-01-02T03:04:05.678Z",
+        });
+        assert_eq!(validate_record(&users(), &record), Ok(()));
+        assert_eq!(validate_record(&users(), &json!({"id": 1})), Ok(()));
+    }
+
+    #[test]
+    fn rejects_ints_out_of_the_int64_range() {
+        for id in [json!(u64::MAX), json!(1.5), json!("1")] {
+            assert_eq!(
+                invalid_fields(&users(), json!({ "id": id })),
+                vec![Some("id".to_string())]
+            );
+        }
+        assert_eq!(validate_record(&users(), &json!({"id": i64::MAX})), Ok(()));
+    }
+
+    #[test]
+    fn checks_datetimes() {
+        for signed_up in [json!(1_704_164_645_678i64), json!("2024-01-02T03:04:05Z")] {
+            let record = json!({"id": 1, "signed_up": signed_up});
+            assert_eq!(validate_record(&users(), &record), Ok(()));
+        }
+        for signed_up in [json!(-1), json!("yesterday"), json!(true)] {
+            assert_eq!(
+                invalid_fields(&users(), json!({"id": 1, "signed_up": signed_up})),
+                vec![Some("signed_up".to_string())]
+            );
+        }
+    }
+
+    #[test]
+    fn checks_every_item_of_a_list() {
+        assert_eq!(
+            invalid_fields(&users(), json!({"id": 1, "tags": ["a", 1]})),
+            vec![Some("tags".to_string())]
+        );
+        assert_eq!(
+            invalid_fields(&users(), json!({"id": 1, "tags": "a"})),
+            vec![Some("tags".to_string())]
+        );
+    }
+
+    #[test]
+    fn requires_columns_without_a_default() {
+        assert_eq!(
+            invalid_fields(&users(), json!({"id": null})),
+            vec![Some("id".to_string())]
+        );
+
+        let mut data_model = users();
+        data_model.columns[0].default = Some(ColumnDefaults::AutoIncrement);
+        assert_eq!(validate_record(&data_model, &json!({})), Ok(()));
+    }
+
+    #[test]
+    fn reports_every_problem_at_once() {
+        let record = json!({"name": 1, "age": 30});
+        assert_eq!(
+            invalid_fields(&users(), record),
+            vec![
+                Some("id".to_string()),
+                Some("name".to_string()),
+                Some("age".to_string())
+            ]
+        );
+        assert_eq!(invalid_fields(&users(), json!([1])), vec![None]);
+    }
+}
@@ -14,10 +14,15 @@ use crate::framework::schema::{MatViewOps, TableOps, UnsupportedDataTypeError};
 use self::{
     config::ClickhouseConfig,
     queries::{
-        CreateMaterializedViewQuery, CreateTableQuery, DropMaterializedViewQuery, DropTableQuery,
+        column_type_name, CreateMaterializedViewQuery, CreateTableQuery, DropMaterializedViewQuery,
+        DropTableQuery, InsertJsonRowsQuery,
     },
 };
 
+// Set by the views of the data models when a row is inserted, so that new rows can be tailed
+pub const INGESTED_AT_COLUMN: &str = "_ingested_at";
+const INGESTED_AT_TYPE: &str = "DateTime64(6)";
+
 #[derive(Debug, Clone)]
 pub enum ClickhouseTableType {
     Table,
@@ -40,6 +45,8 @@ pub enum ClickhouseColumnType {
     ClickhouseFloat(ClickhouseFloat),
     Decimal,
     DateTime,
+    // With the precision of its sub-second part, in digits
+    DateTime64(u8),
     Json,
     Bytes,
     Unsupported,
@@ -100,12 +107,30 @@ pub struct ClickhouseColumn {
     pub default: Option<ClickhouseColumnDefaults>,
 }
 
+#[derive(Debug, Clone)]
+pub enum ClickhouseInputFormat {
+    JSONEachRow,
+    Avro,
+    ProtobufSingle { format_schema: String },
+}
+
+impl fmt::Display for ClickhouseInputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClickhouseInputFormat::JSONEachRow => write!(f, "JSONEachRow"),
+            ClickhouseInputFormat::Avro => write!(f, "Avro"),
+            ClickhouseInputFormat::ProtobufSingle { .. } => write!(f, "ProtobufSingle"),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ClickhouseTable {
     pub db_name: String,
     pub name: String,
     pub columns: Vec<ClickhouseColumn>,
     pub table_type: ClickhouseTableType,
+    pub input_format: ClickhouseInputFormat,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, clickhouse::Row)]
@@ -145,12 +170,14 @@ impl ClickhouseTable {
         name: String,
         columns: Vec<ClickhouseColumn>,
         table_type: ClickhouseTableType,
+        input_format: ClickhouseInputFormat,
     ) -> ClickhouseTable {
         ClickhouseTable {
             db_name,
             name,
             columns,
             table_type,
+            input_format,
         }
     }
 }
@@ -170,11 +197,22 @@ impl TableOps for ClickhouseTable {
     }
 }
 
+#[derive(Debug, Clone, Default)]
+pub enum ClickhouseViewEngine {
+    #[default]
+    Memory,
+    // Rows with the same key are deduplicated when parts are merged
+    ReplacingMergeTree {
+        order_by: String,
+    },
+}
+
 #[derive(Debug, Clone)]
 pub struct ClickhouseView {
     pub db_name: String,
     pub name: String,
     pub source_table: ClickhouseTable,
+    pub engine: ClickhouseViewEngine,
 }
 
 impl ClickhouseView {
@@ -183,8 +221,63 @@ impl ClickhouseView {
             db_name,
             name,
             source_table,
+            engine: ClickhouseViewEngine::default(),
         }
     }
+
+    pub fn insert_json_rows_query(&self) -> Result<QueryString, UnsupportedDataTypeError> {
+        InsertJsonRowsQuery::build(self.clone())
+    }
+
+    pub fn matches_definition(&self, definition: &ViewDefinition) -> bool {
+        //! Whether a view that already exists has the engine and the columns this view would be
+        //! created with, in which case it doesn't have to be recreated.
+        let engine_matches = match &self.engine {
+            ClickhouseViewEngine::Memory => definition.engine == "Memory",
+            ClickhouseViewEngine::ReplacingMergeTree { order_by } => {
+                definition.engine == "ReplacingMergeTree"
+                    && definition.sorting_key.trim_matches('`') == order_by
+            }
+        };
+
+        let columns = self
+            .source_table
+            .columns
+            .iter()
+            .map(|column| {
+                Ok((
+                    column.name.clone(),
+                    reported_type_name(&column_type_name(column)?),
+                ))
+            })
+            .chain([Ok((
+                INGESTED_AT_COLUMN.to_string(),
+                INGESTED_AT_TYPE.to_string(),
+            ))])
+            .collect::<Result<Vec<(String, String)>, UnsupportedDataTypeError>>();
+
+        match columns {
+            Ok(columns) => engine_matches && columns == definition.columns,
+            // A view that can't be created can't match either
+            Err(_) => false,
+        }
+    }
+}
+
+fn reported_type_name(type_name: &str) -> String {
+    //! The name `system.columns` reports for a type, which isn't always the one it was created with.
+    type_name
+        .replace("Boolean", "Bool")
+        .replace("Decimal", "Decimal(10, 0)")
+}
+
+#[derive(Debug, Clone)]
+pub struct ViewDefinition {
+    // The engine and the sorting key of the table that stores the rows of the view
+    pub engine: String,
+    pub sorting_key: String,
+    // Names and types, in order
+    pub columns: Vec<(String, String)>,
 }
 
 pub type QueryString = String;
@@ -227,6 +320,103 @@ pub async fn run_query(
     client.query(query.as_str()).execute().await
 }
 
+#[derive(Debug, Clone, Deserialize, clickhouse::Row)]
+struct ViewStorageRow {
+    engine: String,
+    sorting_key: String,
+}
+
+#[derive(Debug, Clone, Deserialize, clickhouse::Row)]
+struct ViewColumnRow {
+    name: String,
+    #[serde(rename = "type")]
+    column_type: String,
+}
+
+pub async fn fetch_view_definition(
+    view_name: &str,
+    configured_client: &ConfiguredDBClient,
+) -> Result<Option<ViewDefinition>, clickhouse::error::Error> {
+    //! Reads how an existing view was created, `None` when there's no such view.
+    let client = &configured_client.client;
+    let db_name = &configured_client.config.db_name;
+
+    let uuid = client
+        .query("SELECT toString(uuid) FROM system.tables WHERE database = ? AND name = ?")
+        .bind(db_name)
+        .bind(view_name)
+        .fetch_optional::<String>()
+        .await?;
+
+    let uuid = match uuid {
+        Some(uuid) => uuid,
+        None => return Ok(None),
+    };
+
+    // The rows of a materialized view are stored in an inner table, named after the uuid of the
+    // view in Atomic databases and after its name in Ordinary ones
+    let storage = client
+        .query(
+            "SELECT engine, sorting_key FROM system.tables WHERE database = ? AND name IN (?, ?)",
+        )
+        .bind(db_name)
+        .bind(format!(".inner_id.{}", uuid))
+        .bind(format!(".inner.{}", view_name))
+        .fetch_optional::<ViewStorageRow>()
+        .await?
+        .unwrap_or(ViewStorageRow {
+            engine: String::new(),
+            sorting_key: String::new(),
+        });
+
+    let columns = client
+        .query(
+            "SELECT name, type FROM system.columns WHERE database = ? AND table = ? ORDER BY position",
+        )
+        .bind(db_name)
+        .bind(view_name)
+        .fetch_all::<ViewColumnRow>()
+        .await?;
+
+    Ok(Some(ViewDefinition {
+        engine: storage.engine,
+        sorting_key: storage.sorting_key,
+        columns: columns
+            .into_iter()
+            .map(|column| (column.name, column.column_type))
+            .collect(),
+    }))
+}
+
+fn insert_json_rows_body(query: &str, rows: &[serde_json::Value]) -> String {
+    //! Appends the rows to the query as JSON lines. ClickHouse streams the data that follows
+    //! `FORMAT`, so it doesn't count towards `max_query_size`.
+    let mut body = query.trim_end().to_string();
+    for row in rows {
+        body.push('\n');
+        // The client would take a '?' for a query argument, a JSON escape reads the same
+        body.push_str(&row.to_string().replace('?', "\\u003f"));
+    }
+    body
+}
+
+pub async fn insert_json_rows(
+    query: QueryString,
+    rows: &[serde_json::Value],
+    configured_client: &ConfiguredDBClient,
+) -> Result<(), clickhouse::error::Error> {
+    //! Runs a query built by `insert_json_rows_query` with the rows sent as JSON lines.
+    debug!("Inserting {} rows with query: {:?}", rows.len(), query);
+    let client = configured_client
+        .client
+        .clone()
+        .with_option("date_time_input_format", "best_effort");
+    client
+        .query(&insert_json_rows_body(&query, rows))
+        .execute()
+        .await
+}
+
 pub async fn fetch_all_tables(
     configured_client: &ConfiguredDBClient,
 ) -> Result<Vec<ClickhouseSystemTable>, clickhouse::error::Error> {
@@ -256,6 +446,79 @@ pub async fn fetch_all_tables(
     Ok(tables)
 }
 
+#[derive(Debug, Clone, Deserialize, clickhouse::Row)]
+struct IngestedRow {
+    ingested_at: i64,
+    row: String,
+}
+
+fn quote_identifier(identifier: &str) -> String {
+    format!("`{}`", identifier.replace('\\', "\\\\").replace('`', "\\`"))
+}
+
+pub async fn latest_ingested_at(
+    view_name: &str,
+    configured_client: &ConfiguredDBClient,
+) -> Result<i64, clickhouse::error::Error> {
+    //! Returns when the last row of the view was inserted, in microseconds since the epoch, or 0
+    //! for an empty view.
+    let client = &configured_client.client;
+    let db_name = quote_identifier(&configured_client.config.db_name);
+    let view_name = quote_identifier(view_name);
+
+    client
+        .query(
+            format!(
+                "SELECT toUnixTimestamp64Micro(max({INGESTED_AT_COLUMN})) FROM {db_name}.{view_name}"
+            )
+            .as_str(),
+        )
+        .fetch_one::<i64>()
+        .await
+}
+
+pub async fn fetch_rows_ingested_after(
+    view_name: &str,
+    ingested_after: i64,
+    limit: u64,
+    configured_client: &ConfiguredDBClient,
+) -> Result<Vec<(i64, serde_json::Value)>, clickhouse::error::Error> {
+    //! Fetches the rows inserted after the given time in microseconds, in the order they were
+    //! inserted, along with their insertion time. Each row is rendered by ClickHouse as a JSON
+    //! object without the insertion time column.
+    //!
+    //! Rows inserted at the same time are returned together, even past the limit, so that the
+    //! next page can start after the last insertion time.
+    let client = &configured_client.client;
+    let db_name = quote_identifier(&configured_client.config.db_name);
+    let quoted_view_name = quote_identifier(view_name);
+
+    let query = format!(
+        "SELECT toUnixTimestamp64Micro({INGESTED_AT_COLUMN}) AS ingested_at, formatRow('JSONEachRow', *) AS row \
+        FROM {db_name}.{quoted_view_name} \
+        WHERE {INGESTED_AT_COLUMN} > fromUnixTimestamp64Micro(toInt64({ingested_after})) \
+        ORDER BY {INGESTED_AT_COLUMN} \
+        LIMIT {limit} WITH TIES"
+    );
+
+    let mut cursor = client.query(query.as_str()).fetch::<IngestedRow>()?;
+
+    let mut rows = vec![];
+    while let Some(ingested_row) = cursor.next().await? {
+        match serde_json::from_str::<serde_json::Value>(ingested_row.row.trim()) {
+            Ok(mut row) => {
+                if let Some(object) = row.as_object_mut() {
+                    object.remove(INGESTED_AT_COLUMN);
+                }
+                rows.push((ingested_row.ingested_at, row));
+            }
+            Err(e) => debug!("Failed to parse row from {}: {}", view_name, e),
+        }
+    }
+
+    Ok(rows)
+}
+
 pub async fn delete_table_or_view(
     table_or_view_name: String,
     configured_client: &ConfiguredDBClient,
@@ -268,3 +531,207 @@ pub async fn delete_table_or_view(
         .execute()
         .await
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn insert_json_rows_body_keeps_rows_out_of_the_query() {
+        let query = "\nINSERT INTO local.Users_view\nSELECT * FROM input('id String')\nFORMAT JSONEachRow\n";
+        // Well past the default max_query_size of 256 KiB
+        let rows = (0..4096)
+            .map(|i| json!({ "id": format!("{}?{}", i, "x".repeat(100)) }))
+            .collect::<Vec<serde_json::Value>>();
+
+        let body = insert_json_rows_body(query, &rows);
+        assert!(body.len() > 256 * 1024);
+        assert!(!body.contains('?'));
+
+        let mut lines = body.lines();
+        let head = lines.by_ref().take(4).collect::<Vec<&str>>().join("\n");
+        assert_eq!(head, query.trim_end());
+
+        let parsed = lines
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect::<Vec<serde_json::Value>>();
+        assert_eq!(parsed, rows);
+    }
+
+    fn users_view(engine: ClickhouseViewEngine) -> ClickhouseView {
+        users_view_with(engine, ClickhouseColumnType::String, FieldArity::Required)
+    }
+
+    fn users_view_with(
+        engine: ClickhouseViewEngine,
+        name_type: ClickhouseColumnType,
+        name_arity: FieldArity,
+    ) -> ClickhouseView {
+        let column = |name: &str, column_type, arity| ClickhouseColumn {
+            name: name.to_string(),
+            column_type,
+            arity,
+            unique: false,
+            primary_key: false,
+            default: None,
+        };
+        let mut view = ClickhouseView::new(
+            "local".to_string(),
+            "Users_view".to_string(),
+            ClickhouseTable {
+                db_name: "local".to_string(),
+                name: "Users".to_string(),
+                columns: vec![
+                    column("id", ClickhouseColumnType::String, FieldArity::Required),
+                    column("name", name_type, name_arity),
+                ],
+                table_type: ClickhouseTableType::Table,
+                input_format: ClickhouseInputFormat::JSONEachRow,
+            },
+        );
+        view.engine = engine;
+        view
+    }
+
+    fn definition(engine: &str, sorting_key: &str, columns: &[(&str, &str)]) -> ViewDefinition {
+        ViewDefinition {
+            engine: engine.to_string(),
+            sorting_key: sorting_key.to_string(),
+            columns: columns
+                .iter()
+                .map(|(name, column_type)| (name.to_string(), column_type.to_string()))
+                .collect(),
+        }
+    }
+
+    const COLUMNS: [(&str, &str); 3] = [
+        ("id", "String"),
+        ("name", "String"),
+        (INGESTED_AT_COLUMN, INGESTED_AT_TYPE),
+    ];
+
+    #[test]
+    fn matches_views_created_with_the_same_engine() {
+        let rmt = ClickhouseViewEngine::ReplacingMergeTree {
+            order_by: "id".to_string(),
+        };
+
+        assert!(users_view(ClickhouseViewEngine::Memory)
+            .matches_definition(&definition("Memory", "", &COLUMNS)));
+        assert!(users_view(rmt.clone()).matches_definition(&definition(
+            "ReplacingMergeTree",
+            "id",
+            &COLUMNS
+        )));
+        assert!(users_view(rmt.clone()).matches_definition(&definition(
+            "ReplacingMergeTree",
+            "`id`",
+            &COLUMNS
+        )));
+
+        // The engine or the dedup field changed
+        assert!(!users_view(rmt).matches_definition(&definition("Memory", "", &COLUMNS)));
+        assert!(!users_view(ClickhouseViewEngine::ReplacingMergeTree {
+            order_by: "name".to_string(),
+        })
+        .matches_definition(&definition("ReplacingMergeTree", "id", &COLUMNS)));
+        assert!(
+            !users_view(ClickhouseViewEngine::Memory).matches_definition(&definition(
+                "ReplacingMergeTree",
+                "id",
+                &COLUMNS
+            ))
+        );
+        // The inner table couldn't be found
+        assert!(!users_view(ClickhouseViewEngine::Memory)
+            .matches_definition(&definition("", "", &COLUMNS)));
+    }
+
+    #[test]
+    fn matches_views_created_with_the_same_columns() {
+        let memory = ClickhouseViewEngine::Memory;
+
+        // The columns changed, or the view predates the insertion time column
+        assert!(!users_view(memory.clone()).matches_definition(&definition(
+            "Memory",
+            "",
+            &[("id", "String"), (INGESTED_AT_COLUMN, INGESTED_AT_TYPE)]
+        )));
+        assert!(!users_view(memory.clone()).matches_definition(&definition(
+            "Memory",
+            "",
+            &COLUMNS[..2]
+        )));
+
+        // A column changed its type or its arity
+        let int = ClickhouseColumnType::ClickhouseInt(ClickhouseInt::Int64);
+        assert!(
+            !users_view_with(memory.clone(), int.clone(), FieldArity::Required)
+                .matches_definition(&definition("Memory", "", &COLUMNS))
+        );
+        assert!(!users_view_with(
+            memory.clone(),
+            ClickhouseColumnType::String,
+            FieldArity::Optional
+        )
+        .matches_definition(&definition("Memory", "", &COLUMNS)));
+        assert!(!users_view_with(
+            memory.clone(),
+            ClickhouseColumnType::DateTime64(3),
+            FieldArity::Required
+        )
+        .matches_definition(&definition(
+            "Memory",
+            "",
+            &[
+                ("id", "String"),
+                ("name", "DateTime"),
+                (INGESTED_AT_COLUMN, INGESTED_AT_TYPE)
+            ]
+        )));
+
+        // Types are compared by the names ClickHouse reports for them
+        for (column_type, arity, reported) in [
+            (int, FieldArity::Required, "Int64"),
+            (
+                ClickhouseColumnType::Boolean,
+                FieldArity::Optional,
+                "Nullable(Bool)",
+            ),
+            (
+                ClickhouseColumnType::Decimal,
+                FieldArity::Required,
+                "Decimal(10, 0)",
+            ),
+            (
+                ClickhouseColumnType::DateTime64(3),
+                FieldArity::List,
+                "Array(DateTime64(3))",
+            ),
+        ] {
+            assert!(
+                users_view_with(memory.clone(), column_type, arity).matches_definition(
+                    &definition(
+                        "Memory",
+                        "",
+                        &[
+                            ("id", "String"),
+                            ("name", reported),
+                            (INGESTED_AT_COLUMN, INGESTED_AT_TYPE)
+                        ]
+                    )
+                ),
+                "{}",
+                reported
+            );
+        }
+    }
+
+    #[test]
+    fn insert_json_rows_body_without_rows_is_the_query() {
+        let query = "INSERT INTO local.Users_view FORMAT JSONEachRow\n";
+        assert_eq!(insert_json_rows_body(query, &[]), query.trim_end());
+    }
+}
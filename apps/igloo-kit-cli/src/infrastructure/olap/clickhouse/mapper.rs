@@ -1,8 +1,11 @@
 use crate::{
     framework::schema::{ColumnType, Table, TableType},
-    infrastructure::olap::clickhouse::{
-        ClickhouseColumn, ClickhouseColumnType, ClickhouseFloat, ClickhouseInt, ClickhouseTable,
-        ClickhouseTableType,
+    infrastructure::{
+        ingest::{protobuf::ProtoSchema, WireFormat},
+        olap::clickhouse::{
+            ClickhouseColumn, ClickhouseColumnType, ClickhouseFloat, ClickhouseInputFormat,
+            ClickhouseInt, ClickhouseTable, ClickhouseTableType,
+        },
     },
 };
 
@@ -13,27 +16,52 @@ pub fn clickhouse_table_type_mapper(table_type: TableType) -> ClickhouseTableTyp
     }
 }
 
-pub fn std_field_type_to_clickhouse_type_mapper(field_type: ColumnType) -> ClickhouseColumnType {
+pub fn std_field_type_to_clickhouse_type_mapper(
+    field_type: ColumnType,
+    wire_format: WireFormat,
+) -> ClickhouseColumnType {
     match field_type {
         ColumnType::String => ClickhouseColumnType::String,
         ColumnType::Boolean => ClickhouseColumnType::Boolean,
         ColumnType::Int => ClickhouseColumnType::ClickhouseInt(ClickhouseInt::Int64),
         ColumnType::Float => ClickhouseColumnType::ClickhouseFloat(ClickhouseFloat::Float64),
         ColumnType::Decimal => ClickhouseColumnType::Decimal,
-        ColumnType::DateTime => ClickhouseColumnType::DateTime,
+        // Avro and Protobuf carry DateTimes as milliseconds since the epoch, JSON keeps the second
+        // precision its tables have always had
+        ColumnType::DateTime => match wire_format {
+            WireFormat::Json => ClickhouseColumnType::DateTime,
+            WireFormat::Avro | WireFormat::Protobuf => ClickhouseColumnType::DateTime64(3),
+        },
         ColumnType::Unsupported => ClickhouseColumnType::Unsupported,
         _ => ClickhouseColumnType::Unsupported,
     }
 }
 
-pub fn std_table_to_clickhouse_table(table: Table) -> ClickhouseTable {
+pub fn wire_format_to_clickhouse_input_format(
+    wire_format: WireFormat,
+    table: &Table,
+) -> ClickhouseInputFormat {
+    match wire_format {
+        WireFormat::Json => ClickhouseInputFormat::JSONEachRow,
+        WireFormat::Avro => ClickhouseInputFormat::Avro,
+        WireFormat::Protobuf => ClickhouseInputFormat::ProtobufSingle {
+            format_schema: ProtoSchema::format_schema(table),
+        },
+    }
+}
+
+pub fn std_table_to_clickhouse_table(table: Table, wire_format: WireFormat) -> ClickhouseTable {
+    let input_format = wire_format_to_clickhouse_input_format(wire_format, &table);
     let columns = table
         .columns
         .into_iter()
         .map(|column| {
             ClickhouseColumn {
                 name: column.name,
-                column_type: std_field_type_to_clickhouse_type_mapper(column.data_type),
+                column_type: std_field_type_to_clickhouse_type_mapper(
+                    column.data_type,
+                    wire_format,
+                ),
                 arity: column.arity,
                 unique: column.unique,
                 primary_key: column.primary_key,
@@ -47,5 +75,41 @@ pub fn std_table_to_clickhouse_table(table: Table) -> ClickhouseTable {
         name: table.name,
         columns,
         table_type: clickhouse_table_type_mapper(table.table_type),
+        input_format,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use schema_ast::ast::FieldArity;
+
+    use crate::infrastructure::ingest::tests::table;
+
+    use super::*;
+
+    #[test]
+    fn maps_datetimes_to_the_precision_of_the_wire_format() {
+        let data_model = table(
+            "Event",
+            &[("at", ColumnType::DateTime, FieldArity::Required)],
+        );
+
+        let json = std_table_to_clickhouse_table(data_model.clone(), WireFormat::Json);
+        assert!(matches!(
+            json.columns[0].column_type,
+            ClickhouseColumnType::DateTime
+        ));
+        assert!(matches!(
+            json.input_format,
+            ClickhouseInputFormat::JSONEachRow
+        ));
+
+        for wire_format in [WireFormat::Avro, WireFormat::Protobuf] {
+            let clickhouse_table = std_table_to_clickhouse_table(data_model.clone(), wire_format);
+            assert!(matches!(
+                clickhouse_table.columns[0].column_type,
+                ClickhouseColumnType::DateTime64(3)
+            ));
+        }
     }
 }
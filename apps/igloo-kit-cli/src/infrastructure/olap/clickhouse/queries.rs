@@ -5,11 +5,12 @@ use tinytemplate::TinyTemplate;
 use crate::{
     framework::schema::UnsupportedDataTypeError,
     infrastructure::olap::clickhouse::{
-        ClickhouseColumn, ClickhouseColumnType, ClickhouseFloat, ClickhouseInt, ClickhouseTable,
+        ClickhouseColumn, ClickhouseColumnType, ClickhouseFloat, ClickhouseInputFormat,
+        ClickhouseInt, ClickhouseTable,
     },
 };
 
-use super::ClickhouseView;
+use super::{ClickhouseView, ClickhouseViewEngine, INGESTED_AT_COLUMN};
 
 // TODO: Add column comment capability to the schemna and template
 pub static CREATE_TABLE_TEMPLATE: &str = r#"
@@ -21,7 +22,8 @@ CREATE TABLE IF NOT EXISTS {db_name}.{table_name}
 PRIMARY KEY ({primary_key_string})
 {{endif}}
 )
-ENGINE = Kafka('{cluster_network}:{kafka_port}', '{topic}', 'clickhouse-group', 'JSONEachRow');
+ENGINE = Kafka('{cluster_network}:{kafka_port}', '{topic}', 'clickhouse-group', '{input_format}')
+SETTINGS date_time_input_format = 'best_effort'{{if format_schema}}, kafka_schema = '{format_schema}'{{endif}};
 "#;
 
 pub struct CreateTableQuery;
@@ -51,6 +53,8 @@ struct CreateTableContext {
     cluster_network: String,
     kafka_port: u16,
     topic: String,
+    input_format: String,
+    format_schema: Option<String>,
 }
 
 impl CreateTableContext {
@@ -67,7 +71,14 @@ impl CreateTableContext {
             .map(|column| column.name.clone())
             .collect::<Vec<String>>();
 
+        let format_schema = match &table.input_format {
+            ClickhouseInputFormat::ProtobufSingle { format_schema } => Some(format_schema.clone()),
+            _ => None,
+        };
+
         Ok(CreateTableContext {
+            input_format: table.input_format.to_string(),
+            format_schema,
             db_name: table.db_name,
             table_name: table.name,
             fields: table
@@ -133,9 +144,9 @@ impl DropTableContext {
 
 pub static CREATE_MATERIALIZED_VIEW_TEMPLATE: &str = r#"
 CREATE MATERIALIZED VIEW IF NOT EXISTS {db_name}.{view_name} 
-ENGINE = Memory
+{{if order_by}}ENGINE = ReplacingMergeTree ORDER BY ({order_by}) SETTINGS allow_nullable_key = 1{{else}}ENGINE = Memory{{endif}}
 AS
-SELECT * FROM {db_name}.{source_table_name}
+SELECT *, now64(6) AS {ingested_at_column} FROM {db_name}.{source_table_name}
 SETTINGS
 stream_like_engine_allow_direct_select = 1;
 "#;
@@ -183,7 +194,7 @@ impl DropMaterializedViewContext {
     fn new(view: ClickhouseView) -> Result<DropMaterializedViewContext, UnsupportedDataTypeError> {
         Ok(DropMaterializedViewContext {
             db_name: view.db_name,
-            view_name: format!("{}_view", view.name),
+            view_name: view.name,
         })
     }
 }
@@ -193,6 +204,8 @@ struct CreateMaterializedViewContext {
     db_name: String,
     view_name: String,
     source_table_name: String,
+    order_by: Option<String>,
+    ingested_at_column: &'static str,
 }
 
 impl CreateMaterializedViewContext {
@@ -203,6 +216,56 @@ impl CreateMaterializedViewContext {
             db_name: view.db_name,
             view_name: view.name,
             source_table_name: view.source_table.name,
+            order_by: match view.engine {
+                ClickhouseViewEngine::Memory => None,
+                ClickhouseViewEngine::ReplacingMergeTree { order_by } => Some(order_by),
+            },
+            ingested_at_column: INGESTED_AT_COLUMN,
+        })
+    }
+}
+
+pub static INSERT_JSON_ROWS_TEMPLATE: &str = r#"
+INSERT INTO {db_name}.{view_name}
+SELECT *, now64(6) AS {ingested_at_column} FROM input('{structure}')
+FORMAT JSONEachRow
+"#;
+
+pub struct InsertJsonRowsQuery;
+
+impl InsertJsonRowsQuery {
+    pub fn build(view: ClickhouseView) -> Result<String, UnsupportedDataTypeError> {
+        //! The rows follow the query in the body of the request, one JSON object per line.
+        let mut tt = TinyTemplate::new();
+        tt.add_template("insert_json_rows", INSERT_JSON_ROWS_TEMPLATE)
+            .unwrap();
+        let context = InsertJsonRowsContext::new(view)?;
+        let rendered = tt.render("insert_json_rows", &context).unwrap();
+        Ok(rendered)
+    }
+}
+
+#[derive(Serialize)]
+struct InsertJsonRowsContext {
+    db_name: String,
+    view_name: String,
+    structure: String,
+    ingested_at_column: &'static str,
+}
+
+impl InsertJsonRowsContext {
+    fn new(view: ClickhouseView) -> Result<InsertJsonRowsContext, UnsupportedDataTypeError> {
+        Ok(InsertJsonRowsContext {
+            db_name: view.db_name,
+            view_name: view.name,
+            structure: view
+                .source_table
+                .columns
+                .into_iter()
+                .map(clickhouse_column_to_structure)
+                .collect::<Result<Vec<String>, UnsupportedDataTypeError>>()?
+                .join(", "),
+            ingested_at_column: INGESTED_AT_COLUMN,
         })
     }
 }
@@ -234,6 +297,7 @@ fn field_type_to_string(
         },
         ClickhouseColumnType::Decimal => Ok(field_type.to_string()),
         ClickhouseColumnType::DateTime => Ok(field_type.to_string()),
+        ClickhouseColumnType::DateTime64(_) => Ok(field_type.to_string()),
         _ => Err(UnsupportedDataTypeError {
             type_name: field_type.to_string(),
         }),
@@ -265,3 +329,19 @@ fn clickhouse_column_to_create_table_field_context(
         })
     }
 }
+
+pub fn column_type_name(column: &ClickhouseColumn) -> Result<String, UnsupportedDataTypeError> {
+    //! The type of the column as rows are selected from its table, with its arity.
+    let field_type = field_type_to_string(column.column_type.clone())?;
+    Ok(match column.arity {
+        FieldArity::Required => field_type,
+        FieldArity::Optional => format!("Nullable({})", field_type),
+        FieldArity::List => format!("Array({})", field_type),
+    })
+}
+
+fn clickhouse_column_to_structure(
+    column: ClickhouseColumn,
+) -> Result<String, UnsupportedDataTypeError> {
+    Ok(format!("{} {}", column.name, column_type_name(&column)?))
+}
@@ -1,2 +1,154 @@
+//! # Stream
+//! The streaming layer that carries records from the ingest routes to the flows and to the OLAP
+//! storage. Everything that runs during `igloo dev` goes through the `StreamingBackend` trait so
+//! the engine behind it can be swapped in the `project.toml`:
+//!
+//! ```toml
+//! [stream_config]
+//! engine = "local"
+//! ```
+//!
+//! - `redpanda` (default) produces to the Redpanda container, which ClickHouse reads with its Kafka
+//! engine.
+//! - `local` keeps every topic as an append-only log in `.igloo/streams` and runs without Docker.
+//! ClickHouse can't read those logs, so the local engine doesn't create any tables.
+//!
+//! The producer of the `redpanda` engine is tuned in `[redpanda_config]`, with `acks`,
+//! `linger_ms`, `batch_size`, `compression`, `enable_idempotence` and `retries`. Any other
+//! librdkafka setting can be passed in `[redpanda_config.producer_config]`.
+//!
+//! Either engine can be wrapped with a spool on disk that keeps the records the stream fails to
+//! take while it's unavailable, see `spool`.
+//!
+//! ## Suggested Improvements
+//! - support `igloo tail` and `igloo replay` with the local engine
+//! - sink the local logs into an embedded OLAP engine
+
+pub mod local;
 pub mod redpanda;
 pub mod rpk;
+pub mod spool;
+
+use std::{
+    io::{Error, ErrorKind},
+    sync::Arc,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use crate::project::Project;
+
+use self::spool::{SpoolConfig, SpoolStatus};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StreamingEngine {
+    #[default]
+    Redpanda,
+    Local,
+}
+
+impl StreamingEngine {
+    pub fn feeds_clickhouse(&self) -> bool {
+        //! Whether ClickHouse can consume the topics of this engine.
+        matches!(self, StreamingEngine::Redpanda)
+    }
+
+    pub fn requires_docker(&self) -> bool {
+        matches!(self, StreamingEngine::Redpanda)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct StreamConfig {
+    #[serde(default)]
+    pub engine: StreamingEngine,
+    #[serde(default)]
+    pub spool: SpoolConfig,
+}
+
+#[derive(Debug, Clone)]
+pub struct StreamRecord {
+    pub partition: i32,
+    pub offset: i64,
+    pub payload: Vec<u8>,
+}
+
+#[async_trait]
+pub trait StreamingBackend: Send + Sync {
+    async fn create_topic(&self, topic: &str) -> Result<(), Error>;
+
+    async fn delete_topic(&self, topic: &str) -> Result<(), Error>;
+
+    async fn list_topics(&self) -> Result<Vec<String>, Error>;
+
+    async fn produce(&self, topic: &str, key: &str, payload: &[u8]) -> Result<(), Error>;
+
+    // Produces the records together and returns the outcome of each of them, in order
+    async fn produce_batch(
+        &self,
+        topic: &str,
+        key: &str,
+        payloads: &[Vec<u8>],
+    ) -> Vec<Result<(), Error>>;
+
+    // Waits for the records produced so far to be acknowledged, so that none are lost on shutdown
+    async fn flush(&self, timeout: Duration) -> Result<(), Error>;
+
+    // Consumes the topic in a consumer group. New groups start from the earliest record, and
+    // groups resume after the last record they committed.
+    async fn subscribe(
+        &self,
+        topic: &str,
+        group_id: &str,
+    ) -> Result<Box<dyn StreamSubscription>, Error>;
+
+    // The records waiting to be produced again, when the backend spools the ones that fail
+    async fn spool_status(&self) -> Option<SpoolStatus> {
+        None
+    }
+}
+
+#[async_trait]
+pub trait StreamSubscription: Send {
+    // Waits for the next record of the topic
+    async fn recv(&mut self) -> Result<StreamRecord, Error>;
+
+    // Marks the record, and every record received before it, as processed by the group
+    async fn commit(&mut self, record: &StreamRecord) -> Result<(), Error>;
+}
+
+pub fn is_retryable(e: &Error) -> bool {
+    //! Whether a streaming error is transient, like a timeout or a full producer queue.
+    matches!(
+        e.kind(),
+        ErrorKind::TimedOut
+            | ErrorKind::WouldBlock
+            | ErrorKind::NotConnected
+            | ErrorKind::Interrupted
+    )
+}
+
+pub fn create_streaming_backend(project: &Project) -> Result<Arc<dyn StreamingBackend>, Error> {
+    let backend: Arc<dyn StreamingBackend> = match project.stream_config.engine {
+        StreamingEngine::Redpanda => Arc::new(redpanda::RedpandaBackend::new(
+            project.redpanda_config.clone(),
+        )?),
+        StreamingEngine::Local => Arc::new(local::LocalBackend::new(
+            project.internal_dir()?.join("streams"),
+        )?),
+    };
+
+    if !project.stream_config.spool.enabled {
+        return Ok(backend);
+    }
+    let mut spooling_backend = spool::SpoolingBackend::new(
+        backend,
+        project.internal_dir()?.join("spool"),
+        &project.stream_config.spool,
+    )?;
+    spooling_backend.start();
+    Ok(Arc::new(spooling_backend))
+}
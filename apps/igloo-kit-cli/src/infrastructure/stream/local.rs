@@ -0,0 +1,445 @@
+//! # Local
+//! An embedded streaming engine for development without Docker. Every topic is a single partition
+//! kept as an append-only log in `.igloo/streams/<topic>.log`, and the offset of every consumer
+//! group is saved in `.igloo/streams/groups/<group>/<topic>` when it commits a record.
+//!
+//! Each record in the log is laid out as:
+//!
+//! ```text
+//! timestamp (i64 LE) | key length (u32 LE) | key | payload length (u32 LE) | payload
+//! ```
+//!
+//! Writers keep every log open for appending, and subscriptions keep reading from the same handle
+//! until they catch up with the end of the log.
+//!
+//! ## Suggested Improvements
+//! - truncate logs once every consumer group moved past their first records
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{Error, ErrorKind, SeekFrom},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader},
+    sync::{Mutex, Notify},
+};
+
+use super::{StreamRecord, StreamSubscription, StreamingBackend};
+
+const LOG_EXTENSION: &str = "log";
+// Subscriptions also poll the log in case another process appended to it
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+pub struct LocalBackend {
+    dir: PathBuf,
+    // Logs opened for appending, by topic. Appends are serialized by the lock so that records
+    // never interleave in the log
+    writers: Mutex<HashMap<String, File>>,
+    new_records: Arc<Notify>,
+}
+
+impl LocalBackend {
+    pub fn new(dir: PathBuf) -> Result<Self, Error> {
+        std::fs::create_dir_all(dir.join("groups"))?;
+        Ok(Self {
+            dir,
+            writers: Mutex::new(HashMap::new()),
+            new_records: Arc::new(Notify::new()),
+        })
+    }
+
+    fn topic_path(&self, topic: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", topic, LOG_EXTENSION))
+    }
+
+    async fn append(&self, topic: &str, key: &str, payloads: &[&[u8]]) -> Result<(), Error> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since_epoch| since_epoch.as_millis() as i64)
+            .unwrap_or_default();
+
+        let buf = payloads
+            .iter()
+            .flat_map(|payload| encode_record(timestamp, key.as_bytes(), payload))
+            .collect::<Vec<u8>>();
+
+        {
+            let mut writers = self.writers.lock().await;
+            if !writers.contains_key(topic) {
+                let file = OpenOptions::new()
+                    .append(true)
+                    .open(self.topic_path(topic))
+                    .await
+                    .map_err(|e| match e.kind() {
+                        ErrorKind::NotFound => Error::new(
+                            ErrorKind::NotFound,
+                            format!("Topic {} does not exist", topic),
+                        ),
+                        _ => e,
+                    })?;
+                writers.insert(topic.to_string(), file);
+            }
+
+            if let Some(file) = writers.get_mut(topic) {
+                file.write_all(&buf).await?;
+                // Waits for the write to land in the log before subscriptions are woken up
+                file.flush().await?;
+            }
+        }
+
+        self.new_records.notify_waiters();
+        Ok(())
+    }
+}
+
+fn encode_record(timestamp: i64, key: &[u8], payload: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(16 + key.len() + payload.len());
+    buf.extend_from_slice(&timestamp.to_le_bytes());
+    buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    buf.extend_from_slice(key);
+    buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buf.extend_from_slice(payload);
+    buf
+}
+
+async fn read_bytes(reader: &mut (impl AsyncRead + Unpin)) -> Result<Vec<u8>, Error> {
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len).await?;
+    let mut bytes = vec![0u8; u32::from_le_bytes(len) as usize];
+    reader.read_exact(&mut bytes).await?;
+    Ok(bytes)
+}
+
+async fn read_record(reader: &mut (impl AsyncRead + Unpin)) -> Result<(u64, Vec<u8>), Error> {
+    //! Reads the payload of the next record along with the length of the whole record.
+    let mut timestamp = [0u8; 8];
+    reader.read_exact(&mut timestamp).await?;
+    let key = read_bytes(reader).await?;
+    let payload = read_bytes(reader).await?;
+    Ok(((16 + key.len() + payload.len()) as u64, payload))
+}
+
+#[async_trait]
+impl StreamingBackend for LocalBackend {
+    async fn create_topic(&self, topic: &str) -> Result<(), Error> {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.topic_path(topic))
+            .await
+            .map(|_| ())
+    }
+
+    async fn delete_topic(&self, topic: &str) -> Result<(), Error> {
+        self.writers.lock().await.remove(topic);
+        match tokio::fs::remove_file(self.topic_path(topic)).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    async fn list_topics(&self) -> Result<Vec<String>, Error> {
+        let mut topics = vec![];
+        let mut entries = tokio::fs::read_dir(&self.dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().map_or(false, |ext| ext == LOG_EXTENSION) {
+                if let Some(topic) = path.file_stem().and_then(|stem| stem.to_str()) {
+                    topics.push(topic.to_string());
+                }
+            }
+        }
+        Ok(topics)
+    }
+
+    async fn produce(&self, topic: &str, key: &str, payload: &[u8]) -> Result<(), Error> {
+        self.append(topic, key, &[payload]).await
+    }
+
+    async fn produce_batch(
+        &self,
+        topic: &str,
+        key: &str,
+        payloads: &[Vec<u8>],
+    ) -> Vec<Result<(), Error>> {
+        let slices = payloads
+            .iter()
+            .map(|payload| payload.as_slice())
+            .collect::<Vec<&[u8]>>();
+
+        match self.append(topic, key, &slices).await {
+            Ok(()) => payloads.iter().map(|_| Ok(())).collect(),
+            // The batch is written at once, so it fails as a whole
+            Err(e) => payloads
+                .iter()
+                .map(|_| Err(Error::new(e.kind(), e.to_string())))
+                .collect(),
+        }
+    }
+
+    async fn flush(&self, _timeout: Duration) -> Result<(), Error> {
+        // Records are written to the log before `produce` returns
+        Ok(())
+    }
+
+    async fn subscribe(
+        &self,
+        topic: &str,
+        group_id: &str,
+    ) -> Result<Box<dyn StreamSubscription>, Error> {
+        let group_dir = self.dir.join("groups").join(group_id);
+        tokio::fs::create_dir_all(&group_dir).await?;
+
+        let mut subscription = LocalSubscription {
+            log_path: self.topic_path(topic),
+            offset_path: group_dir.join(topic),
+            reader: None,
+            offset: 0,
+            position: 0,
+            received: VecDeque::new(),
+            new_records: Arc::clone(&self.new_records),
+        };
+        subscription.load_offset().await;
+
+        Ok(Box::new(subscription))
+    }
+}
+
+struct LocalSubscription {
+    log_path: PathBuf,
+    offset_path: PathBuf,
+    // Positioned at the next record, dropped whenever the subscription catches up with the log
+    reader: Option<BufReader<File>>,
+    // Offset of the next record and its position in bytes in the log
+    offset: i64,
+    position: u64,
+    // Offsets of the records received since the last commit, with the position right after them
+    received: VecDeque<(i64, u64)>,
+    new_records: Arc<Notify>,
+}
+
+impl LocalSubscription {
+    async fn load_offset(&mut self) {
+        //! Resumes from the saved offset of the group, or from the first record for a new group.
+        let saved = tokio::fs::read_to_string(&self.offset_path).await.ok();
+        let parsed = saved.as_deref().and_then(|saved| {
+            let (offset, position) = saved.trim().split_once(' ')?;
+            Some((offset.parse().ok()?, position.parse().ok()?))
+        });
+
+        if let Some((offset, position)) = parsed {
+            self.offset = offset;
+            self.position = position;
+        }
+    }
+
+    async fn save_offset(&self, offset: i64, position: u64) -> Result<(), Error> {
+        tokio::fs::write(&self.offset_path, format!("{} {}", offset, position)).await
+    }
+
+    async fn open_reader(&mut self) -> Result<Option<BufReader<File>>, Error> {
+        let mut file = match File::open(&self.log_path).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        if self.position > file.metadata().await?.len() {
+            // The topic was deleted and created again
+            self.offset = 0;
+            self.position = 0;
+            self.received.clear();
+        }
+
+        file.seek(SeekFrom::Start(self.position)).await?;
+        Ok(Some(BufReader::new(file)))
+    }
+
+    async fn read_next(&mut self) -> Result<Option<StreamRecord>, Error> {
+        let mut reader = match self.reader.take() {
+            Some(reader) => reader,
+            None => match self.open_reader().await? {
+                Some(reader) => reader,
+                None => return Ok(None),
+            },
+        };
+
+        match read_record(&mut reader).await {
+            Ok((record_len, payload)) => {
+                let record = StreamRecord {
+                    partition: 0,
+                    offset: self.offset,
+                    payload,
+                };
+
+                self.offset += 1;
+                self.position += record_len;
+                self.received.push_back((record.offset, self.position));
+                self.reader = Some(reader);
+
+                Ok(Some(record))
+            }
+            // Caught up with the log, or the record isn't fully written yet. The log is opened
+            // again at the next record so that a topic created again is picked up
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[async_trait]
+impl StreamSubscription for LocalSubscription {
+    async fn recv(&mut self) -> Result<StreamRecord, Error> {
+        let new_records = Arc::clone(&self.new_records);
+
+        loop {
+            // Registered before reading so that a record appended in between still wakes us up
+            let notified = new_records.notified();
+
+            if let Some(record) = self.read_next().await? {
+                return Ok(record);
+            }
+
+            tokio::select! {
+                _ = notified => {}
+                _ = tokio::time::sleep(POLL_INTERVAL) => {}
+            }
+        }
+    }
+
+    async fn commit(&mut self, record: &StreamRecord) -> Result<(), Error> {
+        let mut committed = None;
+        while let Some((offset, position)) = self.received.front().copied() {
+            if offset > record.offset {
+                break;
+            }
+            self.received.pop_front();
+            committed = Some((offset + 1, position));
+        }
+
+        match committed {
+            Some((offset, position)) => self.save_offset(offset, position).await,
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_fs::TempDir;
+
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    async fn recv_record(subscription: &mut Box<dyn StreamSubscription>) -> StreamRecord {
+        tokio::time::timeout(TIMEOUT, subscription.recv())
+            .await
+            .expect("timed out waiting for a record")
+            .unwrap()
+    }
+
+    async fn backend_with_topic(dir: &TempDir) -> LocalBackend {
+        let backend = LocalBackend::new(dir.path().to_path_buf()).unwrap();
+        backend.create_topic("Users").await.unwrap();
+        backend
+    }
+
+    #[tokio::test]
+    async fn reads_back_produced_records_in_order() {
+        let dir = TempDir::new().unwrap();
+        let backend = backend_with_topic(&dir).await;
+
+        backend.produce("Users", "Users", b"first").await.unwrap();
+        let results = backend
+            .produce_batch("Users", "Users", &[b"second".to_vec(), b"third".to_vec()])
+            .await;
+        assert!(results.iter().all(|result| result.is_ok()));
+
+        let mut subscription = backend.subscribe("Users", "group").await.unwrap();
+        for (offset, payload) in ["first", "second", "third"].iter().enumerate() {
+            let record = recv_record(&mut subscription).await;
+            assert_eq!(record.offset, offset as i64);
+            assert_eq!(record.payload, payload.as_bytes());
+        }
+    }
+
+    #[tokio::test]
+    async fn wakes_up_for_records_produced_later() {
+        let dir = TempDir::new().unwrap();
+        let backend = Arc::new(backend_with_topic(&dir).await);
+        let mut subscription = backend.subscribe("Users", "group").await.unwrap();
+
+        let producer = Arc::clone(&backend);
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            producer.produce("Users", "Users", b"late").await.unwrap();
+        });
+
+        assert_eq!(recv_record(&mut subscription).await.payload, b"late");
+    }
+
+    #[tokio::test]
+    async fn resumes_after_the_last_committed_record() {
+        let dir = TempDir::new().unwrap();
+        let backend = backend_with_topic(&dir).await;
+        for payload in ["a", "b", "c"] {
+            backend
+                .produce("Users", "Users", payload.as_bytes())
+                .await
+                .unwrap();
+        }
+
+        let mut subscription = backend.subscribe("Users", "group").await.unwrap();
+        let first = recv_record(&mut subscription).await;
+        recv_record(&mut subscription).await;
+        subscription.commit(&first).await.unwrap();
+        drop(subscription);
+
+        // The second record wasn't committed, so it's received again
+        let mut subscription = backend.subscribe("Users", "group").await.unwrap();
+        let record = recv_record(&mut subscription).await;
+        assert_eq!((record.offset, record.payload), (1, b"b".to_vec()));
+
+        // Other groups start from the first record
+        let mut other = backend.subscribe("Users", "other").await.unwrap();
+        assert_eq!(recv_record(&mut other).await.offset, 0);
+    }
+
+    #[tokio::test]
+    async fn starts_over_when_the_topic_is_created_again() {
+        let dir = TempDir::new().unwrap();
+        let backend = backend_with_topic(&dir).await;
+        backend.produce("Users", "Users", b"old").await.unwrap();
+
+        let mut subscription = backend.subscribe("Users", "group").await.unwrap();
+        let record = recv_record(&mut subscription).await;
+        subscription.commit(&record).await.unwrap();
+
+        backend.delete_topic("Users").await.unwrap();
+        backend.create_topic("Users").await.unwrap();
+        backend.produce("Users", "Users", b"").await.unwrap();
+
+        let record = recv_record(&mut subscription).await;
+        assert_eq!((record.offset, record.payload), (0, vec![]));
+    }
+
+    #[tokio::test]
+    async fn produce_to_a_missing_topic_fails() {
+        let dir = TempDir::new().unwrap();
+        let backend = LocalBackend::new(dir.path().to_path_buf()).unwrap();
+
+        let e = backend
+            .produce("Missing", "Missing", b"record")
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::NotFound);
+        assert!(backend.list_topics().await.unwrap().is_empty());
+    }
+}
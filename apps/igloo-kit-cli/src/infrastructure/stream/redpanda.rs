@@ -1,12 +1,25 @@
+use async_trait::async_trait;
 use log::info;
 use rdkafka::{
-    producer::{FutureProducer, Producer},
-    ClientConfig,
+    consumer::{Consumer, StreamConsumer},
+    producer::{FutureProducer, FutureRecord, Producer},
+    types::RDKafkaErrorCode,
+    util::Timeout,
+    ClientConfig, Message, Offset, TopicPartitionList,
 };
 use serde::{Deserialize, Serialize};
-use std::time::Duration;
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind},
+    str::FromStr,
+    time::Duration,
+};
+use uuid::Uuid;
 
-use crate::{infrastructure::stream::rpk, utilities::docker};
+use crate::{
+    infrastructure::stream::{rpk, StreamRecord, StreamSubscription, StreamingBackend},
+    utilities::docker,
+};
 
 // TODO: We need to configure the application based on the current project directory structure to ensure that we catch changes made outside of development mode
 
@@ -29,10 +42,79 @@ pub fn delete_topic(topic_name: String) -> std::io::Result<String> {
     )))
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Acks {
+    // Every in-sync replica acknowledges the record
+    #[default]
+    All,
+    // Only the leader of the partition acknowledges the record
+    Leader,
+    None,
+}
+
+impl Acks {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Acks::All => "all",
+            Acks::Leader => "1",
+            Acks::None => "0",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionCodec {
+    #[default]
+    None,
+    Gzip,
+    Snappy,
+    Lz4,
+    Zstd,
+}
+
+impl CompressionCodec {
+    fn as_str(&self) -> &'static str {
+        match self {
+            CompressionCodec::None => "none",
+            CompressionCodec::Gzip => "gzip",
+            CompressionCodec::Snappy => "snappy",
+            CompressionCodec::Lz4 => "lz4",
+            CompressionCodec::Zstd => "zstd",
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct RedpandaConfig {
     pub broker: String,
     pub message_timeout_ms: i32,
+    // How long producing waits for room in the producer queue before failing
+    #[serde(default = "default_queue_timeout_ms")]
+    pub queue_timeout_ms: u64,
+    #[serde(default)]
+    pub acks: Acks,
+    // How long records wait to be sent with others, librdkafka's default when not set
+    #[serde(default)]
+    pub linger_ms: Option<u32>,
+    // The largest batch of records sent at once in bytes, librdkafka's default when not set
+    #[serde(default)]
+    pub batch_size: Option<u32>,
+    #[serde(default)]
+    pub compression: CompressionCodec,
+    // Requires `acks = "all"`
+    #[serde(default)]
+    pub enable_idempotence: bool,
+    #[serde(default)]
+    pub retries: Option<u32>,
+    // Any other librdkafka setting of the producer, applied over the ones above
+    #[serde(default)]
+    pub producer_config: HashMap<String, String>,
+}
+
+fn default_queue_timeout_ms() -> u64 {
+    1000
 }
 
 impl Default for RedpandaConfig {
@@ -40,27 +122,236 @@ impl Default for RedpandaConfig {
         Self {
             broker: "localhost:19092".to_string(),
             message_timeout_ms: 1000,
+            queue_timeout_ms: default_queue_timeout_ms(),
+            acks: Acks::default(),
+            linger_ms: None,
+            batch_size: None,
+            compression: CompressionCodec::default(),
+            enable_idempotence: false,
+            retries: None,
+            producer_config: HashMap::new(),
         }
     }
 }
 
+fn producer_client_config(config: &RedpandaConfig) -> ClientConfig {
+    let mut client_config = ClientConfig::new();
+    client_config
+        .set("bootstrap.servers", &config.broker)
+        .set("message.timeout.ms", config.message_timeout_ms.to_string())
+        .set("acks", config.acks.as_str())
+        .set("compression.codec", config.compression.as_str())
+        .set("enable.idempotence", config.enable_idempotence.to_string());
+    if let Some(linger_ms) = config.linger_ms {
+        client_config.set("linger.ms", linger_ms.to_string());
+    }
+    if let Some(batch_size) = config.batch_size {
+        client_config.set("batch.size", batch_size.to_string());
+    }
+    if let Some(retries) = config.retries {
+        client_config.set("retries", retries.to_string());
+    }
+    for (key, value) in &config.producer_config {
+        client_config.set(key, value);
+    }
+    client_config
+}
+
+impl RedpandaConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        //! Checks the producer settings, including the ones passed through in `producer_config`,
+        //! so that a bad `project.toml` is reported when the project loads.
+        let setting = |key: &str, value: &str| {
+            self.producer_config
+                .get(key)
+                .map_or(value.to_string(), |value| value.trim().to_lowercase())
+        };
+        let idempotent = setting("enable.idempotence", &self.enable_idempotence.to_string());
+        let acks = setting("acks", self.acks.as_str());
+        if idempotent == "true" && acks != "all" && acks != "-1" {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "enable_idempotence requires acks = \"all\", the producer is set to acks = {}",
+                    acks
+                ),
+            ));
+        }
+
+        producer_client_config(self)
+            .create_native_config()
+            .map_err(|e| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Invalid producer settings: {}", e),
+                )
+            })?;
+        Ok(())
+    }
+}
+
 #[derive(Clone)]
 pub struct ConfiguredProducer {
     pub producer: FutureProducer,
     pub config: RedpandaConfig,
 }
 
-pub fn create_producer(config: RedpandaConfig) -> ConfiguredProducer {
-    let producer = ClientConfig::new()
-        .set("bootstrap.servers", config.clone().broker)
-        .set(
-            "message.timeout.ms",
-            config.clone().message_timeout_ms.to_string(),
-        )
+pub fn create_producer(config: RedpandaConfig) -> Result<ConfiguredProducer, Error> {
+    config.validate()?;
+    let producer = producer_client_config(&config)
         .create()
-        .expect("Failed to create producer");
+        .map_err(|e| kafka_error("Failed to create producer", e))?;
 
-    ConfiguredProducer { producer, config }
+    Ok(ConfiguredProducer { producer, config })
+}
+
+pub fn create_consumer(
+    config: &RedpandaConfig,
+    group_id: &str,
+) -> Result<StreamConsumer, rdkafka::error::KafkaError> {
+    //! Creates a consumer in the given consumer group. New groups start from the earliest offset.
+    //!
+    //! Offsets are only stored once the caller is done with a record, and then committed in the
+    //! background, so that records that failed to be processed are consumed again.
+    ClientConfig::new()
+        .set("bootstrap.servers", &config.broker)
+        .set("group.id", group_id)
+        .set("enable.partition.eof", "false")
+        .set("enable.auto.offset.store", "false")
+        .set("auto.offset.reset", "earliest")
+        .create()
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum StartOffset {
+    Earliest,
+    Latest,
+    Offset(i64),
+    Timestamp(i64), // milliseconds since the epoch
+}
+
+impl FromStr for StartOffset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        //! Parses `earliest`, `latest`, an offset or an RFC 3339 timestamp.
+        match s {
+            "earliest" => Ok(StartOffset::Earliest),
+            "latest" => Ok(StartOffset::Latest),
+            _ => {
+                if let Ok(offset) = s.parse::<i64>() {
+                    return Ok(StartOffset::Offset(offset));
+                }
+                let time = humantime::parse_rfc3339_weak(s)
+                    .map_err(|_| format!("{} is not an offset or a timestamp", s))?;
+                let since_epoch = time
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map_err(|_| format!("{} is before the epoch", s))?;
+                Ok(StartOffset::Timestamp(since_epoch.as_millis() as i64))
+            }
+        }
+    }
+}
+
+fn create_standalone_consumer(
+    config: &RedpandaConfig,
+) -> Result<StreamConsumer, rdkafka::error::KafkaError> {
+    // A throwaway group, these consumers don't commit offsets
+    ClientConfig::new()
+        .set("bootstrap.servers", &config.broker)
+        .set("group.id", format!("igloo-cli-{}", Uuid::new_v4()))
+        .set("enable.auto.commit", "false")
+        .set("enable.partition.eof", "false")
+        .create()
+}
+
+pub fn create_assigned_consumer(
+    config: &RedpandaConfig,
+    topic: &str,
+    from: StartOffset,
+) -> Result<StreamConsumer, rdkafka::error::KafkaError> {
+    //! Creates a consumer that reads every partition of the topic from the given offset.
+    //! It doesn't commit offsets, the caller is in charge of tracking its progress.
+    let consumer = create_standalone_consumer(config)?;
+
+    let metadata = consumer.fetch_metadata(Some(topic), Duration::from_secs(5))?;
+
+    let mut assignment = TopicPartitionList::new();
+    for partition in metadata.topics().iter().flat_map(|t| t.partitions()) {
+        let offset = match from {
+            StartOffset::Earliest => Offset::Beginning,
+            StartOffset::Latest => Offset::End,
+            StartOffset::Offset(offset) => Offset::Offset(offset),
+            StartOffset::Timestamp(millis) => Offset::Offset(millis),
+        };
+        assignment.add_partition_offset(topic, partition.id(), offset)?;
+    }
+
+    // The offsets of a timestamp lookup are the timestamps themselves until they are resolved
+    if let StartOffset::Timestamp(_) = from {
+        assignment = consumer.offsets_for_times(assignment, Duration::from_secs(5))?;
+    }
+
+    consumer.assign(&assignment)?;
+    Ok(consumer)
+}
+
+pub fn create_consumer_at_offsets(
+    config: &RedpandaConfig,
+    topic: &str,
+    offsets: &HashMap<i32, i64>,
+) -> Result<StreamConsumer, rdkafka::error::KafkaError> {
+    //! Creates a consumer that reads the given partitions of the topic, each from its own offset.
+    let consumer = create_standalone_consumer(config)?;
+
+    let mut assignment = TopicPartitionList::new();
+    for (partition, offset) in offsets {
+        assignment.add_partition_offset(topic, *partition, Offset::Offset(*offset))?;
+    }
+
+    consumer.assign(&assignment)?;
+    Ok(consumer)
+}
+
+pub fn resolve_offsets(
+    config: &RedpandaConfig,
+    topic: &str,
+    at: StartOffset,
+) -> Result<HashMap<i32, i64>, rdkafka::error::KafkaError> {
+    //! Resolves a position in the topic to a concrete offset for every partition. Offsets are
+    //! clamped to the records currently in the partition, and timestamps past the last record
+    //! resolve to the end of the partition.
+    let consumer = create_standalone_consumer(config)?;
+    let timeout = Duration::from_secs(5);
+
+    let metadata = consumer.fetch_metadata(Some(topic), timeout)?;
+
+    let mut offsets = HashMap::new();
+    for partition in metadata.topics().iter().flat_map(|t| t.partitions()) {
+        let (low, high) = consumer.fetch_watermarks(topic, partition.id(), timeout)?;
+
+        let offset = match at {
+            StartOffset::Earliest => low,
+            StartOffset::Latest => high,
+            StartOffset::Offset(offset) => offset.clamp(low, high),
+            StartOffset::Timestamp(millis) => {
+                let mut lookup = TopicPartitionList::new();
+                lookup.add_partition_offset(topic, partition.id(), Offset::Offset(millis))?;
+                let resolved = consumer.offsets_for_times(lookup, timeout)?;
+                match resolved
+                    .find_partition(topic, partition.id())
+                    .map(|elem| elem.offset())
+                {
+                    Some(Offset::Offset(offset)) => offset,
+                    _ => high,
+                }
+            }
+        };
+
+        offsets.insert(partition.id(), offset);
+    }
+
+    Ok(offsets)
 }
 
 pub async fn fetch_topics(
@@ -82,3 +373,225 @@ pub async fn fetch_topics(
         .collect();
     Ok(topics)
 }
+
+fn kafka_error(context: &str, e: rdkafka::error::KafkaError) -> Error {
+    // The kind tells callers whether trying again later could work, see `stream::is_retryable`
+    let kind = match e.rdkafka_error_code() {
+        Some(
+            RDKafkaErrorCode::MessageTimedOut
+            | RDKafkaErrorCode::RequestTimedOut
+            | RDKafkaErrorCode::OperationTimedOut,
+        ) => ErrorKind::TimedOut,
+        Some(RDKafkaErrorCode::QueueFull) => ErrorKind::WouldBlock,
+        Some(RDKafkaErrorCode::AllBrokersDown | RDKafkaErrorCode::BrokerTransportFailure) => {
+            ErrorKind::NotConnected
+        }
+        _ => ErrorKind::Other,
+    };
+    Error::new(kind, format!("{}: {}", context, e))
+}
+
+pub struct RedpandaBackend {
+    configured_producer: ConfiguredProducer,
+}
+
+impl RedpandaBackend {
+    pub fn new(config: RedpandaConfig) -> Result<Self, Error> {
+        Ok(Self {
+            configured_producer: create_producer(config)?,
+        })
+    }
+}
+
+#[async_trait]
+impl StreamingBackend for RedpandaBackend {
+    async fn create_topic(&self, topic: &str) -> Result<(), Error> {
+        create_topic_from_name(topic.to_string()).map(|_| ())
+    }
+
+    async fn delete_topic(&self, topic: &str) -> Result<(), Error> {
+        delete_topic(topic.to_string()).map(|_| ())
+    }
+
+    async fn list_topics(&self) -> Result<Vec<String>, Error> {
+        fetch_topics(&self.configured_producer.config)
+            .await
+            .map_err(|e| kafka_error("Failed to fetch topics", e))
+    }
+
+    async fn produce(&self, topic: &str, key: &str, payload: &[u8]) -> Result<(), Error> {
+        self.configured_producer
+            .producer
+            .send(
+                FutureRecord::to(topic).key(key).payload(payload),
+                Timeout::After(Duration::from_millis(
+                    self.configured_producer.config.queue_timeout_ms,
+                )),
+            )
+            .await
+            .map(|_| ())
+            .map_err(|(e, _)| kafka_error("Failed to produce record", e))
+    }
+
+    async fn produce_batch(
+        &self,
+        topic: &str,
+        key: &str,
+        payloads: &[Vec<u8>],
+    ) -> Vec<Result<(), Error>> {
+        // Every record is queued before waiting on any delivery so that they are sent together
+        let deliveries = payloads
+            .iter()
+            .map(|payload| {
+                self.configured_producer
+                    .producer
+                    .send_result(FutureRecord::to(topic).key(key).payload(payload))
+                    .map_err(|(e, _)| kafka_error("Failed to produce record", e))
+            })
+            .collect::<Vec<_>>();
+
+        let mut results = Vec::with_capacity(deliveries.len());
+        for delivery in deliveries {
+            results.push(match delivery {
+                Ok(delivery) => match delivery.await {
+                    Ok(Ok(_)) => Ok(()),
+                    Ok(Err((e, _))) => Err(kafka_error("Failed to produce record", e)),
+                    Err(_) => Err(Error::new(
+                        ErrorKind::Interrupted,
+                        "The delivery of the record was canceled",
+                    )),
+                },
+                Err(e) => Err(e),
+            });
+        }
+        results
+    }
+
+    async fn flush(&self, timeout: Duration) -> Result<(), Error> {
+        // Flushing blocks until the queue is empty, so it's kept off the async threads
+        let producer = self.configured_producer.producer.clone();
+        tokio::task::spawn_blocking(move || producer.flush(Timeout::After(timeout)))
+            .await
+            .map_err(|e| {
+                Error::new(
+                    ErrorKind::Other,
+                    format!("Failed to flush the producer: {}", e),
+                )
+            })?
+            .map_err(|e| kafka_error("Failed to flush the producer", e))
+    }
+
+    async fn subscribe(
+        &self,
+        topic: &str,
+        group_id: &str,
+    ) -> Result<Box<dyn StreamSubscription>, Error> {
+        let consumer = create_consumer(&self.configured_producer.config, group_id)
+            .map_err(|e| kafka_error("Failed to create consumer", e))?;
+        consumer
+            .subscribe(&[topic])
+            .map_err(|e| kafka_error("Failed to subscribe", e))?;
+        Ok(Box::new(RedpandaSubscription {
+            consumer,
+            topic: topic.to_string(),
+        }))
+    }
+}
+
+struct RedpandaSubscription {
+    consumer: StreamConsumer,
+    topic: String,
+}
+
+#[async_trait]
+impl StreamSubscription for RedpandaSubscription {
+    async fn recv(&mut self) -> Result<StreamRecord, Error> {
+        let message = self
+            .consumer
+            .recv()
+            .await
+            .map_err(|e| kafka_error("Failed to consume", e))?;
+
+        Ok(StreamRecord {
+            partition: message.partition(),
+            offset: message.offset(),
+            payload: message.payload().unwrap_or_default().to_vec(),
+        })
+    }
+
+    async fn commit(&mut self, record: &StreamRecord) -> Result<(), Error> {
+        // Stores the offset after the record, which the consumer commits with its next auto commit.
+        // The committed offset is the next one the group reads, so the record isn't read again.
+        self.consumer
+            .store_offset(&self.topic, record.partition, record.offset + 1)
+            .map_err(|e| kafka_error("Failed to store the offset", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    #[ignore = "needs the Redpanda container of `igloo dev`"]
+    async fn resumes_after_the_last_committed_record() {
+        let backend = RedpandaBackend::new(RedpandaConfig::default()).unwrap();
+        let topic = format!("commit_test_{}", Uuid::new_v4().simple());
+        let group_id = format!("{}_group", topic);
+        backend.create_topic(&topic).await.unwrap();
+        for payload in ["first", "second"] {
+            backend
+                .produce(&topic, &topic, payload.as_bytes())
+                .await
+                .unwrap();
+        }
+
+        let mut subscription = backend.subscribe(&topic, &group_id).await.unwrap();
+        let first = subscription.recv().await.unwrap();
+        assert_eq!(first.payload, b"first");
+        subscription.commit(&first).await.unwrap();
+        // The stored offset is committed when the consumer closes
+        drop(subscription);
+
+        let mut subscription = backend.subscribe(&topic, &group_id).await.unwrap();
+        let next = tokio::time::timeout(Duration::from_secs(30), subscription.recv())
+            .await
+            .expect("timed out waiting for a record")
+            .unwrap();
+        assert_eq!(next.payload, b"second");
+        assert_eq!(next.offset, first.offset + 1);
+
+        drop(subscription);
+        backend.delete_topic(&topic).await.unwrap();
+    }
+
+    #[test]
+    fn idempotence_requires_acks_from_every_replica() {
+        let mut config = RedpandaConfig {
+            enable_idempotence: true,
+            ..RedpandaConfig::default()
+        };
+        assert!(config.validate().is_ok());
+
+        config.acks = Acks::Leader;
+        assert_eq!(
+            config.validate().unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+
+        // Settings passed through override the typed ones
+        config
+            .producer_config
+            .insert("acks".to_string(), "-1".to_string());
+        assert!(config.validate().is_ok());
+
+        let config = RedpandaConfig {
+            producer_config: HashMap::from([
+                ("enable.idempotence".to_string(), "True".to_string()),
+                ("acks".to_string(), "0".to_string()),
+            ]),
+            ..RedpandaConfig::default()
+        };
+        assert!(config.validate().is_err());
+    }
+}
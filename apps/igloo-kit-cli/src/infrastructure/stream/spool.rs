@@ -0,0 +1,808 @@
+//! # Spool
+//! A write-ahead spool on disk for the records the stream can't take while it's unavailable, like
+//! when Redpanda is down or restarting. Records that fail to produce with a retryable error are
+//! appended to `.igloo/spool/<topic>.log` and acknowledged once they're synced to disk, and a
+//! background task produces them again, a batch at a time, until the stream takes them. Records
+//! left in the spool on shutdown are replayed on the next start. The spool is off by default:
+//!
+//! ```toml
+//! [stream_config.spool]
+//! enabled = true
+//! # Once the spool is this large, records fail like they would without it
+//! max_bytes = 1073741824
+//! retry_interval_secs = 5
+//! ```
+//!
+//! Each record in the spool is laid out as:
+//!
+//! ```text
+//! key length (u32 LE) | key | payload length (u32 LE) | payload
+//! ```
+//!
+//! A replay moves the log of a topic to `<topic>.replay` first, so that records spooled meanwhile
+//! don't mix with the ones being produced. A replay cut short by a crash starts over on the next
+//! start, so its records can be produced twice. The console API reports the state of the spool.
+//!
+//! ## Suggested Improvements
+//! - keep the records of a topic in order by spooling new records while older ones wait
+//! - split the spool into segments so that partial replays don't rewrite it
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{File, OpenOptions},
+    io::{BufReader, BufWriter, Error, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use tokio::{sync::Mutex, task::JoinHandle};
+
+use super::{is_retryable, StreamSubscription, StreamingBackend};
+
+const LOG_EXTENSION: &str = "log";
+const REPLAY_EXTENSION: &str = "replay";
+
+// Records are produced again in batches of this size
+const REPLAY_BATCH_SIZE: usize = 500;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SpoolConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_max_bytes")]
+    pub max_bytes: u64,
+    #[serde(default = "default_retry_interval_secs")]
+    pub retry_interval_secs: u64,
+}
+
+fn default_max_bytes() -> u64 {
+    1024 * 1024 * 1024
+}
+
+fn default_retry_interval_secs() -> u64 {
+    5
+}
+
+impl Default for SpoolConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_bytes: default_max_bytes(),
+            retry_interval_secs: default_retry_interval_secs(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct SpoolStatus {
+    pub records: u64,
+    pub bytes: u64,
+    pub max_bytes: u64,
+    // Records waiting in the spool by topic
+    pub topics: BTreeMap<String, u64>,
+    pub spooled_total: u64,
+    pub replayed_total: u64,
+    // The error that last sent records to the spool or stopped a replay
+    pub last_error: Option<String>,
+}
+
+#[derive(Default, Clone, Copy)]
+struct TopicSpool {
+    records: u64,
+    bytes: u64,
+}
+
+#[derive(Default)]
+struct SpoolState {
+    topics: HashMap<String, TopicSpool>,
+    spooled_total: u64,
+    replayed_total: u64,
+    last_error: Option<String>,
+}
+
+type SpooledRecord = (String, Vec<u8>);
+
+fn encode_record(key: &[u8], payload: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(8 + key.len() + payload.len());
+    buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    buf.extend_from_slice(key);
+    buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buf.extend_from_slice(payload);
+    buf
+}
+
+fn read_bytes(reader: &mut impl Read) -> Result<Vec<u8>, Error> {
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len)?;
+    let mut bytes = vec![0u8; u32::from_le_bytes(len) as usize];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_record(reader: &mut impl Read) -> Result<SpooledRecord, Error> {
+    let key = read_bytes(reader)?;
+    let payload = read_bytes(reader)?;
+    Ok((String::from_utf8_lossy(&key).into_owned(), payload))
+}
+
+fn read_chunk(reader: &mut impl Read, max_records: usize) -> Result<Vec<SpooledRecord>, Error> {
+    //! Reads up to `max_records` records of a spool file. A record cut short by a crash ends the
+    //! file.
+    let mut records = vec![];
+    while records.len() < max_records {
+        match read_record(reader) {
+            Ok(record) => records.push(record),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(records)
+}
+
+fn count_records(path: &Path) -> Result<TopicSpool, Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut spool = TopicSpool::default();
+    loop {
+        let chunk = read_chunk(&mut reader, REPLAY_BATCH_SIZE)?;
+        if chunk.is_empty() {
+            return Ok(spool);
+        }
+        spool.records += chunk.len() as u64;
+        spool.bytes += chunk.iter().map(record_size).sum::<u64>();
+    }
+}
+
+fn copy_records(reader: &mut impl Read, writer: &mut impl Write) -> Result<(), Error> {
+    //! Copies the records that are left in a spool file. A record cut short isn't copied, so that
+    //! the records written after it can still be read.
+    loop {
+        let chunk = read_chunk(reader, REPLAY_BATCH_SIZE)?;
+        if chunk.is_empty() {
+            return Ok(());
+        }
+        for (key, payload) in chunk {
+            writer.write_all(&encode_record(key.as_bytes(), &payload))?;
+        }
+    }
+}
+
+async fn blocking<T, F>(f: F) -> Result<T, Error>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, Error> + Send + 'static,
+{
+    //! Runs file operations off the runtime, so that a slow disk doesn't hold up the requests.
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?
+}
+
+fn record_size(record: &SpooledRecord) -> u64 {
+    (8 + record.0.len() + record.1.len()) as u64
+}
+
+struct Spool {
+    dir: PathBuf,
+    max_bytes: u64,
+    state: Mutex<SpoolState>,
+}
+
+impl Spool {
+    fn open(dir: PathBuf, max_bytes: u64) -> Result<Self, Error> {
+        //! Opens the spool in the directory, counting the records left by previous runs.
+        std::fs::create_dir_all(&dir)?;
+
+        let mut state = SpoolState::default();
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let is_spool_file = path
+                .extension()
+                .map_or(false, |ext| ext == LOG_EXTENSION || ext == REPLAY_EXTENSION);
+            let topic = path.file_stem().and_then(|stem| stem.to_str());
+            if let (true, Some(topic)) = (is_spool_file, topic) {
+                let counted = count_records(&path)?;
+                // A record cut short by a crash would hide the records appended after it
+                if std::fs::metadata(&path)?.len() > counted.bytes {
+                    OpenOptions::new()
+                        .write(true)
+                        .open(&path)?
+                        .set_len(counted.bytes)?;
+                }
+                let spool = state.topics.entry(topic.to_string()).or_default();
+                spool.records += counted.records;
+                spool.bytes += counted.bytes;
+            }
+        }
+
+        if !state.topics.is_empty() {
+            info!(
+                "Found {} spooled records to replay",
+                state
+                    .topics
+                    .values()
+                    .map(|spool| spool.records)
+                    .sum::<u64>()
+            );
+        }
+
+        Ok(Self {
+            dir,
+            max_bytes,
+            state: Mutex::new(state),
+        })
+    }
+
+    fn path(&self, topic: &str, extension: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", topic, extension))
+    }
+
+    async fn append(&self, topic: &str, key: &str, payloads: &[&[u8]], cause: &Error) -> bool {
+        //! Spools the records of a failed produce call, all of them or none when they don't fit.
+        let buf = payloads
+            .iter()
+            .flat_map(|payload| encode_record(key.as_bytes(), payload))
+            .collect::<Vec<u8>>();
+
+        let mut state = self.state.lock().await;
+        let spooled_bytes = state.topics.values().map(|spool| spool.bytes).sum::<u64>();
+        if spooled_bytes + buf.len() as u64 > self.max_bytes {
+            debug!(
+                "The spool is full, {} records were not spooled",
+                payloads.len()
+            );
+            return false;
+        }
+
+        let bytes = buf.len() as u64;
+        let path = self.path(topic, LOG_EXTENSION);
+        let written = blocking(move || {
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            let len = file.metadata()?.len();
+            // The records are only acknowledged once they're on disk
+            if let Err(e) = file.write_all(&buf).and_then(|_| file.sync_data()) {
+                // A record written in part would hide the ones appended after it
+                let _ = file.set_len(len);
+                return Err(e);
+            }
+            Ok(())
+        })
+        .await;
+        if let Err(e) = written {
+            error!("Failed to spool records of {}: {}", topic, e);
+            return false;
+        }
+
+        let spool = state.topics.entry(topic.to_string()).or_default();
+        spool.records += payloads.len() as u64;
+        spool.bytes += bytes;
+        state.spooled_total += payloads.len() as u64;
+        state.last_error = Some(cause.to_string());
+        true
+    }
+
+    async fn replay_topic(&self, topic: &str, backend: &dyn StreamingBackend) -> Result<(), Error> {
+        //! Produces the spooled records of the topic again, up to the first batch that fails. The
+        //! records are read a batch at a time, so a large spool doesn't have to fit in memory.
+        let log_path = self.path(topic, LOG_EXTENSION);
+        let replay_path = self.path(topic, REPLAY_EXTENSION);
+
+        // A replay file left by a previous run goes first
+        {
+            let _state = self.state.lock().await;
+            let (log_path, replay_path) = (log_path.clone(), replay_path.clone());
+            let has_records = blocking(move || {
+                if replay_path.exists() {
+                    return Ok(true);
+                }
+                if !log_path.exists() {
+                    return Ok(false);
+                }
+                std::fs::rename(&log_path, &replay_path)?;
+                Ok(true)
+            })
+            .await?;
+            if !has_records {
+                return Ok(());
+            }
+        }
+
+        let mut reader = BufReader::new(
+            blocking({
+                let replay_path = replay_path.clone();
+                move || File::open(replay_path)
+            })
+            .await?,
+        );
+        let mut replayed = 0u64;
+        let mut replayed_bytes = 0u64;
+        let mut remaining = vec![];
+        let mut replay_error = None;
+        loop {
+            let chunk;
+            (reader, chunk) = blocking(move || {
+                let chunk = read_chunk(&mut reader, REPLAY_BATCH_SIZE)?;
+                Ok((reader, chunk))
+            })
+            .await?;
+            if chunk.is_empty() {
+                break;
+            }
+
+            // The spooled records of a batch share their key, but the spool doesn't rely on it
+            for (key, group) in group_by_key(&chunk) {
+                let payloads = group
+                    .iter()
+                    .map(|record| record.1.clone())
+                    .collect::<Vec<_>>();
+                let results = backend.produce_batch(topic, key, &payloads).await;
+                for (result, record) in results.into_iter().zip(group) {
+                    match result {
+                        Ok(()) => {
+                            replayed += 1;
+                            replayed_bytes += record_size(record);
+                        }
+                        Err(e) => {
+                            replay_error.get_or_insert(e);
+                            remaining.push(record.clone());
+                        }
+                    }
+                }
+            }
+            if replay_error.is_some() {
+                break;
+            }
+        }
+
+        let mut state = self.state.lock().await;
+        let tmp_path = self.path(topic, "tmp");
+        let failed = replay_error.is_some();
+        blocking(move || {
+            if failed {
+                // The records that are left go back ahead of the ones spooled during the replay
+                let mut tmp = File::create(&tmp_path)?;
+                {
+                    let mut writer = BufWriter::new(&mut tmp);
+                    for (key, payload) in &remaining {
+                        writer.write_all(&encode_record(key.as_bytes(), payload))?;
+                    }
+                    copy_records(&mut reader, &mut writer)?;
+                    if log_path.exists() {
+                        copy_records(&mut BufReader::new(File::open(&log_path)?), &mut writer)?;
+                    }
+                    writer.flush()?;
+                }
+                tmp.sync_data()?;
+                std::fs::rename(&tmp_path, &log_path)?;
+            }
+            std::fs::remove_file(&replay_path)
+        })
+        .await?;
+
+        state.replayed_total += replayed;
+        if let Some(spool) = state.topics.get_mut(topic) {
+            spool.records = spool.records.saturating_sub(replayed);
+            spool.bytes = spool.bytes.saturating_sub(replayed_bytes);
+            if spool.records == 0 {
+                state.topics.remove(topic);
+            }
+        }
+        if replayed > 0 {
+            info!("Replayed {} spooled records to {}", replayed, topic);
+        }
+
+        match replay_error {
+            Some(e) => {
+                state.last_error = Some(e.to_string());
+                Err(e)
+            }
+            None => Ok(()),
+        }
+    }
+
+    async fn replay(&self, backend: &dyn StreamingBackend) {
+        let topics = self
+            .state
+            .lock()
+            .await
+            .topics
+            .keys()
+            .cloned()
+            .collect::<Vec<String>>();
+
+        for topic in topics {
+            if let Err(e) = self.replay_topic(&topic, backend).await {
+                // The stream is most likely still down, the other topics are tried next time
+                debug!("Failed to replay the spool of {}: {}", topic, e);
+                break;
+            }
+        }
+    }
+
+    async fn status(&self) -> SpoolStatus {
+        let state = self.state.lock().await;
+        SpoolStatus {
+            records: state.topics.values().map(|spool| spool.records).sum(),
+            bytes: state.topics.values().map(|spool| spool.bytes).sum(),
+            max_bytes: self.max_bytes,
+            topics: state
+                .topics
+                .iter()
+                .map(|(topic, spool)| (topic.clone(), spool.records))
+                .collect(),
+            spooled_total: state.spooled_total,
+            replayed_total: state.replayed_total,
+            last_error: state.last_error.clone(),
+        }
+    }
+}
+
+fn group_by_key(records: &[SpooledRecord]) -> Vec<(&str, Vec<&SpooledRecord>)> {
+    //! Groups consecutive records that share a key, keeping their order.
+    let mut groups: Vec<(&str, Vec<&SpooledRecord>)> = vec![];
+    for record in records {
+        match groups.last_mut() {
+            Some((key, group)) if *key == record.0 => group.push(record),
+            _ => groups.push((&record.0, vec![record])),
+        }
+    }
+    groups
+}
+
+pub struct SpoolingBackend {
+    inner: Arc<dyn StreamingBackend>,
+    spool: Arc<Spool>,
+    retry_interval: Duration,
+    replay_task: Option<JoinHandle<()>>,
+}
+
+impl SpoolingBackend {
+    pub fn new(
+        inner: Arc<dyn StreamingBackend>,
+        dir: PathBuf,
+        config: &SpoolConfig,
+    ) -> Result<Self, Error> {
+        //! Wraps the backend with a spool in the directory. Nothing is replayed until `start` is
+        //! called.
+        Ok(Self {
+            inner,
+            spool: Arc::new(Spool::open(dir, config.max_bytes)?),
+            retry_interval: Duration::from_secs(config.retry_interval_secs.max(1)),
+            replay_task: None,
+        })
+    }
+
+    pub fn start(&mut self) {
+        //! Replays the spool in the background at every retry interval, until the backend is
+        //! dropped. Must be called from within the tokio runtime.
+        if self.replay_task.is_some() {
+            return;
+        }
+
+        let inner = Arc::clone(&self.inner);
+        let spool = Arc::clone(&self.spool);
+        let retry_interval = self.retry_interval;
+        self.replay_task = Some(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(retry_interval);
+            loop {
+                interval.tick().await;
+                spool.replay(inner.as_ref()).await;
+            }
+        }));
+    }
+}
+
+impl Drop for SpoolingBackend {
+    fn drop(&mut self) {
+        if let Some(replay_task) = &self.replay_task {
+            replay_task.abort();
+        }
+    }
+}
+
+#[async_trait]
+impl StreamingBackend for SpoolingBackend {
+    async fn create_topic(&self, topic: &str) -> Result<(), Error> {
+        self.inner.create_topic(topic).await
+    }
+
+    async fn delete_topic(&self, topic: &str) -> Result<(), Error> {
+        self.inner.delete_topic(topic).await
+    }
+
+    async fn list_topics(&self) -> Result<Vec<String>, Error> {
+        self.inner.list_topics().await
+    }
+
+    async fn produce(&self, topic: &str, key: &str, payload: &[u8]) -> Result<(), Error> {
+        match self.inner.produce(topic, key, payload).await {
+            Err(e) if is_retryable(&e) && self.spool.append(topic, key, &[payload], &e).await => {
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    async fn produce_batch(
+        &self,
+        topic: &str,
+        key: &str,
+        payloads: &[Vec<u8>],
+    ) -> Vec<Result<(), Error>> {
+        let mut results = self.inner.produce_batch(topic, key, payloads).await;
+
+        let retryable = results
+            .iter()
+            .enumerate()
+            .filter(|(_, result)| result.as_ref().err().map_or(false, is_retryable))
+            .map(|(index, _)| index)
+            .collect::<Vec<usize>>();
+        let cause = match retryable
+            .first()
+            .and_then(|index| results[*index].as_ref().err())
+        {
+            Some(e) => Error::new(e.kind(), e.to_string()),
+            None => return results,
+        };
+
+        let spooled = retryable
+            .iter()
+            .map(|index| payloads[*index].as_slice())
+            .collect::<Vec<&[u8]>>();
+        if self.spool.append(topic, key, &spooled, &cause).await {
+            for index in retryable {
+                results[index] = Ok(());
+            }
+        }
+        results
+    }
+
+    async fn flush(&self, timeout: Duration) -> Result<(), Error> {
+        // Spooled records stay on disk until the next start, only the producer is flushed
+        self.inner.flush(timeout).await
+    }
+
+    async fn subscribe(
+        &self,
+        topic: &str,
+        group_id: &str,
+    ) -> Result<Box<dyn StreamSubscription>, Error> {
+        self.inner.subscribe(topic, group_id).await
+    }
+
+    async fn spool_status(&self) -> Option<SpoolStatus> {
+        Some(self.spool.status().await)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex as StdMutex,
+    };
+
+    use assert_fs::TempDir;
+
+    use super::*;
+
+    // Takes as many records as it's allowed to, and times out on the others like a stream that's down
+    #[derive(Default)]
+    struct FlakyBackend {
+        accepts: AtomicUsize,
+        produced: StdMutex<Vec<Vec<u8>>>,
+    }
+
+    impl FlakyBackend {
+        fn accept(&self, records: usize) {
+            self.accepts.store(records, Ordering::SeqCst);
+        }
+
+        fn produced(&self) -> Vec<Vec<u8>> {
+            self.produced.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl StreamingBackend for FlakyBackend {
+        async fn create_topic(&self, _topic: &str) -> Result<(), Error> {
+            Ok(())
+        }
+
+        async fn delete_topic(&self, _topic: &str) -> Result<(), Error> {
+            Ok(())
+        }
+
+        async fn list_topics(&self) -> Result<Vec<String>, Error> {
+            Ok(vec![])
+        }
+
+        async fn produce(&self, _topic: &str, _key: &str, payload: &[u8]) -> Result<(), Error> {
+            let taken = self
+                .accepts
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |accepts| {
+                    accepts.checked_sub(1)
+                });
+            match taken {
+                Ok(_) => {
+                    self.produced.lock().unwrap().push(payload.to_vec());
+                    Ok(())
+                }
+                Err(_) => Err(Error::new(ErrorKind::TimedOut, "the stream is down")),
+            }
+        }
+
+        async fn produce_batch(
+            &self,
+            topic: &str,
+            key: &str,
+            payloads: &[Vec<u8>],
+        ) -> Vec<Result<(), Error>> {
+            let mut results = vec![];
+            for payload in payloads {
+                results.push(self.produce(topic, key, payload).await);
+            }
+            results
+        }
+
+        async fn flush(&self, _timeout: Duration) -> Result<(), Error> {
+            Ok(())
+        }
+
+        async fn subscribe(
+            &self,
+            _topic: &str,
+            _group_id: &str,
+        ) -> Result<Box<dyn StreamSubscription>, Error> {
+            Err(Error::new(ErrorKind::Unsupported, "not needed"))
+        }
+    }
+
+    fn config(max_bytes: u64) -> SpoolConfig {
+        SpoolConfig {
+            enabled: true,
+            max_bytes,
+            retry_interval_secs: 1,
+        }
+    }
+
+    fn spooling_backend(
+        inner: &Arc<FlakyBackend>,
+        dir: &TempDir,
+        max_bytes: u64,
+    ) -> SpoolingBackend {
+        SpoolingBackend::new(
+            Arc::clone(inner) as Arc<dyn StreamingBackend>,
+            dir.path().join("spool"),
+            &config(max_bytes),
+        )
+        .unwrap()
+    }
+
+    fn payloads(names: &[&str]) -> Vec<Vec<u8>> {
+        names.iter().map(|name| name.as_bytes().to_vec()).collect()
+    }
+
+    #[tokio::test]
+    async fn spools_records_and_replays_them_in_order() {
+        let dir = TempDir::new().unwrap();
+        let inner = Arc::new(FlakyBackend::default());
+        let backend = spooling_backend(&inner, &dir, default_max_bytes());
+
+        backend.produce("Users", "Users", b"a").await.unwrap();
+        let results = backend
+            .produce_batch("Users", "Users", &payloads(&["b", "c"]))
+            .await;
+        assert!(results.iter().all(|result| result.is_ok()));
+
+        let status = backend.spool_status().await.unwrap();
+        assert_eq!(status.records, 3);
+        assert_eq!(status.topics.get("Users"), Some(&3));
+        assert!(inner.produced().is_empty());
+
+        inner.accept(usize::MAX);
+        backend.spool.replay(inner.as_ref()).await;
+
+        assert_eq!(inner.produced(), payloads(&["a", "b", "c"]));
+        let status = backend.spool_status().await.unwrap();
+        assert_eq!((status.records, status.bytes), (0, 0));
+        assert_eq!(status.replayed_total, 3);
+        assert_eq!(
+            std::fs::read_dir(dir.path().join("spool")).unwrap().count(),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_what_a_failed_replay_left_ahead_of_newer_records() {
+        let dir = TempDir::new().unwrap();
+        let inner = Arc::new(FlakyBackend::default());
+        let backend = spooling_backend(&inner, &dir, default_max_bytes());
+
+        for name in ["a", "b", "c"] {
+            backend
+                .produce("Users", "Users", name.as_bytes())
+                .await
+                .unwrap();
+        }
+
+        // The stream takes one record and goes down again
+        inner.accept(1);
+        backend.spool.replay(inner.as_ref()).await;
+        backend.produce("Users", "Users", b"d").await.unwrap();
+
+        let status = backend.spool_status().await.unwrap();
+        assert_eq!(status.records, 3);
+        assert_eq!(status.replayed_total, 1);
+        assert!(status.last_error.is_some());
+
+        inner.accept(usize::MAX);
+        backend.spool.replay(inner.as_ref()).await;
+        assert_eq!(inner.produced(), payloads(&["a", "b", "c", "d"]));
+        assert_eq!(backend.spool_status().await.unwrap().records, 0);
+    }
+
+    #[tokio::test]
+    async fn resumes_the_spool_of_a_previous_run() {
+        let dir = TempDir::new().unwrap();
+        let inner = Arc::new(FlakyBackend::default());
+
+        let backend = spooling_backend(&inner, &dir, default_max_bytes());
+        backend.produce("Users", "Users", b"a").await.unwrap();
+        backend.produce("Users", "Users", b"b").await.unwrap();
+        drop(backend);
+
+        // A replay was cut short, and a record was spooled after it, before a crash that also
+        // left half a record at the end of the log
+        let spool_dir = dir.path().join("spool");
+        std::fs::rename(spool_dir.join("Users.log"), spool_dir.join("Users.replay")).unwrap();
+        let backend = spooling_backend(&inner, &dir, default_max_bytes());
+        backend.produce("Users", "Users", b"c").await.unwrap();
+        drop(backend);
+        let mut log = OpenOptions::new()
+            .append(true)
+            .open(spool_dir.join("Users.log"))
+            .unwrap();
+        log.write_all(&encode_record(b"Users", b"cut short")[..10])
+            .unwrap();
+
+        let backend = spooling_backend(&inner, &dir, default_max_bytes());
+        assert_eq!(backend.spool_status().await.unwrap().records, 3);
+        backend.produce("Users", "Users", b"d").await.unwrap();
+
+        inner.accept(usize::MAX);
+        backend.spool.replay(inner.as_ref()).await;
+        backend.spool.replay(inner.as_ref()).await;
+        assert_eq!(inner.produced(), payloads(&["a", "b", "c", "d"]));
+        assert_eq!(backend.spool_status().await.unwrap().records, 0);
+    }
+
+    #[tokio::test]
+    async fn fails_records_once_the_spool_is_full() {
+        let dir = TempDir::new().unwrap();
+        let inner = Arc::new(FlakyBackend::default());
+        // Room for two records
+        let record_bytes = encode_record(b"Users", b"a").len() as u64;
+        let backend = spooling_backend(&inner, &dir, record_bytes * 2);
+
+        backend.produce("Users", "Users", b"a").await.unwrap();
+        // A batch is spooled whole or not at all
+        let results = backend
+            .produce_batch("Users", "Users", &payloads(&["b", "c"]))
+            .await;
+        assert!(results
+            .iter()
+            .all(|result| matches!(result, Err(e) if e.kind() == ErrorKind::TimedOut)));
+        backend.produce("Users", "Users", b"b").await.unwrap();
+        let e = backend.produce("Users", "Users", b"c").await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::TimedOut);
+
+        let status = backend.spool_status().await.unwrap();
+        assert_eq!(status.records, 2);
+        assert_eq!(status.bytes, record_bytes * 2);
+        assert_eq!(status.spooled_total, 2);
+    }
+}
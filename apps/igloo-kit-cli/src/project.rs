@@ -15,10 +15,13 @@ use std::path::PathBuf;
 
 use crate::cli::local_webserver::LocalWebserverConfig;
 use crate::framework::languages::SupportedLanguages;
+use crate::infrastructure::ingest::IngestConfig;
 use crate::infrastructure::olap::clickhouse::config::ClickhouseConfig;
 use crate::infrastructure::stream::redpanda::RedpandaConfig;
+use crate::infrastructure::stream::StreamConfig;
 use crate::utilities::constants::{
-    APP_DIR, APP_DIR_LAYOUT, CLI_PROJECT_INTERNAL_DIR, PROJECT_CONFIG_FILE, SCHEMAS_DIR,
+    APP_DIR, APP_DIR_LAYOUT, CLI_PROJECT_INTERNAL_DIR, FLOWS_DIR, FORMAT_SCHEMAS_DIR,
+    INGESTION_POINTS_DIR, PROJECT_CONFIG_FILE, SCHEMAS_DIR,
 };
 use config::{Config, ConfigError, File};
 use log::debug;
@@ -42,6 +45,10 @@ pub struct Project {
     pub clickhouse_config: ClickhouseConfig,
     #[serde(default)]
     pub local_webserver_config: LocalWebserverConfig,
+    #[serde(default)]
+    pub ingest_config: IngestConfig,
+    #[serde(default)]
+    pub stream_config: StreamConfig,
 }
 
 impl Project {
@@ -53,6 +60,8 @@ impl Project {
             redpanda_config: RedpandaConfig::default(),
             clickhouse_config: ClickhouseConfig::default(),
             local_webserver_config: LocalWebserverConfig::default(),
+            ingest_config: IngestConfig::default(),
+            stream_config: StreamConfig::default(),
         }
     }
 
@@ -75,6 +84,8 @@ impl Project {
             redpanda_config: RedpandaConfig::default(), // TODO: Add the ability for the developer to configure this
             clickhouse_config: ClickhouseConfig::default(), // TODO: Add the ability for the developer to configure this
             local_webserver_config: LocalWebserverConfig::default(), // TODO: Add the ability for the developer to configure this
+            ingest_config: IngestConfig::default(),
+            stream_config: StreamConfig::default(),
         }
     }
 
@@ -98,7 +109,13 @@ impl Project {
             .set_override("project_file_location", project_file_location)?
             .build()?;
 
-        s.try_deserialize()
+        let project: Project = s.try_deserialize()?;
+        // A producer that can't be created would otherwise only fail once the server starts
+        project
+            .redpanda_config
+            .validate()
+            .map_err(|e| ConfigError::Message(format!("redpanda_config: {}", e)))?;
+        Ok(project)
     }
 
     pub fn setup_app_dir(&self) -> Result<(), std::io::Error> {
@@ -113,6 +130,13 @@ impl Project {
         Ok(())
     }
 
+    pub fn project_dir(&self) -> PathBuf {
+        //! The directory of the project file, which relative paths in it are read from.
+        let mut project_dir = self.project_file_location.clone();
+        project_dir.pop();
+        project_dir
+    }
+
     pub fn app_dir(&self) -> PathBuf {
         let mut app_dir = self.project_file_location.clone();
         app_dir.pop();
@@ -130,12 +154,44 @@ impl Project {
         schemas_dir
     }
 
-    // This is a Result of io::Error because the caller
-    // can be retruning a Result of io::Error or a  Routine Failure
-    pub fn internal_dir(&self) -> std::io::Result<PathBuf> {
+    pub fn flows_dir(&self) -> PathBuf {
+        let mut flows_dir = self.app_dir();
+        flows_dir.push(FLOWS_DIR);
+
+        debug!("Flows dir: {:?}", flows_dir);
+        flows_dir
+    }
+
+    pub fn ingestion_points_dir(&self) -> PathBuf {
+        let mut ingestion_points_dir = self.app_dir();
+        ingestion_points_dir.push(INGESTION_POINTS_DIR);
+
+        debug!("Ingestion points dir: {:?}", ingestion_points_dir);
+        ingestion_points_dir
+    }
+
+    pub fn format_schemas_dir(&self) -> PathBuf {
+        //! Where the generated protobuf definitions are kept, so that their field numbers are stable.
+        let mut format_schemas_dir = self.app_dir();
+        format_schemas_dir.push(FORMAT_SCHEMAS_DIR);
+
+        debug!("Format schemas dir: {:?}", format_schemas_dir);
+        format_schemas_dir
+    }
+
+    pub fn internal_dir_path(&self) -> PathBuf {
+        //! Where the internal directory is, without creating it. Use this to read files that might
+        //! not exist yet, and `internal_dir` to write them.
         let mut internal_dir = self.project_file_location.clone();
         internal_dir.pop();
         internal_dir.push(CLI_PROJECT_INTERNAL_DIR);
+        internal_dir
+    }
+
+    // This is a Result of io::Error because the caller
+    // can be retruning a Result of io::Error or a  Routine Failure
+    pub fn internal_dir(&self) -> std::io::Result<PathBuf> {
+        let internal_dir = self.internal_dir_path();
 
         if !internal_dir.is_dir() {
             if internal_dir.exists() {
@@ -5,14 +5,18 @@ pub const CLI_USER_DIRECTORY: &str = ".igloo";
 pub const CLI_PROJECT_INTERNAL_DIR: &str = ".igloo";
 
 pub const SCHEMAS_DIR: &str = "datamodels";
+pub const FLOWS_DIR: &str = "flows";
+pub const INGESTION_POINTS_DIR: &str = "ingestion_points";
+pub const FORMAT_SCHEMAS_DIR: &str = "format_schemas";
+pub const FLOW_FILE: &str = "flow.ts";
 
 pub const PANDA_NETWORK: &str = "panda-house";
 
 pub const APP_DIR: &str = "app";
 pub const APP_DIR_LAYOUT: [&str; 7] = [
-    "ingestion_points",
+    INGESTION_POINTS_DIR,
     SCHEMAS_DIR,
-    "flows",
+    FLOWS_DIR,
     "insights",
     "insights/dashboards",
     "insights/models",